@@ -0,0 +1,149 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, SafError};
+
+// Os anexos gárdanse fóra da base de datos, nun cartafol xunto a ela, co
+// hash SHA-256 do contido como nome: un mesmo ficheiro só se garda unha vez
+// aínda que estea anexado a varios artigos.
+pub const ATTACHMENTS_DIR: &str = "saf_attachments";
+
+pub const MAX_ATTACHMENT_SIZE: u64 = 25 * 1024 * 1024;
+const THUMBNAIL_SIZE: u32 = 256;
+
+pub fn dir_for_db(db_path: &Path) -> PathBuf {
+    db_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(ATTACHMENTS_DIR)
+}
+
+pub fn check_size(size: u64) -> Result<()> {
+    if size > MAX_ATTACHMENT_SIZE {
+        return Err(SafError::Validation(format!(
+            "O ficheiro ocupa {} MB e o máximo son {} MB",
+            size / (1024 * 1024),
+            MAX_ATTACHMENT_SIZE / (1024 * 1024)
+        )));
+    }
+    Ok(())
+}
+
+pub struct AttachmentStore {
+    root: PathBuf,
+}
+
+// Contido gardado: hash, tamaño e se se puido facer miniatura
+pub struct StoredFile {
+    pub sha256: String,
+    pub size: i64,
+    pub has_thumbnail: bool,
+}
+
+impl AttachmentStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    // "ab/abcdef..." para non acumular miles de ficheiros nun só cartafol
+    pub fn path_for(&self, sha256: &str) -> PathBuf {
+        self.root.join(&sha256[..2]).join(sha256)
+    }
+
+    pub fn thumbnail_path_for(&self, sha256: &str) -> PathBuf {
+        self.root
+            .join(&sha256[..2])
+            .join(format!("{}.thumb.png", sha256))
+    }
+
+    pub fn put(&self, bytes: &[u8], mime_type: &str) -> Result<StoredFile> {
+        check_size(bytes.len() as u64)?;
+
+        let sha256 = Sha256::digest(bytes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        let path = self.path_for(&sha256);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().unwrap_or(&self.root))?;
+            // Escríbese nun temporal e renomease para non deixar ficheiros a medias
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, bytes)?;
+            fs::rename(&tmp, &path)?;
+        }
+
+        let thumbnail = self.thumbnail_path_for(&sha256);
+        let has_thumbnail = thumbnail.exists()
+            || (mime_type.starts_with("image/") && make_thumbnail(bytes, &thumbnail).is_ok());
+
+        Ok(StoredFile {
+            sha256,
+            size: bytes.len() as i64,
+            has_thumbnail,
+        })
+    }
+
+    pub fn read(&self, sha256: &str) -> Result<Vec<u8>> {
+        fs::read(self.path_for(sha256)).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => SafError::NotFound(format!(
+                "Falta o ficheiro do anexo {} no cartafol {}",
+                sha256, ATTACHMENTS_DIR
+            )),
+            _ => SafError::Io(e),
+        })
+    }
+
+    pub fn read_thumbnail(&self, sha256: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.thumbnail_path_for(sha256);
+        if path.exists() {
+            Ok(Some(fs::read(path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Chámase cando xa ningún anexo usa este contido
+    pub fn remove(&self, sha256: &str) -> Result<()> {
+        for path in [self.path_for(sha256), self.thumbnail_path_for(sha256)] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn make_thumbnail(bytes: &[u8], path: &Path) -> std::result::Result<(), image::ImageError> {
+    image::load_from_memory(bytes)?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .save_with_format(path, image::ImageFormat::Png)
+}
+
+// Tipo MIME polo contido (imaxes e PDF) ou, se non, pola extensión
+pub fn detect_mime_type(file_name: &str, bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return "image/jpeg";
+    }
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return "image/png";
+    }
+    if bytes.starts_with(b"%PDF") {
+        return "application/pdf";
+    }
+    let extension = Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "odt" => "application/vnd.oasis.opendocument.text",
+        _ => "application/octet-stream",
+    }
+}
@@ -2,17 +2,20 @@ use chrono::Local;
 use std::fs;
 use std::io::{Read, Write};
 use std::path::Path;
+use walkdir::WalkDir;
 use zip::write::FileOptions;
 
+use crate::attachments::{dir_for_db, ATTACHMENTS_DIR};
+use crate::error::{Result, SafError};
 use crate::models::BackupInfo;
 
-pub fn create_backup<P: AsRef<Path>>(db_path: P, backup_dir: P) -> Result<BackupInfo, String> {
+pub fn create_backup<P: AsRef<Path>>(db_path: P, backup_dir: P) -> Result<BackupInfo> {
     let timestamp = Local::now().format("%Y%m%d_%H%M%S");
     let backup_name = format!("saf_backup_{}.zip", timestamp);
     let backup_path = backup_dir.as_ref().join(&backup_name);
 
     // Create zip file
-    let file = fs::File::create(&backup_path).map_err(|e| e.to_string())?;
+    let file = fs::File::create(&backup_path)?;
     let mut zip = zip::ZipWriter::new(file);
 
     let options = FileOptions::default()
@@ -26,32 +29,47 @@ pub fn create_backup<P: AsRef<Path>>(db_path: P, backup_dir: P) -> Result<Backup
         .and_then(|n| n.to_str())
         .unwrap_or("database.db");
 
-    zip.start_file(db_file_name, options)
-        .map_err(|e| e.to_string())?;
+    zip.start_file(db_file_name, options)?;
 
-    let mut db_file = fs::File::open(&db_path).map_err(|e| e.to_string())?;
+    let mut db_file = fs::File::open(&db_path)?;
     let mut buffer = Vec::new();
-    db_file
-        .read_to_end(&mut buffer)
-        .map_err(|e| e.to_string())?;
-    zip.write_all(&buffer).map_err(|e| e.to_string())?;
+    db_file.read_to_end(&mut buffer)?;
+    zip.write_all(&buffer)?;
+
+    // Os anexos van no cartafol saf_attachments/ da copia, coa mesma estrutura
+    let attachments_dir = dir_for_db(db_path.as_ref());
+    let mut attachment_files = 0;
+    if attachments_dir.exists() {
+        for entry in WalkDir::new(&attachments_dir).sort_by_file_name() {
+            let entry = entry.map_err(std::io::Error::from)?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let Ok(relative) = entry.path().strip_prefix(&attachments_dir) else {
+                continue;
+            };
+            let name = Path::new(ATTACHMENTS_DIR).join(relative);
+            zip.start_file(name.to_string_lossy().replace('\\', "/"), options)?;
+            std::io::copy(&mut fs::File::open(entry.path())?, &mut zip)?;
+            attachment_files += 1;
+        }
+    }
 
     // Add metadata file
     let metadata = serde_json::json!({
         "created_at": Local::now().to_rfc3339(),
         "version": env!("CARGO_PKG_VERSION"),
         "database_file": db_file_name,
+        "attachment_files": attachment_files,
     });
 
-    zip.start_file("metadata.json", options)
-        .map_err(|e| e.to_string())?;
-    zip.write_all(metadata.to_string().as_bytes())
-        .map_err(|e| e.to_string())?;
+    zip.start_file("metadata.json", options)?;
+    zip.write_all(metadata.to_string().as_bytes())?;
 
-    zip.finish().map_err(|e| e.to_string())?;
+    zip.finish()?;
 
     // Get file size
-    let metadata = fs::metadata(&backup_path).map_err(|e| e.to_string())?;
+    let metadata = fs::metadata(&backup_path)?;
     let size = metadata.len();
 
     Ok(BackupInfo {
@@ -62,18 +80,21 @@ pub fn create_backup<P: AsRef<Path>>(db_path: P, backup_dir: P) -> Result<Backup
     })
 }
 
-pub fn restore_backup<P: AsRef<Path>>(backup_path: P, db_path: P) -> Result<(), String> {
-    let file = fs::File::open(&backup_path).map_err(|e| e.to_string())?;
-    let mut archive = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+pub fn restore_backup<P: AsRef<Path>>(backup_path: P, db_path: P) -> Result<()> {
+    let file = fs::File::open(&backup_path)?;
+    let mut archive = zip::ZipArchive::new(file)?;
 
     // Extract database file
+    let mut restored = false;
     for i in 0..archive.len() {
-        let mut file = archive.by_index(i).map_err(|e| e.to_string())?;
+        let mut file = archive.by_index(i)?;
         let name = file.name();
 
-        if name.ends_with(".db") || name.ends_with(".sqlite") || name.ends_with(".sqlite3") {
+        if !restored
+            && (name.ends_with(".db") || name.ends_with(".sqlite") || name.ends_with(".sqlite3"))
+        {
             let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer).map_err(|e| e.to_string())?;
+            file.read_to_end(&mut buffer)?;
 
             // Backup current database first
             if db_path.as_ref().exists() {
@@ -82,27 +103,54 @@ pub fn restore_backup<P: AsRef<Path>>(backup_path: P, db_path: P) -> Result<(),
                     db_path.as_ref().to_string_lossy(),
                     Local::now().format("%Y%m%d_%H%M%S")
                 );
-                fs::copy(&db_path, &backup_name).map_err(|e| e.to_string())?;
+                fs::copy(&db_path, &backup_name)?;
             }
 
             // Write restored database
-            fs::write(&db_path, buffer).map_err(|e| e.to_string())?;
-            return Ok(());
+            fs::write(&db_path, buffer)?;
+            restored = true;
+        }
+    }
+
+    if !restored {
+        return Err(SafError::Validation(
+            "Non se atopou ningún ficheiro de base de datos na copia".to_string(),
+        ));
+    }
+
+    // Anexos: cada ficheiro leva o hash do contido como nome, así que os que
+    // xa existen non se tocan e só se engaden os que faltan
+    let db_dir = db_path.as_ref().parent().unwrap_or_else(|| Path::new("."));
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let Some(relative) = file.enclosed_name().map(|p| p.to_path_buf()) else {
+            continue;
+        };
+        if !file.is_file() || !relative.starts_with(ATTACHMENTS_DIR) {
+            continue;
+        }
+        let dest = db_dir.join(relative);
+        if dest.exists() {
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
         }
+        std::io::copy(&mut file, &mut fs::File::create(dest)?)?;
     }
 
-    Err("No database file found in backup".to_string())
+    Ok(())
 }
 
-pub fn get_backup_list<P: AsRef<Path>>(backup_dir: P) -> Result<Vec<BackupInfo>, String> {
+pub fn get_backup_list<P: AsRef<Path>>(backup_dir: P) -> Result<Vec<BackupInfo>> {
     let mut backups = Vec::new();
 
     if !backup_dir.as_ref().exists() {
         return Ok(backups);
     }
 
-    for entry in fs::read_dir(&backup_dir).map_err(|e| e.to_string())? {
-        let entry = entry.map_err(|e| e.to_string())?;
+    for entry in fs::read_dir(&backup_dir)? {
+        let entry = entry?;
         let path = entry.path();
 
         if path.extension().and_then(|e| e.to_str()) == Some("zip") {
@@ -1,28 +1,19 @@
+use crate::error::SafError;
 use crate::models::*;
 use crate::AppState;
 use tauri::State;
 
 // User commands
 #[tauri::command]
-pub fn get_users(state: State<AppState>) -> Result<Vec<User>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .get_users()
-        .map_err(|e| e.to_string())
+pub fn get_users(state: State<AppState>) -> Result<Vec<User>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_users()
 }
 
 #[tauri::command]
-pub fn create_user(req: CreateUserRequest, state: State<AppState>) -> Result<User, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .create_user(req)
-        .map_err(|e| e.to_string())
+pub fn create_user(req: CreateUserRequest, state: State<AppState>) -> Result<User, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.create_user(req)
 }
 
 #[tauri::command]
@@ -30,63 +21,44 @@ pub fn update_user(
     id: String,
     req: UpdateUserRequest,
     state: State<AppState>,
-) -> Result<User, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .update_user(&id, req)
-        .map_err(|e| e.to_string())
+) -> Result<User, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.update_user(&id, req)
 }
 
 #[tauri::command]
-pub fn delete_user(id: String, state: State<AppState>) -> Result<(), String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .deactivate_user(&id)
-        .map_err(|e| e.to_string())
+pub fn delete_user(id: String, state: State<AppState>) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.deactivate_user(&id)
 }
 
 #[tauri::command]
-pub fn delete_item(id: String, state: State<AppState>) -> Result<(), String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .delete_item(&id)
-        .map_err(|e| e.to_string())
+pub fn delete_item(id: String, state: State<AppState>) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.delete_item(&id)
 }
 
 #[tauri::command]
-pub fn search_users(query: String, state: State<AppState>) -> Result<Vec<User>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .search_users(&query)
-        .map_err(|e| e.to_string())
+pub fn search_users(query: String, state: State<AppState>) -> Result<Vec<User>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.search_users(&query)
+}
+
+#[tauri::command]
+pub fn get_invalid_dnis(state: State<AppState>) -> Result<Vec<InvalidDniEntry>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_invalid_dnis()
 }
 
 // Item commands
 #[tauri::command]
-pub fn get_items(state: State<AppState>) -> Result<Vec<Item>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .get_items()
-        .map_err(|e| e.to_string())
+pub fn get_items(state: State<AppState>) -> Result<Vec<Item>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_items()
 }
 
 #[tauri::command]
-pub fn get_default_items() -> Result<Vec<serde_json::Value>, String> {
+pub fn get_default_items() -> Result<Vec<serde_json::Value>, SafError> {
     // Devolver a lista de artigos por defecto sen engadilos á BD
     let items: Vec<serde_json::Value> = crate::database::DEFAULT_ITEMS
         .iter()
@@ -103,25 +75,25 @@ pub fn get_default_items() -> Result<Vec<serde_json::Value>, String> {
 }
 
 #[tauri::command]
-pub fn add_default_items(state: State<AppState>) -> Result<Vec<Item>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .add_default_items()
-        .map_err(|e| e.to_string())
+pub fn add_default_items(state: State<AppState>) -> Result<Vec<Item>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.add_default_items()
 }
 
 #[tauri::command]
-pub fn create_item(req: CreateItemRequest, state: State<AppState>) -> Result<Item, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .create_item(req)
-        .map_err(|e| e.to_string())
+pub fn create_item(req: CreateItemRequest, state: State<AppState>) -> Result<Item, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.create_item(req)
+}
+
+#[tauri::command]
+pub fn update_item(
+    id: String,
+    req: UpdateItemRequest,
+    state: State<AppState>,
+) -> Result<Item, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.update_item(&id, req)
 }
 
 #[tauri::command]
@@ -129,75 +101,249 @@ pub fn update_item_stock(
     item_id: String,
     new_total_stock: i32,
     state: State<AppState>,
-) -> Result<Item, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
+) -> Result<Item, SafError> {
+    let _lock = state.lock.lock()?;
     state
         .db
-        .lock()
-        .map_err(|e| e.to_string())?
+        .lock()?
         .update_item_stock(&item_id, new_total_stock)
-        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-pub fn search_items(query: String, state: State<AppState>) -> Result<Vec<Item>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
+pub fn search_items(query: String, state: State<AppState>) -> Result<Vec<Item>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.search_items(&query)
+}
+
+// Category commands
+#[tauri::command]
+pub fn get_categories(
+    include_inactive: bool,
+    state: State<AppState>,
+) -> Result<Vec<Category>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_categories(include_inactive)
+}
+
+#[tauri::command]
+pub fn create_category(
+    req: CreateCategoryRequest,
+    state: State<AppState>,
+) -> Result<Category, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.create_category(req)
+}
+
+#[tauri::command]
+pub fn update_category(
+    id: String,
+    req: UpdateCategoryRequest,
+    state: State<AppState>,
+) -> Result<Category, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.update_category(&id, req)
+}
+
+#[tauri::command]
+pub fn merge_categories(
+    source_id: String,
+    target_id: String,
+    state: State<AppState>,
+) -> Result<Category, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.merge_categories(&source_id, &target_id)
+}
+
+#[tauri::command]
+pub fn retire_category(id: String, state: State<AppState>) -> Result<Category, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.retire_category(&id)
+}
+
+// Attachment commands
+#[tauri::command]
+pub fn get_attachments(
+    item_id: String,
+    state: State<AppState>,
+) -> Result<Vec<Attachment>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_attachments(&item_id)
+}
+
+#[tauri::command]
+pub fn add_attachment(
+    req: AddAttachmentRequest,
+    state: State<AppState>,
+) -> Result<Attachment, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.add_attachment(req)
+}
+
+#[tauri::command]
+pub fn get_attachment_thumbnail(id: String, state: State<AppState>) -> Result<Vec<u8>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_attachment_thumbnail(&id)
+}
+
+#[tauri::command]
+pub fn export_attachment(
+    id: String,
+    dest_path: String,
+    state: State<AppState>,
+) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.export_attachment(&id, &dest_path)
+}
+
+#[tauri::command]
+pub fn delete_attachment(id: String, state: State<AppState>) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.delete_attachment(&id)
+}
+
+// Unit commands
+#[tauri::command]
+pub fn get_item_units(item_id: String, state: State<AppState>) -> Result<Vec<Unit>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_item_units(&item_id)
+}
+
+#[tauri::command]
+pub fn add_unit(req: CreateUnitRequest, state: State<AppState>) -> Result<Unit, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.add_unit(req)
+}
+
+#[tauri::command]
+pub fn update_unit(
+    id: String,
+    req: UpdateUnitRequest,
+    state: State<AppState>,
+) -> Result<Unit, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.update_unit(&id, req)
+}
+
+#[tauri::command]
+pub fn retire_unit(
+    id: String,
+    reason: Option<String>,
+    state: State<AppState>,
+) -> Result<Unit, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.retire_unit(&id, reason)
+}
+
+#[tauri::command]
+pub fn get_unit_loans(unit_id: String, state: State<AppState>) -> Result<Vec<Loan>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_unit_loans(&unit_id)
+}
+
+// Maintenance commands
+#[tauri::command]
+pub fn get_maintenance_orders(
+    item_id: Option<String>,
+    open_only: bool,
+    state: State<AppState>,
+) -> Result<Vec<MaintenanceOrder>, SafError> {
+    let _lock = state.lock.lock()?;
     state
         .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .search_items(&query)
-        .map_err(|e| e.to_string())
+        .lock()?
+        .get_maintenance_orders(item_id.as_deref(), open_only)
+}
+
+#[tauri::command]
+pub fn open_maintenance_order(
+    req: CreateMaintenanceOrderRequest,
+    state: State<AppState>,
+) -> Result<MaintenanceOrder, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.open_maintenance_order(req)
+}
+
+#[tauri::command]
+pub fn update_maintenance_order(
+    id: String,
+    req: UpdateMaintenanceOrderRequest,
+    state: State<AppState>,
+) -> Result<MaintenanceOrder, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.update_maintenance_order(&id, req)
+}
+
+#[tauri::command]
+pub fn close_maintenance_order(
+    id: String,
+    req: CloseMaintenanceOrderRequest,
+    state: State<AppState>,
+) -> Result<MaintenanceOrder, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.close_maintenance_order(&id, req)
 }
 
 // Loan commands
 #[tauri::command]
-pub fn get_loans(state: State<AppState>) -> Result<Vec<Loan>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .get_loans()
-        .map_err(|e| e.to_string())
+pub fn get_loans(state: State<AppState>) -> Result<Vec<Loan>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_loans()
 }
 
 #[tauri::command]
-pub fn get_loan_by_id(id: String, state: State<AppState>) -> Result<Loan, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .get_loan_by_id(&id)
-        .map_err(|e| e.to_string())
+pub fn get_loan_by_id(id: String, state: State<AppState>) -> Result<Loan, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_loan_by_id(&id)
 }
 
 #[tauri::command]
-pub fn create_loan(req: CreateLoanRequest, state: State<AppState>) -> Result<Loan, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .create_loan(req)
-        .map_err(|e| e.to_string())
+pub fn create_loan(req: CreateLoanRequest, state: State<AppState>) -> Result<Loan, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.create_loan(req)
 }
 
 #[tauri::command]
 pub fn return_loan(
     id: String,
-    condition: Option<String>,
+    condition: Option<ReturnCondition>,
     notes: Option<String>,
     state: State<AppState>,
-) -> Result<Loan, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
+) -> Result<Loan, SafError> {
+    let _lock = state.lock.lock()?;
     state
         .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .return_loan(&id, condition, notes)
-        .map_err(|e| e.to_string())
+        .lock()?
+        .return_loan(&id, ReturnLoanRequest { condition, notes })
+}
+
+#[tauri::command]
+pub fn return_loan_item(
+    loan_item_id: String,
+    req: ReturnLoanItemRequest,
+    state: State<AppState>,
+) -> Result<Loan, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.return_loan_item(&loan_item_id, req)
+}
+
+#[tauri::command]
+pub fn extend_loan(
+    id: String,
+    new_end_date: chrono::NaiveDate,
+    reason: Option<String>,
+    state: State<AppState>,
+) -> Result<Loan, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.extend_loan(&id, new_end_date, reason)
+}
+
+#[tauri::command]
+pub fn get_loan_extensions(
+    loan_id: String,
+    state: State<AppState>,
+) -> Result<Vec<LoanExtension>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_loan_extensions(&loan_id)
 }
 
 #[tauri::command]
@@ -205,88 +351,106 @@ pub fn cancel_return(
     loan_id: String,
     reason: Option<String>,
     state: State<AppState>,
-) -> Result<Loan, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .cancel_return(&loan_id, reason)
-        .map_err(|e| e.to_string())
+) -> Result<Loan, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.cancel_return(&loan_id, reason)
 }
 
 #[tauri::command]
-pub fn get_active_loans(state: State<AppState>) -> Result<Vec<Loan>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    // Implementation to filter active loans
-    let loans = state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .get_loans()
-        .map_err(|e| e.to_string())?;
-    Ok(loans
-        .into_iter()
-        .filter(|l| matches!(l.status, LoanStatus::Active))
-        .collect())
+pub fn deliver_loan(id: String, state: State<AppState>) -> Result<Loan, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.deliver_loan(&id)
 }
 
 #[tauri::command]
-pub fn get_overdue_loans(state: State<AppState>) -> Result<Vec<Loan>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    // Implementation to filter overdue loans
-    let loans = state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .get_loans()
-        .map_err(|e| e.to_string())?;
-    Ok(loans
-        .into_iter()
-        .filter(|l| matches!(l.status, LoanStatus::Overdue))
-        .collect())
+pub fn query_loans(query: LoanQuery, state: State<AppState>) -> Result<LoanPage, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.query_loans(&query)
+}
+
+#[tauri::command]
+pub fn get_active_loans(state: State<AppState>) -> Result<Vec<Loan>, SafError> {
+    let _lock = state.lock.lock()?;
+    let query = LoanQuery {
+        statuses: Some(vec![LoanStatus::Active]),
+        ..Default::default()
+    };
+    Ok(state.db.lock()?.query_loans(&query)?.loans)
+}
+
+#[tauri::command]
+pub fn get_overdue_loans(state: State<AppState>) -> Result<Vec<Loan>, SafError> {
+    let _lock = state.lock.lock()?;
+    let query = LoanQuery {
+        statuses: Some(vec![LoanStatus::Overdue]),
+        ..Default::default()
+    };
+    Ok(state.db.lock()?.query_loans(&query)?.loans)
 }
 
 // Dashboard
 #[tauri::command]
-pub fn get_dashboard_stats(state: State<AppState>) -> Result<DashboardStats, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    // Update overdue loans first
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .update_overdue_loans()
-        .map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .get_dashboard_stats()
-        .map_err(|e| e.to_string())
+pub fn get_dashboard_stats(state: State<AppState>) -> Result<DashboardStats, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_dashboard_stats()
+}
+
+// Comprobación dos contadores de stock (con repair = true corríxense)
+#[tauri::command]
+pub fn check_stock(repair: bool, state: State<AppState>) -> Result<StockCheckReport, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.check_stock(repair)
 }
 
 // Events
 #[tauri::command]
-pub fn get_events(limit: i64, state: State<AppState>) -> Result<Vec<Event>, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    state
-        .db
-        .lock()
-        .map_err(|e| e.to_string())?
-        .get_events(limit)
-        .map_err(|e| e.to_string())
+pub fn get_events(limit: i64, state: State<AppState>) -> Result<Vec<Event>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_events(limit)
+}
+
+#[tauri::command]
+pub fn get_events_by_loan(loan_id: String, state: State<AppState>) -> Result<Vec<Event>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_events_by_loan(&loan_id)
+}
+
+#[tauri::command]
+pub fn query_events(query: EventQuery, state: State<AppState>) -> Result<EventPage, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.query_events(&query)
+}
+
+#[tauri::command]
+pub fn cancel_event(
+    event_id: String,
+    reason: Option<String>,
+    state: State<AppState>,
+) -> Result<Event, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.cancel_event(&event_id, reason)
 }
 
+// Search
 #[tauri::command]
-pub fn get_events_by_loan(_loan_id: String, _state: State<AppState>) -> Result<Vec<Event>, String> {
-    // Implementation to get events by loan
-    Err("Not implemented".to_string())
+pub fn global_search(
+    query: String,
+    limit: Option<i64>,
+    state: State<AppState>,
+) -> Result<GlobalSearchResults, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.global_search(&query, limit)
+}
+
+#[tauri::command]
+pub fn get_schema_version(state: State<AppState>) -> Result<SchemaInfo, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_schema_info()
 }
 
 // Settings
 #[tauri::command]
-pub fn get_db_location(app_handle: tauri::AppHandle) -> Result<String, String> {
+pub fn get_db_location(app_handle: tauri::AppHandle) -> Result<String, SafError> {
     use std::path::PathBuf;
 
     let settings_path = app_handle
@@ -314,7 +478,12 @@ pub fn get_db_location(app_handle: tauri::AppHandle) -> Result<String, String> {
 }
 
 #[tauri::command]
-pub fn set_db_location(path: String, app_handle: tauri::AppHandle) -> Result<(), String> {
+pub fn set_db_location(path: String, app_handle: tauri::AppHandle) -> Result<(), SafError> {
+    write_setting(&app_handle, "db_path", serde_json::json!(path))
+}
+
+// Le unha opción de settings.json
+fn read_setting(app_handle: &tauri::AppHandle, key: &str) -> Option<serde_json::Value> {
     use std::path::PathBuf;
 
     let settings_path = app_handle
@@ -323,15 +492,39 @@ pub fn set_db_location(path: String, app_handle: tauri::AppHandle) -> Result<(),
         .unwrap_or_else(|| PathBuf::from("."))
         .join("settings.json");
 
-    let settings = serde_json::json!({
-        "db_path": path
-    });
+    std::fs::read_to_string(settings_path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+        .and_then(|settings| settings.get(key).cloned())
+}
+
+// Cambia unha opción de settings.json conservando as demais
+fn write_setting(
+    app_handle: &tauri::AppHandle,
+    key: &str,
+    value: serde_json::Value,
+) -> Result<(), SafError> {
+    use std::path::PathBuf;
 
-    std::fs::write(&settings_path, settings.to_string()).map_err(|e| e.to_string())
+    let settings_path = app_handle
+        .path_resolver()
+        .app_data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("settings.json");
+
+    let mut settings = std::fs::read_to_string(&settings_path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+        .filter(|settings| settings.is_object())
+        .unwrap_or_else(|| serde_json::json!({}));
+    settings[key] = value;
+
+    std::fs::write(&settings_path, settings.to_string())?;
+    Ok(())
 }
 
 #[tauri::command]
-pub fn has_db_location_configured(app_handle: tauri::AppHandle) -> Result<bool, String> {
+pub fn has_db_location_configured(app_handle: tauri::AppHandle) -> Result<bool, SafError> {
     use std::path::PathBuf;
 
     let settings_path = app_handle
@@ -351,12 +544,88 @@ pub fn has_db_location_configured(app_handle: tauri::AppHandle) -> Result<bool,
     Ok(false)
 }
 
+// Minutos entre comprobacións automáticas de préstamos atrasados.
+// O cambio aplícase ao volver abrir a aplicación.
+#[tauri::command]
+pub fn get_overdue_check_interval(app_handle: tauri::AppHandle) -> Result<u64, SafError> {
+    Ok(crate::get_overdue_check_minutes(&app_handle))
+}
+
+#[tauri::command]
+pub fn set_overdue_check_interval(
+    minutes: u64,
+    app_handle: tauri::AppHandle,
+) -> Result<(), SafError> {
+    if minutes == 0 {
+        return Err(SafError::Validation(
+            "O intervalo debe ser de polo menos un minuto".to_string(),
+        ));
+    }
+    write_setting(
+        &app_handle,
+        "overdue_check_minutes",
+        serde_json::json!(minutes),
+    )
+}
+
+// Modelo das cartas de recordatorio; sen configurar úsase o de serie
+#[tauri::command]
+pub fn get_reminder_template(app_handle: tauri::AppHandle) -> Result<String, SafError> {
+    Ok(read_setting(&app_handle, "reminder_template")
+        .and_then(|template| template.as_str().map(str::to_string))
+        .unwrap_or_else(|| crate::reminders::DEFAULT_TEMPLATE.to_string()))
+}
+
+// Marcadores que se poden usar no modelo, coa súa descrición
+#[tauri::command]
+pub fn get_reminder_placeholders() -> Result<Vec<(&'static str, &'static str)>, SafError> {
+    Ok(crate::reminders::PLACEHOLDERS.to_vec())
+}
+
+// Un modelo baleiro volve ao de serie
+#[tauri::command]
+pub fn set_reminder_template(
+    template: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), SafError> {
+    match template.filter(|t| !t.trim().is_empty()) {
+        Some(template) => {
+            crate::reminders::validate_template(&template)?;
+            write_setting(
+                &app_handle,
+                "reminder_template",
+                serde_json::json!(template),
+            )
+        }
+        None => write_setting(&app_handle, "reminder_template", serde_json::Value::Null),
+    }
+}
+
+#[tauri::command]
+pub fn export_reminder_letters(
+    path: String,
+    one_file_per_user: bool,
+    app_handle: tauri::AppHandle,
+    state: State<AppState>,
+) -> Result<Vec<String>, SafError> {
+    let template = get_reminder_template(app_handle)?;
+    let _lock = state.lock.lock()?;
+    let db = state.db.lock()?;
+    crate::excel::export_reminder_letters(&db, &path, &template, one_file_per_user)
+        .map_err(SafError::from)
+}
+
 // Excel export
 #[tauri::command]
-pub fn export_to_excel(path: String, state: State<AppState>) -> Result<(), String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    let db = state.db.lock().map_err(|e| e.to_string())?;
-    crate::excel::export_loans_to_excel(&*db, &path).map_err(|e| e.to_string())
+pub fn export_to_excel(
+    path: String,
+    query: Option<LoanQuery>,
+    state: State<AppState>,
+) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    let db = state.db.lock()?;
+    crate::excel::export_loans_to_excel(&*db, &path, &query.unwrap_or_default())
+        .map_err(SafError::from)
 }
 
 // Backup — uses app_handle to resolve paths server-side
@@ -364,8 +633,8 @@ pub fn export_to_excel(path: String, state: State<AppState>) -> Result<(), Strin
 pub fn create_backup(
     app_handle: tauri::AppHandle,
     state: State<AppState>,
-) -> Result<BackupInfo, String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
+) -> Result<BackupInfo, SafError> {
+    let _lock = state.lock.lock()?;
     let db_path = get_db_location(app_handle.clone())?;
     let backup_dir = app_handle
         .path_resolver()
@@ -373,44 +642,43 @@ pub fn create_backup(
         .unwrap_or_else(|| std::path::PathBuf::from("."))
         .join("backups");
     // Ensure dir exists
-    std::fs::create_dir_all(&backup_dir).map_err(|e| e.to_string())?;
+    std::fs::create_dir_all(&backup_dir)?;
     let backup_dir_str = backup_dir.to_string_lossy().to_string();
-    crate::backup::create_backup(&db_path, &backup_dir_str).map_err(|e| e.to_string())
+    crate::backup::create_backup(&db_path, &backup_dir_str)
 }
 
 #[tauri::command]
-pub fn restore_backup(backup_path: String, app_handle: tauri::AppHandle) -> Result<(), String> {
+pub fn restore_backup(backup_path: String, app_handle: tauri::AppHandle) -> Result<(), SafError> {
     let db_path = get_db_location(app_handle)?;
-    crate::backup::restore_backup(&backup_path, &db_path).map_err(|e| e.to_string())
+    crate::backup::restore_backup(&backup_path, &db_path)
 }
 
 #[tauri::command]
-pub fn get_backup_list(app_handle: tauri::AppHandle) -> Result<Vec<BackupInfo>, String> {
+pub fn get_backup_list(app_handle: tauri::AppHandle) -> Result<Vec<BackupInfo>, SafError> {
     let backup_dir = app_handle
         .path_resolver()
         .app_data_dir()
         .unwrap_or_else(|| std::path::PathBuf::from("."))
         .join("backups");
     let backup_dir_str = backup_dir.to_string_lossy().to_string();
-    crate::backup::get_backup_list(&backup_dir_str).map_err(|e| e.to_string())
+    crate::backup::get_backup_list(&backup_dir_str)
 }
 
 #[tauri::command]
-pub fn export_backup(source_path: String, dest_path: String) -> Result<(), String> {
-    std::fs::copy(&source_path, &dest_path)
-        .map(|_| ())
-        .map_err(|e| format!("Erro ao exportar: {}", e))
+pub fn export_backup(source_path: String, dest_path: String) -> Result<(), SafError> {
+    std::fs::copy(&source_path, &dest_path)?;
+    Ok(())
 }
 
 #[tauri::command]
-pub fn import_backup(source_path: String, app_handle: tauri::AppHandle) -> Result<(), String> {
+pub fn import_backup(source_path: String, app_handle: tauri::AppHandle) -> Result<(), SafError> {
     let backup_dir = app_handle
         .path_resolver()
         .app_data_dir()
         .unwrap_or_else(|| std::path::PathBuf::from("."))
         .join("backups");
 
-    std::fs::create_dir_all(&backup_dir).map_err(|e| e.to_string())?;
+    std::fs::create_dir_all(&backup_dir)?;
 
     let source_path = std::path::Path::new(&source_path);
     let filename = source_path
@@ -420,24 +688,48 @@ pub fn import_backup(source_path: String, app_handle: tauri::AppHandle) -> Resul
 
     let dest_path = backup_dir.join(filename);
 
-    std::fs::copy(source_path, &dest_path)
-        .map(|_| ())
-        .map_err(|e| format!("Erro ao importar: {}", e))
+    std::fs::copy(source_path, &dest_path)?;
+    Ok(())
 }
 
 #[tauri::command]
-pub fn delete_backup(backup_path: String) -> Result<(), String> {
-    std::fs::remove_file(&backup_path)
-        .map(|_| ())
-        .map_err(|e| format!("Erro ao eliminar: {}", e))
+pub fn delete_backup(backup_path: String) -> Result<(), SafError> {
+    std::fs::remove_file(&backup_path)?;
+    Ok(())
 }
 
 // Annual report
 #[tauri::command]
-pub fn export_annual_report(path: String, year: i32, state: State<AppState>) -> Result<(), String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    let db = state.db.lock().map_err(|e| e.to_string())?;
-    crate::excel::export_annual_report(&*db, &path, year).map_err(|e| e.to_string())
+pub fn export_annual_report(
+    path: String,
+    year: i32,
+    state: State<AppState>,
+) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    let db = state.db.lock()?;
+    crate::excel::export_annual_report(&*db, &path, year).map_err(SafError::from)
+}
+
+#[tauri::command]
+pub fn get_damage_report(
+    from: chrono::NaiveDate,
+    to: chrono::NaiveDate,
+    state: State<AppState>,
+) -> Result<Vec<DamageReportEntry>, SafError> {
+    let _lock = state.lock.lock()?;
+    state.db.lock()?.get_damage_report(from, to)
+}
+
+#[tauri::command]
+pub fn export_damage_report(
+    path: String,
+    from: chrono::NaiveDate,
+    to: chrono::NaiveDate,
+    state: State<AppState>,
+) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    let db = state.db.lock()?;
+    crate::excel::export_damage_report(&db, &path, from, to).map_err(SafError::from)
 }
 
 #[tauri::command]
@@ -445,8 +737,30 @@ pub fn export_annual_report_pdf(
     path: String,
     year: i32,
     state: State<AppState>,
-) -> Result<(), String> {
-    let _lock = state.lock.lock().map_err(|e| e.to_string())?;
-    let db = state.db.lock().map_err(|e| e.to_string())?;
-    crate::excel::export_annual_report_pdf(&*db, &path, year).map_err(|e| e.to_string())
+) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    let db = state.db.lock()?;
+    crate::excel::export_annual_report_pdf(&*db, &path, year).map_err(SafError::from)
+}
+
+#[tauri::command]
+pub fn export_delivery_receipt_pdf(
+    path: String,
+    loan_id: String,
+    state: State<AppState>,
+) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    let db = state.db.lock()?;
+    crate::excel::export_delivery_receipt_pdf(&db, &path, &loan_id).map_err(SafError::from)
+}
+
+#[tauri::command]
+pub fn export_return_receipt_pdf(
+    path: String,
+    loan_id: String,
+    state: State<AppState>,
+) -> Result<(), SafError> {
+    let _lock = state.lock.lock()?;
+    let db = state.db.lock()?;
+    crate::excel::export_return_receipt_pdf(&db, &path, &loan_id).map_err(SafError::from)
 }
@@ -1,8 +1,11 @@
-use chrono::Local;
-use rusqlite::{params, Connection, Result};
+use chrono::{Local, NaiveDate};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use uuid::Uuid;
 
+use crate::attachments::AttachmentStore;
+use crate::error::{Result, SafError};
 use crate::models::*;
 
 // Lista expandida de artigos por defecto que se poden engadir
@@ -178,254 +181,393 @@ pub const DEFAULT_ITEMS: &[(&str, &str, &str, &str)] = &[
 
 pub struct Database {
     conn: Connection,
+    attachments: AttachmentStore,
+}
+
+// Etiqueta de inventario por defecto: tres letras do nome do artigo e un
+// número consecutivo, p.ex. "CAM-004". Pódese cambiar despois con update_unit.
+pub(crate) fn next_unit_tag(conn: &Connection, item_name: &str) -> rusqlite::Result<String> {
+    let mut prefix: String = item_name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(3)
+        .collect::<String>()
+        .to_uppercase();
+    if prefix.is_empty() {
+        prefix = "UNI".to_string();
+    }
+
+    let mut n: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM units WHERE tag LIKE ?1",
+        [format!("{}-%", prefix)],
+        |row| row.get(0),
+    )?;
+    loop {
+        n += 1;
+        let tag = format!("{}-{:03}", prefix, n);
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM units WHERE tag = ?1)",
+            [&tag],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(tag);
+        }
+    }
+}
+
+// Palabras dunha busca: os signos de puntuación separan palabras
+fn search_terms(input: &str) -> Vec<String> {
+    input
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Converte o texto dunha busca nunha consulta FTS5: cada palabra como
+// prefixo ("cadeira rodas" -> "cadeira"* "rodas"*), todas obrigatorias.
+// Devolve None se non queda ningunha palabra.
+fn fts_query(input: &str) -> Option<String> {
+    let terms = search_terms(input);
+    if terms.is_empty() {
+        None
+    } else {
+        Some(
+            terms
+                .iter()
+                .map(|t| format!("\"{}\"*", t))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+}
+
+// Fragmento de `text` arredor da primeira aparición de `term` (sen
+// distinguir maiúsculas), co termo marcado entre [ ]
+fn excerpt(text: &str, term: &str) -> Option<String> {
+    const CONTEXT: usize = 30;
+    let chars: Vec<char> = text.chars().collect();
+    let lower: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+    let needle: Vec<char> = term
+        .chars()
+        .map(|c| c.to_lowercase().next().unwrap_or(c))
+        .collect();
+    if needle.is_empty() || needle.len() > lower.len() {
+        return None;
+    }
+    let start = lower
+        .windows(needle.len())
+        .position(|w| w == needle.as_slice())?;
+    let end = start + needle.len();
+    let from = start.saturating_sub(CONTEXT);
+    let to = (end + CONTEXT).min(chars.len());
+
+    let mut out = String::new();
+    if from > 0 {
+        out.push('…');
+    }
+    out.extend(&chars[from..start]);
+    out.push('[');
+    out.extend(&chars[start..end]);
+    out.push(']');
+    out.extend(&chars[end..to]);
+    if to < chars.len() {
+        out.push('…');
+    }
+    Some(out)
 }
 
 impl Database {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let attachments = AttachmentStore::new(crate::attachments::dir_for_db(path.as_ref()));
         let conn = Connection::open(path)?;
 
         // Enable foreign keys
         conn.execute("PRAGMA foreign_keys = ON", [])?;
 
-        let db = Self { conn };
-        db.init_tables()?;
+        crate::migrations::run(&conn)?;
+
+        let db = Self { conn, attachments };
         // Non engadimos artigos por defecto - o inventario comeza baleiro
 
         Ok(db)
     }
 
-    fn init_tables(&self) -> Result<()> {
-        // Users table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS users (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                dni TEXT UNIQUE NOT NULL,
-                address TEXT NOT NULL,
-                phone TEXT,
-                email TEXT,
-                notes TEXT,
-                active INTEGER NOT NULL DEFAULT 1,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-            )",
-            [],
-        )?;
+    // Versión do esquema da base de datos (para diagnóstico)
+    pub fn get_schema_info(&self) -> Result<SchemaInfo> {
+        Ok(SchemaInfo {
+            current_version: crate::migrations::current_version(&self.conn)?,
+            latest_version: crate::migrations::latest_version(),
+        })
+    }
 
-        // Migration: add active column if missing
-        let has_active: bool = self
-            .conn
-            .prepare("SELECT active FROM users LIMIT 0")
-            .is_ok();
-        if !has_active {
-            self.conn
-                .execute(
-                    "ALTER TABLE users ADD COLUMN active INTEGER NOT NULL DEFAULT 1",
-                    [],
-                )
-                .ok();
+    // Unidade de traballo: todas as escrituras de `f` (incluídos os eventos)
+    // fanse nunha única transacción. Se `f` falla, desfaise todo.
+    // As chamadas aniñadas reutilizan a transacción xa aberta.
+    fn unit_of_work<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        if !self.conn.is_autocommit() {
+            return f();
         }
 
-        // Items table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS items (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                description TEXT,
-                category TEXT NOT NULL,
-                icon TEXT NOT NULL,
-                total_stock INTEGER NOT NULL DEFAULT 0,
-                available_stock INTEGER NOT NULL DEFAULT 0,
-                notes TEXT,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-            )",
-            [],
-        )?;
-
-        // Loans table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS loans (
-                id TEXT PRIMARY KEY,
-                user_id TEXT NOT NULL,
-                start_date DATE NOT NULL,
-                expected_end_date DATE NOT NULL,
-                actual_end_date DATE,
-                status TEXT NOT NULL DEFAULT 'active',
-                notes TEXT,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY (user_id) REFERENCES users(id)
-            )",
-            [],
-        )?;
-
-        // Loan items table (many-to-many)
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS loan_items (
-                id TEXT PRIMARY KEY,
-                loan_id TEXT NOT NULL,
-                item_id TEXT NOT NULL,
-                quantity INTEGER NOT NULL DEFAULT 1,
-                FOREIGN KEY (loan_id) REFERENCES loans(id) ON DELETE CASCADE,
-                FOREIGN KEY (item_id) REFERENCES items(id)
-            )",
-            [],
-        )?;
-
-        // Events table (Event Sourcing)
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS events (
-                id TEXT PRIMARY KEY,
-                event_type TEXT NOT NULL,
-                data TEXT NOT NULL,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                loan_id TEXT,
-                user_id TEXT,
-                cancelled_by TEXT,
-                cancellation_reason TEXT
-            )",
-            [],
-        )?;
-
-        // Create indexes
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_loans_user_id ON loans(user_id)",
-            [],
-        )?;
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_loans_status ON loans(status)",
-            [],
-        )?;
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_loan_items_loan_id ON loan_items(loan_id)",
-            [],
-        )?;
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_events_created_at ON events(created_at)",
-            [],
-        )?;
-
-        Ok(())
+        let tx = self.conn.unchecked_transaction()?;
+        let result = f()?;
+        tx.commit()?;
+        Ok(result)
     }
 
     // Engadir artigos por defecto (chámase manualmente cando o usuario quere)
     pub fn add_default_items(&self) -> Result<Vec<Item>> {
-        let mut added_items = Vec::new();
-        let now = Local::now().naive_local();
-
-        for (name, desc, category, icon) in DEFAULT_ITEMS {
-            // Comprobar se xa existe un artigo con este nome
-            let exists: bool = self.conn.query_row(
-                "SELECT EXISTS(SELECT 1 FROM items WHERE name = ?1)",
-                [*name],
-                |row| row.get(0),
-            )?;
+        self.unit_of_work(|| {
+            let mut added_items = Vec::new();
+            let now = Local::now().naive_local();
 
-            if !exists {
-                let id = Uuid::new_v4().to_string();
-                self.conn.execute(
-                    "INSERT INTO items (id, name, description, category, icon, total_stock, available_stock, created_at, updated_at) 
-                     VALUES (?1, ?2, ?3, ?4, ?5, 0, 0, ?6, ?6)",
-                    params![id, name, desc, category, icon, now],
+            for (name, desc, category, icon) in DEFAULT_ITEMS {
+                // Comprobar se xa existe un artigo con este nome
+                let exists: bool = self.conn.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM items WHERE name = ?1)",
+                    [*name],
+                    |row| row.get(0),
                 )?;
 
-                let item = Item {
-                    id,
-                    name: name.to_string(),
-                    description: Some(desc.to_string()),
-                    category: category.to_string(),
-                    icon: icon.to_string(),
-                    total_stock: 0,
-                    available_stock: 0,
-                    notes: None,
-                    created_at: now,
-                    updated_at: now,
-                };
-                added_items.push(item.clone());
+                if !exists {
+                    let id = Uuid::new_v4().to_string();
+                    // Se a categoría por defecto se borrou ou retirou, vólvese crear ou usar
+                    let category = self.category_by_name(category, true)?;
+                    self.conn.execute(
+                        "INSERT INTO items (id, name, description, category, category_id, icon, total_stock, available_stock, created_at, updated_at) 
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, 0, 0, ?7, ?7)",
+                        params![id, name, desc, category.name, category.id, icon, now],
+                    )?;
 
-                self.log_event(
-                    "ITEM_CREATED",
-                    serde_json::json!({"itemId": &item.id, "name": name, "source": "default_list"}),
-                    None,
-                    None,
-                )?;
+                    added_items.push(self.get_item_by_id(&id)?);
+
+                    self.log_event(
+                        "ITEM_CREATED",
+                        serde_json::json!({"itemId": &id, "name": name, "source": "default_list"}),
+                        None,
+                        None,
+                    )?;
+                }
             }
-        }
 
-        Ok(added_items)
+            Ok(added_items)
+        })
     }
 
     // Crear un artigo personalizado
     pub fn create_item(&self, req: CreateItemRequest) -> Result<Item> {
-        let id = Uuid::new_v4().to_string();
-        let now = Local::now().naive_local();
+        self.unit_of_work(|| {
+            let id = Uuid::new_v4().to_string();
+            let now = Local::now().naive_local();
+            let category = self.category_by_name(&req.category, false)?;
 
-        self.conn.execute(
-            "INSERT INTO items (id, name, description, category, icon, total_stock, available_stock, notes, created_at, updated_at) 
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, ?7, ?8, ?8)",
-            params![id, req.name, req.description, req.category, req.icon, req.total_stock, req.notes, now],
-        )?;
+            self.conn.execute(
+                "INSERT INTO items (id, name, description, category, category_id, icon, total_stock, available_stock, notes, created_at, updated_at) 
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, 0, 0, ?7, ?8, ?8)",
+                params![id, req.name, req.description, category.name, category.id, req.icon, req.notes, now],
+            )?;
 
-        self.log_event(
-            "ITEM_CREATED",
-            serde_json::json!({"itemId": &id, "name": &req.name, "source": "custom"}),
-            None,
-            None,
-        )?;
+            // Unha unidade física por cada existencia
+            for _ in 0..req.total_stock.max(0) {
+                self.insert_unit(&id, None, None, None)?;
+            }
+            self.refresh_item_stock(&id)?;
+
+            self.log_event(
+                "ITEM_CREATED",
+                serde_json::json!({"itemId": &id, "name": &req.name, "source": "custom"}),
+                None,
+                None,
+            )?;
 
-        self.get_item_by_id(&id)
+            self.get_item_by_id(&id)
+        })
     }
 
     // Actualizar stock dun artigo
     pub fn update_item_stock(&self, item_id: &str, new_total_stock: i32) -> Result<Item> {
-        let now = Local::now().naive_local();
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
 
-        // Obter stock actual para calcular diferenza
-        let current: (i32, i32) = self.conn.query_row(
-            "SELECT total_stock, available_stock FROM items WHERE id = ?1",
-            [item_id],
-            |row| Ok((row.get(0)?, row.get(1)?)),
-        )?;
+            // Obter stock actual para calcular diferenza
+            let current: (i32, i32) = self.conn.query_row(
+                "SELECT total_stock, available_stock FROM items WHERE id = ?1",
+                [item_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
 
-        let (current_total, current_available) = current;
-        let loaned = current_total - current_available;
+            let (current_total, current_available) = current;
+            let loaned = current_total - current_available;
 
-        // Non se pode reducir por debaixo do que está prestado
-        if new_total_stock < loaned {
-            return Err(rusqlite::Error::InvalidParameterName(format!(
-                "Non se pode reducir o stock a {} porque hai {} unidades en préstamo",
-                new_total_stock, loaned
-            )));
-        }
+            // Non se pode reducir por debaixo do que está prestado
+            if new_total_stock < loaned {
+                return Err(SafError::Conflict(format!(
+                    "Non se pode reducir o stock a {} porque hai {} unidades en préstamo",
+                    new_total_stock, loaned
+                )));
+            }
 
-        let difference = new_total_stock - current_total;
-        let new_available = current_available + difference;
+            let difference = new_total_stock - current_total;
 
-        self.conn.execute(
-            "UPDATE items SET total_stock = ?1, available_stock = ?2, updated_at = ?3 WHERE id = ?4",
-            params![new_total_stock, new_available, now, item_id],
-        )?;
+            // Engadir unidades novas ou dar de baixa as dispoñibles máis recentes
+            let mut retired_units: Vec<String> = Vec::new();
+            if difference > 0 {
+                for _ in 0..difference {
+                    self.insert_unit(item_id, None, None, None)?;
+                }
+            } else if difference < 0 {
+                let mut stmt = self.conn.prepare(
+                    "SELECT id FROM units WHERE item_id = ?1 AND status = 'available'
+                     ORDER BY created_at DESC, tag DESC LIMIT ?2",
+                )?;
+                retired_units = stmt
+                    .query_map(params![item_id, -difference], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<String>>>()?;
+                for unit_id in &retired_units {
+                    self.conn.execute(
+                        "UPDATE units SET status = 'retired', updated_at = ?1 WHERE id = ?2",
+                        params![now, unit_id],
+                    )?;
+                }
+            }
 
-        self.log_event(
-            "STOCK_UPDATED",
-            serde_json::json!({
-                "itemId": item_id,
-                "previousTotal": current_total,
-                "newTotal": new_total_stock,
-                "previousAvailable": current_available,
-                "newAvailable": new_available
-            }),
-            None,
-            None,
-        )?;
+            self.refresh_item_stock(item_id)?;
+            let new_available: i32 = self.conn.query_row(
+                "SELECT available_stock FROM items WHERE id = ?1",
+                [item_id],
+                |row| row.get(0),
+            )?;
+
+            self.log_event(
+                "STOCK_UPDATED",
+                serde_json::json!({
+                    "itemId": item_id,
+                    "previousTotal": current_total,
+                    "newTotal": new_total_stock,
+                    "previousAvailable": current_available,
+                    "newAvailable": new_available,
+                    "retiredUnits": &retired_units
+                }),
+                None,
+                None,
+            )?;
+
+            self.get_item_by_id(item_id)
+        })
+    }
+
+    // Cambios parciais dun artigo. O stock total pasa pola mesma comprobación
+    // que update_item_stock; no evento ITEM_UPDATED gárdase o valor anterior
+    // e o novo de cada campo cambiado.
+    pub fn update_item(&self, id: &str, req: UpdateItemRequest) -> Result<Item> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let before = self.get_item_by_id(id)?;
+
+            let name = req.name.as_deref().map(str::trim);
+            if name.is_some_and(|n| n.is_empty()) {
+                return Err(SafError::Validation(
+                    "O nome do artigo non pode quedar baleiro".to_string(),
+                ));
+            }
+
+            let mut changes = serde_json::Map::new();
+            let mut diff = |field: &str, from: serde_json::Value, to: serde_json::Value| {
+                if from != to {
+                    changes.insert(field.to_string(), serde_json::json!({"from": from, "to": to}));
+                    true
+                } else {
+                    false
+                }
+            };
+
+            let mut updates = vec![];
+            let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+
+            if let Some(name) = &name {
+                if diff("name", before.name.clone().into(), (*name).into()) {
+                    updates.push("name = ?");
+                    params.push(name);
+                }
+            }
+            if let Some(description) = &req.description {
+                if diff(
+                    "description",
+                    before.description.clone().into(),
+                    description.clone().into(),
+                ) {
+                    updates.push("description = ?");
+                    params.push(description);
+                }
+            }
+            let category = match &req.category {
+                Some(name) => Some(self.category_by_name(name, false)?),
+                None => None,
+            };
+            if let Some(category) = &category {
+                if diff(
+                    "category",
+                    before.category.clone().into(),
+                    category.name.clone().into(),
+                ) {
+                    updates.push("category = ?");
+                    params.push(&category.name);
+                    updates.push("category_id = ?");
+                    params.push(&category.id);
+                }
+            }
+            if let Some(icon) = &req.icon {
+                if diff("icon", before.icon.clone().into(), icon.clone().into()) {
+                    updates.push("icon = ?");
+                    params.push(icon);
+                }
+            }
+            if let Some(notes) = &req.notes {
+                if diff("notes", before.notes.clone().into(), notes.clone().into()) {
+                    updates.push("notes = ?");
+                    params.push(notes);
+                }
+            }
+            let stock_changed = match req.total_stock {
+                Some(total) => diff("totalStock", before.total_stock.into(), total.into()),
+                None => false,
+            };
+
+            if !updates.is_empty() {
+                updates.push("updated_at = ?");
+                params.push(&now);
+
+                let query = format!("UPDATE items SET {} WHERE id = ?", updates.join(", "));
+                params.push(&id);
+
+                self.conn.execute(&query, params.as_slice())?;
+            }
+
+            // Rexistra o seu propio STOCK_UPDATED, que se pode anular por separado
+            if let (true, Some(total)) = (stock_changed, req.total_stock) {
+                self.update_item_stock(id, total)?;
+            }
 
-        self.get_item_by_id(item_id)
+            if !changes.is_empty() {
+                self.log_event(
+                    "ITEM_UPDATED",
+                    serde_json::json!({"itemId": id, "name": name.unwrap_or(&before.name), "changes": changes}),
+                    None,
+                    None,
+                )?;
+            }
+
+            self.get_item_by_id(id)
+        })
     }
 
     pub fn get_item_by_id(&self, id: &str) -> Result<Item> {
         self.conn.query_row(
-            "SELECT id, name, description, category, icon, total_stock, available_stock, notes, created_at, updated_at 
+            "SELECT id, name, description, category, icon, total_stock, available_stock, notes, created_at, updated_at, category_id
              FROM items WHERE id = ?1",
             [id],
             |row| {
@@ -434,6 +576,7 @@ impl Database {
                     name: row.get(1)?,
                     description: row.get(2)?,
                     category: row.get(3)?,
+                    category_id: row.get(10)?,
                     icon: row.get(4)?,
                     total_stock: row.get(5)?,
                     available_stock: row.get(6)?,
@@ -443,628 +586,3263 @@ impl Database {
                 })
             },
         )
+        .optional()?
+        .ok_or_else(|| SafError::NotFound(format!("Non se atopou o artigo {}", id)))
     }
 
-    // User operations
-    pub fn create_user(&self, req: CreateUserRequest) -> Result<User> {
-        let now = Local::now().naive_local();
+    // Categorías
+    const CATEGORY_COLUMNS: &'static str =
+        "c.id, c.name, c.icon, c.sort_order, c.default_loan_days, c.active,
+         (SELECT COUNT(*) FROM items i WHERE i.category_id = c.id), c.created_at, c.updated_at";
+
+    fn map_category(row: &rusqlite::Row) -> rusqlite::Result<Category> {
+        Ok(Category {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            icon: row.get(2)?,
+            sort_order: row.get(3)?,
+            default_loan_days: row.get(4)?,
+            active: row.get(5)?,
+            item_count: row.get(6)?,
+            created_at: row.get(7)?,
+            updated_at: row.get(8)?,
+        })
+    }
+
+    pub fn get_categories(&self, include_inactive: bool) -> Result<Vec<Category>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM categories c
+             WHERE c.active = 1 OR ?1
+             ORDER BY c.sort_order, c.name",
+            Self::CATEGORY_COLUMNS
+        ))?;
+        let categories = stmt
+            .query_map([include_inactive], Self::map_category)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(categories)
+    }
+
+    pub fn get_category_by_id(&self, id: &str) -> Result<Category> {
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM categories c WHERE c.id = ?1",
+                    Self::CATEGORY_COLUMNS
+                ),
+                [id],
+                Self::map_category,
+            )
+            .optional()?
+            .ok_or_else(|| SafError::NotFound(format!("Non se atopou a categoría {}", id)))
+    }
 
-        // Check if a user with the same DNI exists (possibly deactivated)
-        let existing: Option<(String, bool)> = self
+    // Categoría dun artigo a partir do nome (sen distinguir maiúsculas).
+    // Con `create_missing` créase se non existe e admítense as retiradas.
+    fn category_by_name(&self, name: &str, create_missing: bool) -> Result<Category> {
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+        let found = self
             .conn
             .query_row(
-                "SELECT id, active FROM users WHERE dni = ?1",
-                [&req.dni],
-                |row| Ok((row.get(0)?, row.get::<_, bool>(1)?)),
+                &format!(
+                    "SELECT {} FROM categories c WHERE c.name = ?1",
+                    Self::CATEGORY_COLUMNS
+                ),
+                [&name],
+                Self::map_category,
             )
-            .ok();
+            .optional()?;
+
+        match found {
+            Some(category) if category.active || create_missing => Ok(category),
+            Some(category) => Err(SafError::Validation(format!(
+                "A categoría {} está retirada",
+                category.name
+            ))),
+            None if create_missing => self.create_category(CreateCategoryRequest {
+                name,
+                icon: None,
+                sort_order: None,
+                default_loan_days: None,
+            }),
+            None => Err(SafError::Validation(format!(
+                "Non existe a categoría '{}'",
+                name
+            ))),
+        }
+    }
+
+    fn check_category_name(&self, name: &str, except_id: Option<&str>) -> Result<String> {
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+        if name.is_empty() {
+            return Err(SafError::Validation(
+                "O nome da categoría non pode estar baleiro".to_string(),
+            ));
+        }
+        let taken: bool = self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM categories WHERE name = ?1 AND id != ?2)",
+            params![name, except_id.unwrap_or_default()],
+            |row| row.get(0),
+        )?;
+        if taken {
+            return Err(SafError::Conflict(format!(
+                "Xa existe unha categoría chamada {}",
+                name
+            )));
+        }
+        Ok(name)
+    }
+
+    pub fn create_category(&self, req: CreateCategoryRequest) -> Result<Category> {
+        self.unit_of_work(|| {
+            let id = Uuid::new_v4().to_string();
+            let now = Local::now().naive_local();
+            let name = self.check_category_name(&req.name, None)?;
+
+            // Sen orde indicada vai ao final
+            let sort_order = match req.sort_order {
+                Some(order) => order,
+                None => self.conn.query_row(
+                    "SELECT COALESCE(MAX(sort_order), -1) + 1 FROM categories",
+                    [],
+                    |row| row.get(0),
+                )?,
+            };
 
-        if let Some((existing_id, active)) = existing {
-            if active {
-                return Err(rusqlite::Error::InvalidParameterName(
-                    "Xa existe un usuario activo con ese DNI".to_string(),
-                ));
-            }
-            // Reactivate existing user with updated data
             self.conn.execute(
-                "UPDATE users SET name = ?1, address = ?2, phone = ?3, email = ?4, notes = ?5, active = 1, updated_at = ?6 WHERE id = ?7",
-                params![req.name, req.address, req.phone, req.email, req.notes, now, existing_id],
+                "INSERT INTO categories (id, name, icon, sort_order, default_loan_days, active, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6, ?6)",
+                params![
+                    id,
+                    name,
+                    req.icon.as_deref().unwrap_or("📦"),
+                    sort_order,
+                    req.default_loan_days.filter(|d| *d > 0),
+                    now
+                ],
             )?;
+
             self.log_event(
-                "USER_REACTIVATED",
-                serde_json::json!({"userId": &existing_id, "name": &req.name, "dni": &req.dni}),
+                "CATEGORY_CREATED",
+                serde_json::json!({"categoryId": &id, "name": &name}),
+                None,
                 None,
-                Some(&existing_id),
             )?;
-            return self.get_user_by_id(&existing_id);
-        }
 
-        let id = Uuid::new_v4().to_string();
-        self.conn.execute(
-            "INSERT INTO users (id, name, dni, address, phone, email, notes, active, created_at, updated_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 1, ?8, ?8)",
-            params![
-                id, req.name, req.dni, req.address, req.phone, req.email, req.notes, now
-            ],
-        )?;
+            self.get_category_by_id(&id)
+        })
+    }
 
-        self.log_event(
-            "USER_CREATED",
-            serde_json::json!({"userId": &id, "name": &req.name}),
-            None,
-            Some(&id),
-        )?;
+    // Cambios nunha categoría. Ao renomeala cámbiase tamén o nome nos seus
+    // artigos; `default_loan_days = 0` quita a duración por defecto.
+    pub fn update_category(&self, id: &str, req: UpdateCategoryRequest) -> Result<Category> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let before = self.get_category_by_id(id)?;
 
-        self.get_user_by_id(&id)
-    }
+            let name = match &req.name {
+                Some(name) => Some(self.check_category_name(name, Some(id))?),
+                None => None,
+            };
+            let default_loan_days = req.default_loan_days.map(|d| Some(d).filter(|d| *d > 0));
 
-    pub fn get_user_by_id(&self, id: &str) -> Result<User> {
-        self.conn.query_row(
-            "SELECT id, name, dni, address, phone, email, notes, active, created_at, updated_at 
-             FROM users WHERE id = ?1",
-            [id],
-            |row| {
-                Ok(User {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    dni: row.get(2)?,
-                    address: row.get(3)?,
-                    phone: row.get(4)?,
-                    email: row.get(5)?,
-                    notes: row.get(6)?,
-                    active: row.get(7)?,
-                    created_at: row.get(8)?,
-                    updated_at: row.get(9)?,
-                })
-            },
-        )
-    }
+            let mut updates = vec![];
+            let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
 
-    pub fn get_users(&self) -> Result<Vec<User>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, name, dni, address, phone, email, notes, active, created_at, updated_at 
-             FROM users WHERE active = 1 ORDER BY name",
-        )?;
+            if let Some(name) = &name {
+                updates.push("name = ?");
+                params.push(name);
+            }
+            if let Some(icon) = &req.icon {
+                updates.push("icon = ?");
+                params.push(icon);
+            }
+            if let Some(sort_order) = &req.sort_order {
+                updates.push("sort_order = ?");
+                params.push(sort_order);
+            }
+            if let Some(days) = &default_loan_days {
+                updates.push("default_loan_days = ?");
+                params.push(days);
+            }
+            if let Some(active) = &req.active {
+                updates.push("active = ?");
+                params.push(active);
+            }
 
-        let users = stmt
-            .query_map([], |row| {
-                Ok(User {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    dni: row.get(2)?,
-                    address: row.get(3)?,
-                    phone: row.get(4)?,
-                    email: row.get(5)?,
-                    notes: row.get(6)?,
-                    active: row.get(7)?,
-                    created_at: row.get(8)?,
-                    updated_at: row.get(9)?,
-                })
-            })?
-            .collect::<Result<Vec<_>>>()?;
+            if !updates.is_empty() {
+                updates.push("updated_at = ?");
+                params.push(&now);
 
-        Ok(users)
+                let query = format!("UPDATE categories SET {} WHERE id = ?", updates.join(", "));
+                params.push(&id);
+                self.conn.execute(&query, params.as_slice())?;
+
+                if let Some(name) = &name {
+                    self.conn.execute(
+                        "UPDATE items SET category = ?1, updated_at = ?2 WHERE category_id = ?3",
+                        params![name, now, id],
+                    )?;
+                }
+
+                self.log_event(
+                    "CATEGORY_UPDATED",
+                    serde_json::json!({"categoryId": id, "name": &before.name, "changes": &req}),
+                    None,
+                    None,
+                )?;
+            }
+
+            self.get_category_by_id(id)
+        })
     }
 
-    pub fn search_users(&self, query: &str) -> Result<Vec<User>> {
-        let search_pattern = format!("%{}%", query);
-        let mut stmt = self.conn.prepare(
-            "SELECT id, name, dni, address, phone, email, notes, active, created_at, updated_at 
-             FROM users 
-             WHERE active = 1 AND (name LIKE ?1 OR dni LIKE ?1 OR address LIKE ?1)
-             ORDER BY name",
-        )?;
+    // Pasa os artigos de `source_id` a `target_id` e borra a categoría orixe
+    pub fn merge_categories(&self, source_id: &str, target_id: &str) -> Result<Category> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            if source_id == target_id {
+                return Err(SafError::Validation(
+                    "Non se pode fusionar unha categoría consigo mesma".to_string(),
+                ));
+            }
+            let source = self.get_category_by_id(source_id)?;
+            let target = self.get_category_by_id(target_id)?;
 
-        let users = stmt
-            .query_map([&search_pattern], |row| {
-                Ok(User {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    dni: row.get(2)?,
-                    address: row.get(3)?,
-                    phone: row.get(4)?,
-                    email: row.get(5)?,
-                    notes: row.get(6)?,
-                    active: row.get(7)?,
-                    created_at: row.get(8)?,
-                    updated_at: row.get(9)?,
-                })
-            })?
-            .collect::<Result<Vec<_>>>()?;
+            let moved = self.conn.execute(
+                "UPDATE items SET category_id = ?1, category = ?2, updated_at = ?3
+                 WHERE category_id = ?4",
+                params![target.id, target.name, now, source.id],
+            )?;
+            self.conn
+                .execute("DELETE FROM categories WHERE id = ?1", [&source.id])?;
 
-        Ok(users)
+            self.log_event(
+                "CATEGORIES_MERGED",
+                serde_json::json!({
+                    "sourceId": &source.id,
+                    "sourceName": &source.name,
+                    "targetId": &target.id,
+                    "targetName": &target.name,
+                    "movedItems": moved
+                }),
+                None,
+                None,
+            )?;
+
+            self.get_category_by_id(target_id)
+        })
     }
 
-    pub fn update_user(&self, id: &str, req: UpdateUserRequest) -> Result<User> {
-        let now = Local::now().naive_local();
+    // Unha categoría retirada segue nos seus artigos pero non se ofrece
+    // para artigos novos
+    pub fn retire_category(&self, id: &str) -> Result<Category> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let category = self.get_category_by_id(id)?;
+            if !category.active {
+                return Err(SafError::Conflict(format!(
+                    "A categoría {} xa está retirada",
+                    category.name
+                )));
+            }
 
-        let mut updates = vec![];
-        let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+            self.conn.execute(
+                "UPDATE categories SET active = 0, updated_at = ?1 WHERE id = ?2",
+                params![now, id],
+            )?;
 
-        if let Some(name) = &req.name {
-            updates.push("name = ?");
-            params.push(name);
-        }
-        if let Some(dni) = &req.dni {
-            updates.push("dni = ?");
-            params.push(dni);
-        }
-        if let Some(address) = &req.address {
-            updates.push("address = ?");
-            params.push(address);
-        }
-        if let Some(phone) = &req.phone {
-            updates.push("phone = ?");
-            params.push(phone);
-        }
-        if let Some(email) = &req.email {
-            updates.push("email = ?");
-            params.push(email);
-        }
-        if let Some(notes) = &req.notes {
-            updates.push("notes = ?");
-            params.push(notes);
-        }
+            self.log_event(
+                "CATEGORY_RETIRED",
+                serde_json::json!({"categoryId": id, "name": &category.name, "itemCount": category.item_count}),
+                None,
+                None,
+            )?;
+
+            self.get_category_by_id(id)
+        })
+    }
+
+    // Attachment operations
+
+    const ATTACHMENT_COLUMNS: &'static str =
+        "id, item_id, kind, file_name, mime_type, size, sha256, has_thumbnail, notes, created_at";
+
+    fn map_attachment(row: &rusqlite::Row) -> rusqlite::Result<Attachment> {
+        Ok(Attachment {
+            id: row.get(0)?,
+            item_id: row.get(1)?,
+            kind: AttachmentKind::parse(&row.get::<_, String>(2)?),
+            file_name: row.get(3)?,
+            mime_type: row.get(4)?,
+            size: row.get(5)?,
+            sha256: row.get(6)?,
+            has_thumbnail: row.get(7)?,
+            notes: row.get(8)?,
+            created_at: row.get(9)?,
+        })
+    }
+
+    // Fotos primeiro e despois o resto na orde na que se engadiron
+    pub fn get_attachments(&self, item_id: &str) -> Result<Vec<Attachment>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM attachments WHERE item_id = ?1
+             ORDER BY kind <> 'photo', created_at, rowid",
+            Self::ATTACHMENT_COLUMNS
+        ))?;
+        let attachments = stmt
+            .query_map([item_id], Self::map_attachment)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(attachments)
+    }
+
+    pub fn get_attachment_by_id(&self, id: &str) -> Result<Attachment> {
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM attachments WHERE id = ?1",
+                    Self::ATTACHMENT_COLUMNS
+                ),
+                [id],
+                Self::map_attachment,
+            )
+            .optional()?
+            .ok_or_else(|| SafError::NotFound(format!("Non se atopou o anexo {}", id)))
+    }
 
-        if !updates.is_empty() {
-            updates.push("updated_at = ?");
-            params.push(&now);
+    pub fn add_attachment(&self, req: AddAttachmentRequest) -> Result<Attachment> {
+        let item = self.get_item_by_id(&req.item_id)?;
+        let source = Path::new(&req.source_path);
+        // Compróbase o tamaño antes de cargar o ficheiro en memoria
+        crate::attachments::check_size(std::fs::metadata(source)?.len())?;
+        let bytes = std::fs::read(source)?;
+        let file_name = source
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("anexo")
+            .to_string();
+        let mime_type = crate::attachments::detect_mime_type(&file_name, &bytes);
+        // Sen tipo indicado, as imaxes son fotos
+        let kind = req.kind.unwrap_or(if mime_type.starts_with("image/") {
+            AttachmentKind::Photo
+        } else {
+            AttachmentKind::Other
+        });
 
-            let query = format!("UPDATE users SET {} WHERE id = ?", updates.join(", "));
-            params.push(&id);
+        let stored = self.attachments.put(&bytes, mime_type)?;
+        let result = self.unit_of_work(|| {
+            let duplicate: Option<String> = self
+                .conn
+                .query_row(
+                    "SELECT file_name FROM attachments WHERE item_id = ?1 AND sha256 = ?2",
+                    params![item.id, stored.sha256],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(existing) = duplicate {
+                return Err(SafError::Conflict(format!(
+                    "Este ficheiro xa está anexado a {} como {}",
+                    item.name, existing
+                )));
+            }
 
-            self.conn.execute(&query, params.as_slice())?;
+            let id = Uuid::new_v4().to_string();
+            let now = Local::now().naive_local();
+            let notes = req.notes.as_deref().map(str::trim).filter(|n| !n.is_empty());
+            self.conn.execute(
+                "INSERT INTO attachments (id, item_id, kind, file_name, mime_type, size, sha256, has_thumbnail, notes, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    id,
+                    item.id,
+                    kind.as_str(),
+                    file_name,
+                    mime_type,
+                    stored.size,
+                    stored.sha256,
+                    stored.has_thumbnail,
+                    notes,
+                    now
+                ],
+            )?;
 
             self.log_event(
-                "USER_UPDATED",
-                serde_json::json!({"userId": id, "changes": &req}),
+                "ATTACHMENT_ADDED",
+                serde_json::json!({
+                    "itemId": &item.id,
+                    "itemName": &item.name,
+                    "attachmentId": &id,
+                    "fileName": &file_name,
+                    "kind": kind.as_str(),
+                }),
+                None,
                 None,
-                Some(id),
             )?;
+
+            self.get_attachment_by_id(&id)
+        });
+
+        // Se non se gardou o anexo non debe quedar o ficheiro solto
+        if result.is_err() {
+            self.remove_unreferenced_files(&[stored.sha256])?;
         }
+        result
+    }
 
-        self.get_user_by_id(id)
+    // Miniatura PNG das fotos, para mostrar na ficha do artigo
+    pub fn get_attachment_thumbnail(&self, id: &str) -> Result<Vec<u8>> {
+        let attachment = self.get_attachment_by_id(id)?;
+        self.attachments
+            .read_thumbnail(&attachment.sha256)?
+            .ok_or_else(|| {
+                SafError::NotFound(format!(
+                    "O anexo {} non ten miniatura",
+                    attachment.file_name
+                ))
+            })
     }
 
-    pub fn deactivate_user(&self, id: &str) -> Result<()> {
-        let now = Local::now().naive_local();
-        self.conn.execute(
-            "UPDATE users SET active = 0, updated_at = ?1 WHERE id = ?2",
-            params![now, id],
-        )?;
-        self.log_event(
-            "USER_DEACTIVATED",
-            serde_json::json!({"userId": id}),
-            None,
-            Some(id),
-        )?;
+    pub fn export_attachment(&self, id: &str, dest_path: &str) -> Result<()> {
+        let attachment = self.get_attachment_by_id(id)?;
+        let bytes = self.attachments.read(&attachment.sha256)?;
+        std::fs::write(dest_path, bytes)?;
         Ok(())
     }
 
-    pub fn delete_item(&self, id: &str) -> Result<()> {
-        // Only allow deletion if total_stock == 0 and available_stock == 0
-        let (total, available): (i32, i32) = self.conn.query_row(
-            "SELECT total_stock, available_stock FROM items WHERE id = ?1",
-            [id],
-            |row| Ok((row.get(0)?, row.get(1)?)),
-        )?;
-        if total != 0 || available != 0 {
-            return Err(rusqlite::Error::InvalidParameterName(
-                "Non se pode eliminar un artigo con stock > 0".to_string(),
-            ));
-        }
-        let name: String =
+    pub fn delete_attachment(&self, id: &str) -> Result<()> {
+        let attachment = self.get_attachment_by_id(id)?;
+        self.unit_of_work(|| {
             self.conn
-                .query_row("SELECT name FROM items WHERE id = ?1", [id], |row| {
-                    row.get(0)
-                })?;
-        self.conn.execute("DELETE FROM items WHERE id = ?1", [id])?;
-        self.log_event(
-            "ITEM_DELETED",
-            serde_json::json!({"itemId": id, "name": name}),
-            None,
-            None,
+                .execute("DELETE FROM attachments WHERE id = ?1", [id])?;
+            self.log_event(
+                "ATTACHMENT_DELETED",
+                serde_json::json!({
+                    "itemId": &attachment.item_id,
+                    "attachmentId": id,
+                    "fileName": &attachment.file_name,
+                    "kind": attachment.kind.as_str(),
+                }),
+                None,
+                None,
+            )?;
+            Ok(())
+        })?;
+        self.remove_unreferenced_files(&[attachment.sha256])
+    }
+
+    // Borra do cartafol os ficheiros que xa non usa ningún anexo. Chámase
+    // despois de confirmar a transacción para non perder ficheiros se falla.
+    fn remove_unreferenced_files(&self, hashes: &[String]) -> Result<()> {
+        for sha256 in hashes {
+            let referenced: bool = self.conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM attachments WHERE sha256 = ?1)",
+                [sha256],
+                |row| row.get(0),
+            )?;
+            if !referenced {
+                self.attachments.remove(sha256)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Unit operations
+
+    // Recalcula os contadores do artigo a partir do estado das súas unidades
+    fn refresh_item_stock(&self, item_id: &str) -> Result<()> {
+        let now = Local::now().naive_local();
+        self.conn.execute(
+            "UPDATE items SET
+                total_stock = (SELECT COUNT(*) FROM units WHERE item_id = ?1 AND status NOT IN ('retired', 'lost')),
+                available_stock = (SELECT COUNT(*) FROM units WHERE item_id = ?1 AND status = 'available'),
+                updated_at = ?2
+             WHERE id = ?1",
+            params![item_id, now],
         )?;
         Ok(())
     }
 
-    // Item operations
-    pub fn get_items(&self) -> Result<Vec<Item>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, name, description, category, icon, total_stock, available_stock, notes, created_at, updated_at 
-             FROM items ORDER BY name"
+    fn insert_unit(
+        &self,
+        item_id: &str,
+        tag: Option<&str>,
+        acquisition_date: Option<NaiveDate>,
+        notes: Option<&str>,
+    ) -> Result<String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Local::now().naive_local();
+
+        let tag = match tag {
+            Some(tag) => tag.to_string(),
+            None => {
+                let item_name: String = self.conn.query_row(
+                    "SELECT name FROM items WHERE id = ?1",
+                    [item_id],
+                    |row| row.get(0),
+                )?;
+                next_unit_tag(&self.conn, &item_name)?
+            }
+        };
+
+        self.conn.execute(
+            "INSERT INTO units (id, item_id, tag, status, acquisition_date, notes, created_at, updated_at)
+             VALUES (?1, ?2, ?3, 'available', ?4, ?5, ?6, ?6)",
+            params![id, item_id, tag, acquisition_date, notes, now],
         )?;
 
-        let items = stmt
-            .query_map([], |row| {
-                Ok(Item {
+        Ok(id)
+    }
+
+    pub fn get_unit_by_id(&self, id: &str) -> Result<Unit> {
+        self.conn.query_row(
+            "SELECT u.id, u.item_id, i.name, u.tag, u.status, u.acquisition_date, u.notes, u.created_at, u.updated_at
+             FROM units u
+             JOIN items i ON u.item_id = i.id
+             WHERE u.id = ?1",
+            [id],
+            |row| {
+                let status_str: String = row.get(4)?;
+                Ok(Unit {
                     id: row.get(0)?,
-                    name: row.get(1)?,
-                    description: row.get(2)?,
-                    category: row.get(3)?,
-                    icon: row.get(4)?,
-                    total_stock: row.get(5)?,
-                    available_stock: row.get(6)?,
-                    notes: row.get(7)?,
-                    created_at: row.get(8)?,
-                    updated_at: row.get(9)?,
+                    item_id: row.get(1)?,
+                    item_name: row.get(2)?,
+                    tag: row.get(3)?,
+                    status: UnitStatus::parse(&status_str),
+                    acquisition_date: row.get(5)?,
+                    notes: row.get(6)?,
+                    created_at: row.get(7)?,
+                    updated_at: row.get(8)?,
                 })
-            })?
-            .collect::<Result<Vec<_>>>()?;
-
-        Ok(items)
+            },
+        )
+        .optional()?
+        .ok_or_else(|| SafError::NotFound(format!("Non se atopou a unidade {}", id)))
     }
 
-    pub fn search_items(&self, query: &str) -> Result<Vec<Item>> {
-        let search_pattern = format!("%{}%", query);
+    pub fn get_item_units(&self, item_id: &str) -> Result<Vec<Unit>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, name, description, category, icon, total_stock, available_stock, notes, created_at, updated_at 
-             FROM items 
-             WHERE name LIKE ?1 OR description LIKE ?1
-             ORDER BY name"
+            "SELECT u.id, u.item_id, i.name, u.tag, u.status, u.acquisition_date, u.notes, u.created_at, u.updated_at
+             FROM units u
+             JOIN items i ON u.item_id = i.id
+             WHERE u.item_id = ?1
+             ORDER BY u.tag",
         )?;
 
-        let items = stmt
-            .query_map([&search_pattern], |row| {
-                Ok(Item {
+        let units = stmt
+            .query_map([item_id], |row| {
+                let status_str: String = row.get(4)?;
+                Ok(Unit {
                     id: row.get(0)?,
-                    name: row.get(1)?,
-                    description: row.get(2)?,
-                    category: row.get(3)?,
-                    icon: row.get(4)?,
-                    total_stock: row.get(5)?,
-                    available_stock: row.get(6)?,
-                    notes: row.get(7)?,
-                    created_at: row.get(8)?,
-                    updated_at: row.get(9)?,
+                    item_id: row.get(1)?,
+                    item_name: row.get(2)?,
+                    tag: row.get(3)?,
+                    status: UnitStatus::parse(&status_str),
+                    acquisition_date: row.get(5)?,
+                    notes: row.get(6)?,
+                    created_at: row.get(7)?,
+                    updated_at: row.get(8)?,
                 })
             })?
-            .collect::<Result<Vec<_>>>()?;
+            .collect::<rusqlite::Result<Vec<_>>>()?;
 
-        Ok(items)
+        Ok(units)
     }
 
-    // Loan operations
-    pub fn create_loan(&self, req: CreateLoanRequest) -> Result<Loan> {
-        let id = Uuid::new_v4().to_string();
-        let now = Local::now().naive_local();
-
-        // Check user exists
-        let _user: User = self.get_user_by_id(&req.user_id)?;
+    pub fn add_unit(&self, req: CreateUnitRequest) -> Result<Unit> {
+        self.unit_of_work(|| {
+            let id = self.insert_unit(
+                &req.item_id,
+                req.tag.as_deref(),
+                req.acquisition_date,
+                req.notes.as_deref(),
+            )?;
+            self.refresh_item_stock(&req.item_id)?;
 
-        // Check items availability
-        for item_id in &req.item_ids {
-            let available: i32 = self.conn.query_row(
-                "SELECT available_stock FROM items WHERE id = ?1",
-                [item_id],
-                |row| row.get(0),
+            let unit = self.get_unit_by_id(&id)?;
+            self.log_event(
+                "UNIT_ADDED",
+                serde_json::json!({"unitId": &id, "itemId": &req.item_id, "tag": &unit.tag}),
+                None,
+                None,
             )?;
 
-            if available < 1 {
-                return Err(rusqlite::Error::InvalidParameterName(format!(
-                    "Item {} not available",
-                    item_id
-                )));
+            Ok(unit)
+        })
+    }
+
+    pub fn update_unit(&self, id: &str, req: UpdateUnitRequest) -> Result<Unit> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+
+            let mut updates = vec![];
+            let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+
+            if let Some(tag) = &req.tag {
+                updates.push("tag = ?");
+                params.push(tag);
+            }
+            if let Some(acquisition_date) = &req.acquisition_date {
+                updates.push("acquisition_date = ?");
+                params.push(acquisition_date);
+            }
+            if let Some(notes) = &req.notes {
+                updates.push("notes = ?");
+                params.push(notes);
             }
-        }
 
-        // Create loan
-        self.conn.execute(
-            "INSERT INTO loans (id, user_id, start_date, expected_end_date, status, notes, created_at, updated_at)
-             VALUES (?1, ?2, ?3, ?4, 'active', ?5, ?6, ?6)",
-            params![id, req.user_id, req.start_date, req.expected_end_date, req.notes, now],
-        )?;
+            if !updates.is_empty() {
+                updates.push("updated_at = ?");
+                params.push(&now);
 
-        // Add loan items and update stock
-        for item_id in &req.item_ids {
-            let loan_item_id = Uuid::new_v4().to_string();
-            self.conn.execute(
-                "INSERT INTO loan_items (id, loan_id, item_id, quantity) VALUES (?1, ?2, ?3, 1)",
-                params![loan_item_id, id, item_id],
-            )?;
+                let query = format!("UPDATE units SET {} WHERE id = ?", updates.join(", "));
+                params.push(&id);
+
+                self.conn.execute(&query, params.as_slice())?;
+
+                self.log_event(
+                    "UNIT_UPDATED",
+                    serde_json::json!({"unitId": id, "changes": &req}),
+                    None,
+                    None,
+                )?;
+            }
+
+            self.get_unit_by_id(id)
+        })
+    }
+
+    // Dar de baixa unha unidade (só se non está prestada)
+    pub fn retire_unit(&self, id: &str, reason: Option<String>) -> Result<Unit> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let unit = self.get_unit_by_id(id)?;
+
+            if unit.status != UnitStatus::Available {
+                return Err(SafError::Conflict(format!(
+                    "Non se pode dar de baixa a unidade {} porque non está dispoñible",
+                    unit.tag
+                )));
+            }
 
-            // Update stock
             self.conn.execute(
-                "UPDATE items SET available_stock = available_stock - 1, updated_at = ?1 WHERE id = ?2",
-                params![now, item_id],
+                "UPDATE units SET status = 'retired', updated_at = ?1 WHERE id = ?2",
+                params![now, id],
             )?;
-        }
-
-        // Log event
-        self.log_event(
-            "LOAN_CREATED",
-            serde_json::json!({
-                "loanId": &id,
-                "userId": &req.user_id,
-                "items": &req.item_ids,
-                "startDate": &req.start_date,
-                "expectedEndDate": &req.expected_end_date
-            }),
-            Some(&id),
-            Some(&req.user_id),
-        )?;
+            self.refresh_item_stock(&unit.item_id)?;
 
-        // Log stock reserved for each item
-        for item_id in &req.item_ids {
             self.log_event(
-                "STOCK_RESERVED",
+                "UNIT_RETIRED",
                 serde_json::json!({
-                    "itemId": item_id,
-                    "quantity": 1,
-                    "loanId": &id
+                    "unitId": id,
+                    "itemId": &unit.item_id,
+                    "tag": &unit.tag,
+                    "reason": &reason
                 }),
-                Some(&id),
-                Some(&req.user_id),
+                None,
+                None,
             )?;
-        }
 
-        self.get_loan_by_id(&id)
+            self.get_unit_by_id(id)
+        })
     }
 
-    pub fn get_loan_by_id(&self, id: &str) -> Result<Loan> {
-        let loan = self.conn.query_row(
-            "SELECT l.id, l.user_id, u.name as user_name, l.start_date, l.expected_end_date, 
-                    l.actual_end_date, l.status, l.notes, l.created_at, l.updated_at
-             FROM loans l
-             JOIN users u ON l.user_id = u.id
-             WHERE l.id = ?1",
-            [id],
-            |row| {
-                let status_str: String = row.get(6)?;
-                let status = match status_str.as_str() {
-                    "pending" => LoanStatus::Pending,
-                    "returned" => LoanStatus::Returned,
-                    "overdue" => LoanStatus::Overdue,
-                    _ => LoanStatus::Active,
-                };
-
-                Ok(Loan {
-                    id: row.get(0)?,
-                    user_id: row.get(1)?,
-                    user_name: row.get(2)?,
-                    start_date: row.get(3)?,
-                    expected_end_date: row.get(4)?,
-                    actual_end_date: row.get(5)?,
-                    status,
-                    notes: row.get(7)?,
-                    created_at: row.get(8)?,
-                    updated_at: row.get(9)?,
-                    items: vec![],
-                })
-            },
+    // Historial de préstamos nos que saíu unha unidade concreta
+    pub fn get_unit_loans(&self, unit_id: &str) -> Result<Vec<Loan>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT li.loan_id, l.created_at
+             FROM loan_item_units liu
+             JOIN loan_items li ON liu.loan_item_id = li.id
+             JOIN loans l ON li.loan_id = l.id
+             WHERE liu.unit_id = ?1
+             ORDER BY l.created_at DESC",
         )?;
 
-        // Get loan items
-        let items = self.get_loan_items(id)?;
+        let loan_ids = stmt
+            .query_map([unit_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
 
-        Ok(Loan { items, ..loan })
+        loan_ids.iter().map(|id| self.get_loan_by_id(id)).collect()
     }
 
-    fn get_loan_items(&self, loan_id: &str) -> Result<Vec<LoanItem>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT li.id, li.loan_id, li.item_id, i.name as item_name, li.quantity
-             FROM loan_items li
-             JOIN items i ON li.item_id = i.id
-             WHERE li.loan_id = ?1",
-        )?;
+    // Mantemento e reparacións
+    const MAINTENANCE_SELECT: &'static str =
+        "SELECT m.id, m.item_id, i.name, m.unit_id, u.tag, m.loan_id, m.opened_at, m.description,
+                m.cost, m.closed_at, m.outcome, m.notes, m.created_at, m.updated_at
+         FROM maintenance_orders m
+         JOIN items i ON m.item_id = i.id
+         JOIN units u ON m.unit_id = u.id";
 
-        let items = stmt
-            .query_map([loan_id], |row| {
-                Ok(LoanItem {
-                    id: row.get(0)?,
-                    loan_id: row.get(1)?,
-                    item_id: row.get(2)?,
-                    item_name: row.get(3)?,
-                    quantity: row.get(4)?,
-                })
-            })?
-            .collect::<Result<Vec<_>>>()?;
+    fn map_maintenance_order(row: &rusqlite::Row) -> rusqlite::Result<MaintenanceOrder> {
+        Ok(MaintenanceOrder {
+            id: row.get(0)?,
+            item_id: row.get(1)?,
+            item_name: row.get(2)?,
+            unit_id: row.get(3)?,
+            unit_tag: row.get(4)?,
+            loan_id: row.get(5)?,
+            opened_at: row.get(6)?,
+            description: row.get(7)?,
+            cost: row.get(8)?,
+            closed_at: row.get(9)?,
+            outcome: row
+                .get::<_, Option<String>>(10)?
+                .and_then(|o| MaintenanceOutcome::parse(&o)),
+            notes: row.get(11)?,
+            created_at: row.get(12)?,
+            updated_at: row.get(13)?,
+        })
+    }
 
-        Ok(items)
+    pub fn get_maintenance_order_by_id(&self, id: &str) -> Result<MaintenanceOrder> {
+        self.conn
+            .query_row(
+                &format!("{} WHERE m.id = ?1", Self::MAINTENANCE_SELECT),
+                [id],
+                Self::map_maintenance_order,
+            )
+            .optional()?
+            .ok_or_else(|| SafError::NotFound(format!("Non se atopou a orde de mantemento {}", id)))
     }
 
-    pub fn get_loans(&self) -> Result<Vec<Loan>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT l.id, l.user_id, u.name as user_name, l.start_date, l.expected_end_date, 
-                    l.actual_end_date, l.status, l.notes, l.created_at, l.updated_at
-             FROM loans l
-             JOIN users u ON l.user_id = u.id
-             ORDER BY l.created_at DESC",
-        )?;
+    pub fn get_maintenance_orders(
+        &self,
+        item_id: Option<&str>,
+        open_only: bool,
+    ) -> Result<Vec<MaintenanceOrder>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{} WHERE (?1 IS NULL OR m.item_id = ?1) AND (?2 = 0 OR m.closed_at IS NULL)
+             ORDER BY m.opened_at DESC, m.created_at DESC",
+            Self::MAINTENANCE_SELECT
+        ))?;
 
-        let loans = stmt
-            .query_map([], |row| {
-                let status_str: String = row.get(6)?;
-                let status = match status_str.as_str() {
-                    "pending" => LoanStatus::Pending,
-                    "returned" => LoanStatus::Returned,
-                    "overdue" => LoanStatus::Overdue,
-                    _ => LoanStatus::Active,
-                };
+        let orders = stmt
+            .query_map(params![item_id, open_only], Self::map_maintenance_order)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
 
-                Ok(Loan {
-                    id: row.get(0)?,
-                    user_id: row.get(1)?,
-                    user_name: row.get(2)?,
-                    start_date: row.get(3)?,
-                    expected_end_date: row.get(4)?,
-                    actual_end_date: row.get(5)?,
-                    status,
+        Ok(orders)
+    }
+
+    // Crea a orde e pasa a unidade a reparación
+    fn insert_maintenance_order(
+        &self,
+        unit: &Unit,
+        loan_id: Option<&str>,
+        opened_at: NaiveDate,
+        description: &str,
+        cost: Option<f64>,
+        notes: Option<&str>,
+    ) -> Result<String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Local::now().naive_local();
+
+        self.conn.execute(
+            "INSERT INTO maintenance_orders (id, item_id, unit_id, loan_id, opened_at, description, cost, notes, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9)",
+            params![id, unit.item_id, unit.id, loan_id, opened_at, description, cost, notes, now],
+        )?;
+        self.conn.execute(
+            "UPDATE units SET status = 'in_repair', updated_at = ?1 WHERE id = ?2",
+            params![now, unit.id],
+        )?;
+        self.refresh_item_stock(&unit.item_id)?;
+
+        self.log_event(
+            "MAINTENANCE_OPENED",
+            serde_json::json!({
+                "orderId": &id,
+                "itemId": &unit.item_id,
+                "unitId": &unit.id,
+                "tag": &unit.tag,
+                "loanId": loan_id,
+                "description": description,
+                "cost": cost
+            }),
+            loan_id,
+            None,
+        )?;
+
+        Ok(id)
+    }
+
+    pub fn open_maintenance_order(
+        &self,
+        req: CreateMaintenanceOrderRequest,
+    ) -> Result<MaintenanceOrder> {
+        self.unit_of_work(|| {
+            let unit_id = match &req.unit_id {
+                Some(unit_id) => unit_id.clone(),
+                None => {
+                    let available: Option<String> = self
+                        .conn
+                        .query_row(
+                            "SELECT id FROM units WHERE item_id = ?1 AND status = 'available' ORDER BY tag LIMIT 1",
+                            [&req.item_id],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    match available {
+                        Some(unit_id) => unit_id,
+                        None => {
+                            let item = self.get_item_by_id(&req.item_id)?;
+                            return Err(SafError::StockUnavailable {
+                                item_id: item.id,
+                                item_name: item.name,
+                                requested: 1,
+                                available: 0,
+                            });
+                        }
+                    }
+                }
+            };
+            let unit = self.get_unit_by_id(&unit_id)?;
+
+            if unit.item_id != req.item_id {
+                return Err(SafError::Validation(format!(
+                    "A unidade {} non pertence a este artigo",
+                    unit.tag
+                )));
+            }
+
+            // Unha unidade devolta danada xa está en reparación, pero só pode ter unha orde aberta
+            let has_open_order: bool = self.conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM maintenance_orders WHERE unit_id = ?1 AND closed_at IS NULL)",
+                [&unit.id],
+                |row| row.get(0),
+            )?;
+            if has_open_order
+                || !matches!(unit.status, UnitStatus::Available | UnitStatus::InRepair)
+            {
+                return Err(SafError::Conflict(format!(
+                    "A unidade {} non se pode enviar a mantemento",
+                    unit.tag
+                )));
+            }
+
+            let opened_at = req
+                .opened_at
+                .unwrap_or_else(|| Local::now().naive_local().date());
+            let id = self.insert_maintenance_order(
+                &unit,
+                None,
+                opened_at,
+                &req.description,
+                req.cost,
+                req.notes.as_deref(),
+            )?;
+
+            self.get_maintenance_order_by_id(&id)
+        })
+    }
+
+    pub fn update_maintenance_order(
+        &self,
+        id: &str,
+        req: UpdateMaintenanceOrderRequest,
+    ) -> Result<MaintenanceOrder> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+
+            let mut updates = vec![];
+            let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+
+            if let Some(description) = &req.description {
+                updates.push("description = ?");
+                params.push(description);
+            }
+            if let Some(cost) = &req.cost {
+                updates.push("cost = ?");
+                params.push(cost);
+            }
+            if let Some(notes) = &req.notes {
+                updates.push("notes = ?");
+                params.push(notes);
+            }
+
+            if !updates.is_empty() {
+                updates.push("updated_at = ?");
+                params.push(&now);
+
+                let query = format!(
+                    "UPDATE maintenance_orders SET {} WHERE id = ?",
+                    updates.join(", ")
+                );
+                params.push(&id);
+
+                self.conn.execute(&query, params.as_slice())?;
+
+                self.log_event(
+                    "MAINTENANCE_UPDATED",
+                    serde_json::json!({"orderId": id, "changes": &req}),
+                    None,
+                    None,
+                )?;
+            }
+
+            self.get_maintenance_order_by_id(id)
+        })
+    }
+
+    // Pechar a orde: a unidade reparada volve estar dispoñible e a irrecuperable dáse de baixa
+    pub fn close_maintenance_order(
+        &self,
+        id: &str,
+        req: CloseMaintenanceOrderRequest,
+    ) -> Result<MaintenanceOrder> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let order = self.get_maintenance_order_by_id(id)?;
+
+            if order.closed_at.is_some() {
+                return Err(SafError::Conflict(
+                    "Esta orde de mantemento xa está pechada".to_string(),
+                ));
+            }
+
+            let closed_at = req.closed_at.unwrap_or_else(|| now.date());
+            if closed_at < order.opened_at {
+                return Err(SafError::Validation(
+                    "A data de peche non pode ser anterior á de apertura".to_string(),
+                ));
+            }
+
+            self.conn.execute(
+                "UPDATE maintenance_orders SET closed_at = ?1, outcome = ?2,
+                        cost = COALESCE(?3, cost), notes = COALESCE(?4, notes), updated_at = ?5
+                 WHERE id = ?6",
+                params![
+                    closed_at,
+                    req.outcome.as_str(),
+                    req.cost,
+                    req.notes,
+                    now,
+                    id
+                ],
+            )?;
+
+            let unit_status = match req.outcome {
+                MaintenanceOutcome::WrittenOff => UnitStatus::Retired,
+                MaintenanceOutcome::Repaired | MaintenanceOutcome::Cancelled => {
+                    UnitStatus::Available
+                }
+            };
+            self.conn.execute(
+                "UPDATE units SET status = ?1, updated_at = ?2 WHERE id = ?3",
+                params![unit_status.as_str(), now, order.unit_id],
+            )?;
+            self.refresh_item_stock(&order.item_id)?;
+
+            self.log_event(
+                "MAINTENANCE_CLOSED",
+                serde_json::json!({
+                    "orderId": id,
+                    "itemId": &order.item_id,
+                    "unitId": &order.unit_id,
+                    "outcome": req.outcome.as_str(),
+                    "cost": req.cost.or(order.cost)
+                }),
+                order.loan_id.as_deref(),
+                None,
+            )?;
+
+            self.get_maintenance_order_by_id(id)
+        })
+    }
+
+    // Reserva as unidades dunha liña de préstamo e devolve os seus ids
+    fn reserve_units(&self, loan_item_id: &str, line: &LoanItemRequest) -> Result<Vec<String>> {
+        let now = Local::now().naive_local();
+
+        let unit_ids: Vec<String> = match &line.unit_ids {
+            Some(ids) => {
+                if ids.len() != line.quantity as usize {
+                    return Err(SafError::Validation(format!(
+                        "Indicáronse {} unidades pero a cantidade é {}",
+                        ids.len(),
+                        line.quantity
+                    )));
+                }
+                if ids.iter().collect::<HashSet<_>>().len() != ids.len() {
+                    return Err(SafError::Validation(
+                        "Hai unidades repetidas no préstamo".to_string(),
+                    ));
+                }
+                for unit_id in ids {
+                    let unit = self.get_unit_by_id(unit_id)?;
+                    if unit.item_id != line.item_id {
+                        return Err(SafError::Validation(format!(
+                            "A unidade {} non pertence a este artigo",
+                            unit.tag
+                        )));
+                    }
+                    if unit.status != UnitStatus::Available {
+                        return Err(SafError::Conflict(format!(
+                            "A unidade {} non está dispoñible",
+                            unit.tag
+                        )));
+                    }
+                }
+                ids.clone()
+            }
+            None => {
+                let mut stmt = self.conn.prepare(
+                    "SELECT id FROM units WHERE item_id = ?1 AND status = 'available'
+                     ORDER BY tag LIMIT ?2",
+                )?;
+                let ids = stmt
+                    .query_map(params![line.item_id, line.quantity], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<String>>>()?;
+                if ids.len() < line.quantity as usize {
+                    let item = self.get_item_by_id(&line.item_id)?;
+                    return Err(SafError::StockUnavailable {
+                        item_id: item.id,
+                        item_name: item.name,
+                        requested: line.quantity,
+                        available: ids.len() as i32,
+                    });
+                }
+                ids
+            }
+        };
+
+        for unit_id in &unit_ids {
+            self.conn.execute(
+                "UPDATE units SET status = 'loaned', updated_at = ?1 WHERE id = ?2",
+                params![now, unit_id],
+            )?;
+            self.conn.execute(
+                "INSERT INTO loan_item_units (loan_item_id, unit_id) VALUES (?1, ?2)",
+                params![loan_item_id, unit_id],
+            )?;
+        }
+
+        Ok(unit_ids)
+    }
+
+    // User operations
+    pub fn create_user(&self, req: CreateUserRequest) -> Result<User> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let dni = crate::dni::document_from_request(req.dni.as_deref(), req.no_document)?;
+
+            // Check if a user with the same DNI exists (possibly deactivated)
+            let existing: Option<(String, bool)> = match &dni {
+                Some(dni) => self
+                    .conn
+                    .query_row(
+                        "SELECT id, active FROM users WHERE dni = ?1",
+                        [dni],
+                        |row| Ok((row.get(0)?, row.get::<_, bool>(1)?)),
+                    )
+                    .optional()?,
+                None => None,
+            };
+
+            if let Some((existing_id, active)) = existing {
+                if active {
+                    return Err(SafError::Conflict(
+                        "Xa existe un usuario activo con ese DNI".to_string(),
+                    ));
+                }
+                // Reactivate existing user with updated data
+                self.conn.execute(
+                    "UPDATE users SET name = ?1, address = ?2, phone = ?3, email = ?4, notes = ?5, active = 1, updated_at = ?6 WHERE id = ?7",
+                    params![req.name, req.address, req.phone, req.email, req.notes, now, existing_id],
+                )?;
+                self.log_event(
+                    "USER_REACTIVATED",
+                    serde_json::json!({"userId": &existing_id, "name": &req.name, "dni": &dni}),
+                    None,
+                    Some(&existing_id),
+                )?;
+                return self.get_user_by_id(&existing_id);
+            }
+
+            let id = Uuid::new_v4().to_string();
+            self.conn.execute(
+                "INSERT INTO users (id, name, dni, address, phone, email, notes, active, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 1, ?8, ?8)",
+                params![id, req.name, dni, req.address, req.phone, req.email, req.notes, now],
+            )?;
+
+            self.log_event(
+                "USER_CREATED",
+                serde_json::json!({"userId": &id, "name": &req.name}),
+                None,
+                Some(&id),
+            )?;
+
+            self.get_user_by_id(&id)
+        })
+    }
+
+    pub fn get_user_by_id(&self, id: &str) -> Result<User> {
+        self.conn.query_row(
+            "SELECT id, name, dni, address, phone, email, notes, active, created_at, updated_at 
+             FROM users WHERE id = ?1",
+            [id],
+            |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    dni: row.get(2)?,
+                    address: row.get(3)?,
+                    phone: row.get(4)?,
+                    email: row.get(5)?,
+                    notes: row.get(6)?,
+                    active: row.get(7)?,
+                    created_at: row.get(8)?,
+                    updated_at: row.get(9)?,
+                })
+            },
+        )
+        .optional()?
+        .ok_or_else(|| SafError::NotFound(format!("Non se atopou a persoa usuaria {}", id)))
+    }
+
+    // Persoas usuarias (activas ou non) indexadas por id, nunha soa consulta
+    pub fn get_users_by_ids(&self, ids: &[String]) -> Result<HashMap<String, User>> {
+        let ids = serde_json::json!(ids).to_string();
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, dni, address, phone, email, notes, active, created_at, updated_at
+             FROM users WHERE id IN (SELECT value FROM json_each(?1))",
+        )?;
+
+        let users = stmt
+            .query_map([&ids], |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    dni: row.get(2)?,
+                    address: row.get(3)?,
+                    phone: row.get(4)?,
+                    email: row.get(5)?,
+                    notes: row.get(6)?,
+                    active: row.get(7)?,
+                    created_at: row.get(8)?,
+                    updated_at: row.get(9)?,
+                })
+            })?
+            .map(|user| user.map(|u| (u.id.clone(), u)))
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+
+        Ok(users)
+    }
+
+    pub fn get_users(&self) -> Result<Vec<User>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, dni, address, phone, email, notes, active, created_at, updated_at 
+             FROM users WHERE active = 1 ORDER BY name",
+        )?;
+
+        let users = stmt
+            .query_map([], |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    dni: row.get(2)?,
+                    address: row.get(3)?,
+                    phone: row.get(4)?,
+                    email: row.get(5)?,
+                    notes: row.get(6)?,
+                    active: row.get(7)?,
+                    created_at: row.get(8)?,
+                    updated_at: row.get(9)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(users)
+    }
+
+    pub fn search_users(&self, query: &str) -> Result<Vec<User>> {
+        if fts_query(query).is_none() {
+            return self.get_users();
+        }
+        Ok(self
+            .search_user_hits(query, -1)?
+            .into_iter()
+            .map(|hit| hit.user)
+            .collect())
+    }
+
+    // Usuarios activos que coinciden co texto, ordenados por relevancia (o
+    // nome pesa máis ca o resto de campos). Se a busca só ten números
+    // tamén se buscan como fragmento do teléfono ou do DNI.
+    fn search_user_hits(&self, query: &str, limit: i64) -> Result<Vec<UserSearchHit>> {
+        let map_hit = |row: &rusqlite::Row| {
+            Ok(UserSearchHit {
+                user: User {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    dni: row.get(2)?,
+                    address: row.get(3)?,
+                    phone: row.get(4)?,
+                    email: row.get(5)?,
+                    notes: row.get(6)?,
+                    active: row.get(7)?,
+                    created_at: row.get(8)?,
+                    updated_at: row.get(9)?,
+                },
+                snippet: row.get(10)?,
+            })
+        };
+
+        let mut hits = match fts_query(query) {
+            Some(fts) => {
+                let mut stmt = self.conn.prepare(
+                    "SELECT u.id, u.name, u.dni, u.address, u.phone, u.email, u.notes, u.active, u.created_at, u.updated_at,
+                            snippet(users_fts, -1, '[', ']', '…', 8)
+                     FROM users_fts f
+                     JOIN users u ON u.id = f.user_id
+                     WHERE users_fts MATCH ?1 AND u.active = 1
+                     ORDER BY bm25(users_fts, 0.0, 10.0, 5.0, 2.0, 2.0, 1.0), u.name
+                     LIMIT ?2",
+                )?;
+                let hits = stmt
+                    .query_map(params![fts, limit], map_hit)?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                hits
+            }
+            None => vec![],
+        };
+
+        let digits: String = query.chars().filter(|c| c.is_ascii_digit()).collect();
+        if digits.len() >= 3 && !query.chars().any(char::is_alphabetic) {
+            let mut stmt = self.conn.prepare(
+                "SELECT id, name, dni, address, phone, email, notes, active, created_at, updated_at,
+                        COALESCE(phone, dni)
+                 FROM users
+                 WHERE active = 1
+                   AND (replace(replace(replace(COALESCE(phone, ''), ' ', ''), '-', ''), '.', '') LIKE ?1
+                        OR dni LIKE ?1)
+                 ORDER BY name",
+            )?;
+            let extra = stmt
+                .query_map([format!("%{}%", digits)], map_hit)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for hit in extra {
+                if !hits.iter().any(|h| h.user.id == hit.user.id) {
+                    hits.push(hit);
+                }
+            }
+            if limit >= 0 {
+                hits.truncate(limit as usize);
+            }
+        }
+
+        Ok(hits)
+    }
+
+    pub fn update_user(&self, id: &str, req: UpdateUserRequest) -> Result<User> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+
+            // None: non se cambia; Some(None): a persoa non ten documento
+            let dni: Option<Option<String>> = if req.no_document {
+                Some(None)
+            } else {
+                match req.dni.as_deref().map(str::trim).filter(|d| !d.is_empty()) {
+                    Some(dni) => Some(Some(crate::dni::normalize_dni(dni)?)),
+                    None => None,
+                }
+            };
+            if let Some(Some(dni)) = &dni {
+                let taken: bool = self.conn.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM users WHERE dni = ?1 AND id != ?2)",
+                    params![dni, id],
+                    |row| row.get(0),
+                )?;
+                if taken {
+                    return Err(SafError::Conflict(
+                        "Xa existe outro usuario con ese DNI".to_string(),
+                    ));
+                }
+            }
+
+            let mut updates = vec![];
+            let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+
+            if let Some(name) = &req.name {
+                updates.push("name = ?");
+                params.push(name);
+            }
+            if let Some(dni) = &dni {
+                updates.push("dni = ?");
+                params.push(dni);
+            }
+            if let Some(address) = &req.address {
+                updates.push("address = ?");
+                params.push(address);
+            }
+            if let Some(phone) = &req.phone {
+                updates.push("phone = ?");
+                params.push(phone);
+            }
+            if let Some(email) = &req.email {
+                updates.push("email = ?");
+                params.push(email);
+            }
+            if let Some(notes) = &req.notes {
+                updates.push("notes = ?");
+                params.push(notes);
+            }
+
+            if !updates.is_empty() {
+                updates.push("updated_at = ?");
+                params.push(&now);
+
+                let query = format!("UPDATE users SET {} WHERE id = ?", updates.join(", "));
+                params.push(&id);
+
+                self.conn.execute(&query, params.as_slice())?;
+
+                self.log_event(
+                    "USER_UPDATED",
+                    serde_json::json!({"userId": id, "changes": &req}),
+                    None,
+                    Some(id),
+                )?;
+            }
+
+            self.get_user_by_id(id)
+        })
+    }
+
+    // Informe dos DNI/NIE gardados que non son válidos ou que non se puideron
+    // pasar á forma canónica (p.ex. porque quedarían duplicados)
+    pub fn get_invalid_dnis(&self) -> Result<Vec<InvalidDniEntry>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, name, dni FROM users WHERE dni IS NOT NULL ORDER BY name")?;
+        let users = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut entries = vec![];
+        for (user_id, name, dni) in users {
+            let reason = match crate::dni::normalize_dni(&dni) {
+                Err(e) => e.to_string(),
+                Ok(canonical) if canonical != dni => {
+                    format!("Non está en forma canónica ({})", canonical)
+                }
+                Ok(_) => continue,
+            };
+            entries.push(InvalidDniEntry {
+                user_id,
+                name,
+                dni,
+                reason,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    pub fn deactivate_user(&self, id: &str) -> Result<()> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            self.conn.execute(
+                "UPDATE users SET active = 0, updated_at = ?1 WHERE id = ?2",
+                params![now, id],
+            )?;
+            self.log_event(
+                "USER_DEACTIVATED",
+                serde_json::json!({"userId": id}),
+                None,
+                Some(id),
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn delete_item(&self, id: &str) -> Result<()> {
+        let hashes = self.unit_of_work(|| {
+            // Only allow deletion if total_stock == 0 and available_stock == 0
+            let (total, available): (i32, i32) = self.conn.query_row(
+                "SELECT total_stock, available_stock FROM items WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            if total != 0 || available != 0 {
+                return Err(SafError::Conflict(
+                    "Non se pode eliminar un artigo con stock > 0".to_string(),
+                ));
+            }
+            let name: String =
+                self.conn
+                    .query_row("SELECT name FROM items WHERE id = ?1", [id], |row| {
+                        row.get(0)
+                    })?;
+            self.conn
+                .execute("DELETE FROM maintenance_orders WHERE item_id = ?1", [id])?;
+            self.conn
+                .execute("DELETE FROM units WHERE item_id = ?1", [id])?;
+            let hashes = {
+                let mut stmt = self
+                    .conn
+                    .prepare("SELECT DISTINCT sha256 FROM attachments WHERE item_id = ?1")?;
+                let rows = stmt
+                    .query_map([id], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                rows
+            };
+            self.conn
+                .execute("DELETE FROM attachments WHERE item_id = ?1", [id])?;
+            self.conn.execute("DELETE FROM items WHERE id = ?1", [id])?;
+            self.log_event(
+                "ITEM_DELETED",
+                serde_json::json!({"itemId": id, "name": name}),
+                None,
+                None,
+            )?;
+            Ok(hashes)
+        })?;
+        self.remove_unreferenced_files(&hashes)
+    }
+
+    // Item operations
+    pub fn get_items(&self) -> Result<Vec<Item>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, description, category, icon, total_stock, available_stock, notes, created_at, updated_at, category_id
+             FROM items ORDER BY name"
+        )?;
+
+        let items = stmt
+            .query_map([], |row| {
+                Ok(Item {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    description: row.get(2)?,
+                    category: row.get(3)?,
+                    category_id: row.get(10)?,
+                    icon: row.get(4)?,
+                    total_stock: row.get(5)?,
+                    available_stock: row.get(6)?,
                     notes: row.get(7)?,
                     created_at: row.get(8)?,
                     updated_at: row.get(9)?,
-                    items: vec![],
                 })
             })?
-            .collect::<Result<Vec<_>>>()?;
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(items)
+    }
+
+    pub fn search_items(&self, query: &str) -> Result<Vec<Item>> {
+        if fts_query(query).is_none() {
+            return self.get_items();
+        }
+        Ok(self
+            .search_item_hits(query, -1)?
+            .into_iter()
+            .map(|hit| hit.item)
+            .collect())
+    }
+
+    fn search_item_hits(&self, query: &str, limit: i64) -> Result<Vec<ItemSearchHit>> {
+        let Some(fts) = fts_query(query) else {
+            return Ok(vec![]);
+        };
+        let mut stmt = self.conn.prepare(
+            "SELECT i.id, i.name, i.description, i.category, i.icon, i.total_stock, i.available_stock, i.notes, i.created_at, i.updated_at, i.category_id,
+                    snippet(items_fts, -1, '[', ']', '…', 8)
+             FROM items_fts f
+             JOIN items i ON i.id = f.item_id
+             WHERE items_fts MATCH ?1
+             ORDER BY bm25(items_fts, 0.0, 10.0, 3.0, 3.0, 1.0), i.name
+             LIMIT ?2",
+        )?;
+
+        let hits = stmt
+            .query_map(params![fts, limit], |row| {
+                Ok(ItemSearchHit {
+                    item: Item {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        description: row.get(2)?,
+                        category: row.get(3)?,
+                        category_id: row.get(10)?,
+                        icon: row.get(4)?,
+                        total_stock: row.get(5)?,
+                        available_stock: row.get(6)?,
+                        notes: row.get(7)?,
+                        created_at: row.get(8)?,
+                        updated_at: row.get(9)?,
+                    },
+                    snippet: row.get(11)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(hits)
+    }
+
+    // Préstamos que mencionan o texto nas notas ou levan un artigo con ese
+    // nome. Primeiro os das notas, por relevancia; despois os máis recentes.
+    fn search_loan_hits(&self, query: &str, limit: i64) -> Result<Vec<LoanSearchHit>> {
+        let Some(fts) = fts_query(query) else {
+            return Ok(vec![]);
+        };
+        let map_hit = |matched: LoanMatch| {
+            move |row: &rusqlite::Row| {
+                Ok(LoanSearchHit {
+                    loan_id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    user_name: row.get(2)?,
+                    status: LoanStatus::parse(&row.get::<_, String>(3)?),
+                    start_date: row.get(4)?,
+                    expected_end_date: row.get(5)?,
+                    matched: matched.clone(),
+                    snippet: row.get(6)?,
+                })
+            }
+        };
+
+        let mut stmt = self.conn.prepare(
+            "SELECT l.id, l.user_id, u.name, l.status, l.start_date, l.expected_end_date,
+                    snippet(loans_fts, 1, '[', ']', '…', 8)
+             FROM loans_fts f
+             JOIN loans l ON l.id = f.loan_id
+             JOIN users u ON u.id = l.user_id
+             WHERE loans_fts MATCH ?1
+             ORDER BY bm25(loans_fts), l.start_date DESC
+             LIMIT ?2",
+        )?;
+        let mut hits = stmt
+            .query_map(params![fts, limit], map_hit(LoanMatch::Notes))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT l.id, l.user_id, u.name, l.status, l.start_date, l.expected_end_date,
+                    group_concat(DISTINCT i.name)
+             FROM items_fts f
+             JOIN loan_items li ON li.item_id = f.item_id
+             JOIN items i ON i.id = li.item_id
+             JOIN loans l ON l.id = li.loan_id
+             JOIN users u ON u.id = l.user_id
+             WHERE items_fts MATCH ?1
+             GROUP BY l.id
+             ORDER BY l.start_date DESC, l.created_at DESC
+             LIMIT ?2",
+        )?;
+        let by_item = stmt
+            .query_map(
+                params![format!("name : ({})", fts), limit],
+                map_hit(LoanMatch::ItemName),
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for hit in by_item {
+            if !hits.iter().any(|h| h.loan_id == hit.loan_id) {
+                hits.push(hit);
+            }
+        }
+        if limit >= 0 {
+            hits.truncate(limit as usize);
+        }
+
+        Ok(hits)
+    }
+
+    // Os eventos gardan os datos en JSON: búscanse todas as palabras nel,
+    // do máis recente ao máis antigo
+    fn search_event_hits(&self, query: &str, limit: i64) -> Result<Vec<EventSearchHit>> {
+        let terms = search_terms(query);
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        let patterns: Vec<String> = terms.iter().map(|t| format!("%{}%", t)).collect();
+        let conditions = vec!["(data LIKE ? OR event_type LIKE ?)"; patterns.len()].join(" AND ");
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+        for pattern in &patterns {
+            params.push(pattern);
+            params.push(pattern);
+        }
+        params.push(&limit);
+
+        let sql = format!(
+            "SELECT id, event_type, data, created_at, loan_id, user_id, item_id,
+                    cancelled_by, cancellation_reason
+             FROM events
+             WHERE {}
+             ORDER BY rowid DESC
+             LIMIT ?",
+            conditions
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let hits = stmt
+            .query_map(params.as_slice(), |row| {
+                let data: String = row.get(2)?;
+                Ok(EventSearchHit {
+                    event: Self::map_event(row)?,
+                    snippet: excerpt(&data, &terms[0]),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(hits)
+    }
+
+    // Busca un texto en usuarios, artigos, préstamos e eventos á vez.
+    // `limit` é o máximo de resultados por grupo.
+    pub fn global_search(&self, query: &str, limit: Option<i64>) -> Result<GlobalSearchResults> {
+        let limit = limit.unwrap_or(10).clamp(1, 100);
+        Ok(GlobalSearchResults {
+            users: self.search_user_hits(query, limit)?,
+            items: self.search_item_hits(query, limit)?,
+            loans: self.search_loan_hits(query, limit)?,
+            events: self.search_event_hits(query, limit)?,
+        })
+    }
+
+    // Loan operations
+    pub fn create_loan(&self, req: CreateLoanRequest) -> Result<Loan> {
+        self.unit_of_work(|| {
+            let id = Uuid::new_v4().to_string();
+            let now = Local::now().naive_local();
+
+            // Check user exists
+            let _user: User = self.get_user_by_id(&req.user_id)?;
+
+            let status = LoanStatus::next(None, LoanTransition::Create)
+                ?;
+
+            if req.items.is_empty() {
+                return Err(SafError::Validation(
+                    "O préstamo debe incluír polo menos un artigo".to_string(),
+                ));
+            }
+
+            // Check items availability (a mesma liña pode repetirse na petición)
+            let mut requested: Vec<(&str, i32)> = Vec::new();
+            for line in &req.items {
+                if line.quantity < 1 {
+                    return Err(SafError::Validation(format!(
+                        "Cantidade non válida ({}) para o artigo {}",
+                        line.quantity, line.item_id
+                    )));
+                }
+                match requested.iter_mut().find(|(id, _)| *id == line.item_id) {
+                    Some((_, quantity)) => *quantity += line.quantity,
+                    None => requested.push((&line.item_id, line.quantity)),
+                }
+            }
+
+            for (item_id, quantity) in &requested {
+                let (name, available): (String, i32) = self.conn.query_row(
+                    "SELECT name, available_stock FROM items WHERE id = ?1",
+                    [item_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+
+                if available < *quantity {
+                    return Err(SafError::StockUnavailable {
+                        item_id: item_id.to_string(),
+                        item_name: name,
+                        requested: *quantity,
+                        available,
+                    });
+                }
+            }
+
+            // Create loan
+            self.conn.execute(
+                "INSERT INTO loans (id, user_id, start_date, expected_end_date, status, notes, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+                params![id, req.user_id, req.start_date, req.expected_end_date, status.as_str(), req.notes, now],
+            )?;
+
+            // Add loan items and reserve their units
+            let mut reserved_units = Vec::new();
+            for line in &req.items {
+                let loan_item_id = Uuid::new_v4().to_string();
+                self.conn.execute(
+                    "INSERT INTO loan_items (id, loan_id, item_id, quantity) VALUES (?1, ?2, ?3, ?4)",
+                    params![loan_item_id, id, line.item_id, line.quantity],
+                )?;
+
+                reserved_units.push(self.reserve_units(&loan_item_id, line)?);
+                self.refresh_item_stock(&line.item_id)?;
+            }
+
+            // Log event
+            self.log_event(
+                "LOAN_CREATED",
+                serde_json::json!({
+                    "loanId": &id,
+                    "userId": &req.user_id,
+                    "items": req.items.iter().map(|line| serde_json::json!({
+                        "itemId": &line.item_id,
+                        "quantity": line.quantity
+                    })).collect::<Vec<_>>(),
+                    "startDate": &req.start_date,
+                    "expectedEndDate": &req.expected_end_date
+                }),
+                Some(&id),
+                Some(&req.user_id),
+            )?;
+
+            // Log stock reserved for each item
+            for (line, unit_ids) in req.items.iter().zip(&reserved_units) {
+                self.log_event(
+                    "STOCK_RESERVED",
+                    serde_json::json!({
+                        "itemId": &line.item_id,
+                        "quantity": line.quantity,
+                        "unitIds": unit_ids,
+                        "loanId": &id
+                    }),
+                    Some(&id),
+                    Some(&req.user_id),
+                )?;
+            }
+
+            self.get_loan_by_id(&id)
+        })
+    }
+
+    // Comproba que a transición é válida para o estado actual do préstamo
+    // e devolve o novo estado
+    fn check_transition(&self, loan_id: &str, transition: LoanTransition) -> Result<LoanStatus> {
+        let current: String = self
+            .conn
+            .query_row("SELECT status FROM loans WHERE id = ?1", [loan_id], |row| {
+                row.get(0)
+            })
+            .optional()?
+            .ok_or_else(|| SafError::NotFound(format!("Non se atopou o préstamo {}", loan_id)))?;
+
+        Ok(LoanStatus::next(
+            Some(&LoanStatus::parse(&current)),
+            transition,
+        )?)
+    }
+
+    // Entregar un préstamo pendente
+    pub fn deliver_loan(&self, loan_id: &str) -> Result<Loan> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let status = self.check_transition(loan_id, LoanTransition::Deliver)?;
+
+            self.conn.execute(
+                "UPDATE loans SET status = ?1, updated_at = ?2 WHERE id = ?3",
+                params![status.as_str(), now, loan_id],
+            )?;
+
+            self.log_event(
+                "LOAN_DELIVERED",
+                serde_json::json!({"loanId": loan_id}),
+                Some(loan_id),
+                None,
+            )?;
+
+            self.get_loan_by_id(loan_id)
+        })
+    }
+
+    pub fn get_loan_by_id(&self, id: &str) -> Result<Loan> {
+        let loan = self.conn.query_row(
+            "SELECT l.id, l.user_id, u.name as user_name, l.start_date, l.expected_end_date, 
+                    l.actual_end_date, l.status, l.notes, l.created_at, l.updated_at,
+                    (SELECT COUNT(*) FROM loan_extensions e WHERE e.loan_id = l.id) as extension_count
+             FROM loans l
+             JOIN users u ON l.user_id = u.id
+             WHERE l.id = ?1",
+            [id],
+            Self::map_loan,
+        )
+        .optional()?
+        .ok_or_else(|| SafError::NotFound(format!("Non se atopou o préstamo {}", id)))?;
+
+        // Get loan items
+        let items = self.get_loan_items(id)?;
+
+        Ok(Loan { items, ..loan })
+    }
+
+    fn get_loan_items(&self, loan_id: &str) -> Result<Vec<LoanItem>> {
+        Ok(self
+            .load_loan_items(&[loan_id.to_string()])?
+            .remove(loan_id)
+            .unwrap_or_default())
+    }
+
+    // Liñas (coas súas unidades) de varios préstamos en dúas consultas,
+    // agrupadas por préstamo. Os ids pásanse como un array JSON para non
+    // depender do límite de parámetros de SQLite.
+    fn load_loan_items(&self, loan_ids: &[String]) -> Result<HashMap<String, Vec<LoanItem>>> {
+        let ids = serde_json::json!(loan_ids).to_string();
+
+        let mut stmt = self.conn.prepare(
+            "SELECT li.id, li.loan_id, li.item_id, i.name as item_name, li.quantity,
+                    li.returned_quantity, li.returned_at, li.return_condition, li.return_notes
+             FROM loan_items li
+             JOIN items i ON li.item_id = i.id
+             WHERE li.loan_id IN (SELECT value FROM json_each(?1))
+             ORDER BY li.rowid",
+        )?;
+
+        let mut items = stmt
+            .query_map([&ids], |row| {
+                Ok(LoanItem {
+                    id: row.get(0)?,
+                    loan_id: row.get(1)?,
+                    item_id: row.get(2)?,
+                    item_name: row.get(3)?,
+                    quantity: row.get(4)?,
+                    returned_quantity: row.get(5)?,
+                    returned_at: row.get(6)?,
+                    return_condition: row
+                        .get::<_, Option<String>>(7)?
+                        .and_then(|c| ReturnCondition::parse(&c)),
+                    return_notes: row.get(8)?,
+                    units: vec![],
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        // Unidades físicas asignadas a cada liña
+        let mut stmt = self.conn.prepare(
+            "SELECT liu.loan_item_id, u.id, u.tag, liu.returned_at, liu.return_condition
+             FROM loan_item_units liu
+             JOIN loan_items li ON liu.loan_item_id = li.id
+             JOIN units u ON liu.unit_id = u.id
+             WHERE li.loan_id IN (SELECT value FROM json_each(?1))
+             ORDER BY u.tag",
+        )?;
+        let units = stmt
+            .query_map([&ids], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    LoanUnit {
+                        unit_id: row.get(1)?,
+                        tag: row.get(2)?,
+                        returned_at: row.get(3)?,
+                        return_condition: row
+                            .get::<_, Option<String>>(4)?
+                            .and_then(|c| ReturnCondition::parse(&c)),
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let index: HashMap<String, usize> = items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (item.id.clone(), i))
+            .collect();
+        for (loan_item_id, unit) in units {
+            if let Some(&i) = index.get(&loan_item_id) {
+                items[i].units.push(unit);
+            }
+        }
+
+        let mut by_loan: HashMap<String, Vec<LoanItem>> = HashMap::new();
+        for item in items {
+            by_loan.entry(item.loan_id.clone()).or_default().push(item);
+        }
+
+        Ok(by_loan)
+    }
+
+    pub fn get_loans(&self) -> Result<Vec<Loan>> {
+        Ok(self.query_loans(&LoanQuery::default())?.loans)
+    }
+
+    fn map_loan(row: &rusqlite::Row) -> rusqlite::Result<Loan> {
+        let status_str: String = row.get(6)?;
+        let status = LoanStatus::parse(&status_str);
+
+        Ok(Loan {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            user_name: row.get(2)?,
+            start_date: row.get(3)?,
+            expected_end_date: row.get(4)?,
+            actual_end_date: row.get(5)?,
+            status,
+            notes: row.get(7)?,
+            extension_count: row.get(10)?,
+            created_at: row.get(8)?,
+            updated_at: row.get(9)?,
+            items: vec![],
+        })
+    }
+
+    // Lista de préstamos filtrada, ordenada e paxinada na propia consulta
+    pub fn query_loans(&self, query: &LoanQuery) -> Result<LoanPage> {
+        let today = Local::now().naive_local().date();
+
+        let mut conditions = vec![];
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+
+        let statuses: Vec<&str> = query
+            .statuses
+            .iter()
+            .flatten()
+            .map(|s| s.as_str())
+            .collect();
+        if !statuses.is_empty() {
+            conditions.push(format!(
+                "l.status IN ({})",
+                vec!["?"; statuses.len()].join(", ")
+            ));
+            for status in &statuses {
+                params.push(status);
+            }
+        }
+        if let Some(from) = &query.start_from {
+            conditions.push("l.start_date >= ?".to_string());
+            params.push(from);
+        }
+        if let Some(to) = &query.start_to {
+            conditions.push("l.start_date <= ?".to_string());
+            params.push(to);
+        }
+        if let Some(from) = &query.due_from {
+            conditions.push("l.expected_end_date >= ?".to_string());
+            params.push(from);
+        }
+        if let Some(to) = &query.due_to {
+            conditions.push("l.expected_end_date <= ?".to_string());
+            params.push(to);
+        }
+        if let Some(user_id) = &query.user_id {
+            conditions.push("l.user_id = ?".to_string());
+            params.push(user_id);
+        }
+        if let Some(item_id) = &query.item_id {
+            // Subconsulta sen correlación: co índice de item_id resólvese unha
+            // soa vez e non por cada préstamo
+            conditions.push(
+                "l.id IN (SELECT li.loan_id FROM loan_items li WHERE li.item_id = ?)".to_string(),
+            );
+            params.push(item_id);
+        }
+        // Pola categoría (e non polo seu nome) para que siga valendo tras
+        // renomeala ou fusionala
+        if let Some(category_id) = &query.category_id {
+            conditions.push(
+                "l.id IN (SELECT li.loan_id FROM loan_items li JOIN items i ON li.item_id = i.id
+                          WHERE i.category_id = ?)"
+                    .to_string(),
+            );
+            params.push(category_id);
+        }
+        let overdue_cutoff = query
+            .overdue_by_days
+            .map(|days| today - chrono::Duration::days(days.max(0)));
+        if let Some(cutoff) = &overdue_cutoff {
+            conditions
+                .push("l.status IN ('active', 'overdue') AND l.expected_end_date < ?".to_string());
+            params.push(cutoff);
+        }
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        let total: i64 = self.conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM loans l JOIN users u ON l.user_id = u.id {}",
+                where_clause
+            ),
+            params.as_slice(),
+            |row| row.get(0),
+        )?;
+
+        let sort_column = match query.sort {
+            LoanSortKey::CreatedAt => "l.created_at",
+            LoanSortKey::StartDate => "l.start_date",
+            LoanSortKey::ExpectedEndDate => "l.expected_end_date",
+            LoanSortKey::UserName => "u.name",
+        };
+        let (direction, comparison) = if query.ascending {
+            ("ASC", ">")
+        } else {
+            ("DESC", "<")
+        };
+
+        // O cursor é o id do último préstamo da páxina anterior: seguimos
+        // despois del na mesma orde (co rowid para desempatar)
+        let mut paged_conditions = conditions.clone();
+        if let Some(cursor) = &query.cursor {
+            paged_conditions.push(format!(
+                "({col}, l.rowid) {cmp} (SELECT {col}, l.rowid FROM loans l
+                     JOIN users u ON l.user_id = u.id WHERE l.id = ?)",
+                col = sort_column,
+                cmp = comparison
+            ));
+            params.push(cursor);
+        }
+        let where_clause = if paged_conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", paged_conditions.join(" AND "))
+        };
+
+        // Pídese un de máis para saber se hai outra páxina
+        let limit = query.limit.map(|l| l.max(1));
+        let fetch = limit.map(|l| l + 1).unwrap_or(-1);
+        let offset = query.offset.unwrap_or(0).max(0);
+        params.push(&fetch);
+        params.push(&offset);
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT l.id, l.user_id, u.name as user_name, l.start_date, l.expected_end_date,
+                    l.actual_end_date, l.status, l.notes, l.created_at, l.updated_at,
+                    (SELECT COUNT(*) FROM loan_extensions e WHERE e.loan_id = l.id) as extension_count
+             FROM loans l
+             JOIN users u ON l.user_id = u.id
+             {}
+             ORDER BY {} {dir}, l.rowid {dir}
+             LIMIT ? OFFSET ?",
+            where_clause,
+            sort_column,
+            dir = direction
+        ))?;
+
+        let mut loans = stmt
+            .query_map(params.as_slice(), Self::map_loan)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let next_cursor = match limit {
+            Some(limit) if loans.len() as i64 > limit => {
+                loans.truncate(limit as usize);
+                loans.last().map(|l| l.id.clone())
+            }
+            _ => None,
+        };
+
+        // As liñas de todos os préstamos da páxina nunha soa volta
+        let ids: Vec<String> = loans.iter().map(|l| l.id.clone()).collect();
+        let mut items = self.load_loan_items(&ids)?;
+        for loan in &mut loans {
+            loan.items = items.remove(&loan.id).unwrap_or_default();
+        }
+
+        Ok(LoanPage {
+            loans,
+            total,
+            next_cursor,
+        })
+    }
+
+    // Devolve unidades dunha liña de préstamo e libera o seu stock.
+    // Se non se indican unidades concretas, devólvense as primeiras pendentes.
+    fn release_loan_item(
+        &self,
+        item: &LoanItem,
+        quantity: i32,
+        unit_ids: Option<&[String]>,
+        condition: Option<ReturnCondition>,
+        notes: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let now = Local::now().naive_local();
+        let today = now.date();
+
+        let outstanding = item.quantity - item.returned_quantity;
+        if quantity < 1 || quantity > outstanding {
+            return Err(SafError::Validation(format!(
+                "Cantidade a devolver non válida ({}): quedan {} unidades pendentes de {}",
+                quantity, outstanding, item.item_name
+            )));
+        }
+
+        let pending: Vec<&LoanUnit> = item
+            .units
+            .iter()
+            .filter(|u| u.returned_at.is_none())
+            .collect();
+        let released: Vec<String> = match unit_ids {
+            Some(ids) => {
+                if ids.len() != quantity as usize {
+                    return Err(SafError::Validation(format!(
+                        "Indicáronse {} unidades pero a cantidade é {}",
+                        ids.len(),
+                        quantity
+                    )));
+                }
+                if ids.iter().collect::<HashSet<_>>().len() != ids.len() {
+                    return Err(SafError::Validation(
+                        "Hai unidades repetidas na devolución".to_string(),
+                    ));
+                }
+                if let Some(id) = ids
+                    .iter()
+                    .find(|id| !pending.iter().any(|u| &u.unit_id == *id))
+                {
+                    return Err(SafError::Validation(format!(
+                        "A unidade {} non está pendente de devolución neste préstamo",
+                        id
+                    )));
+                }
+                ids.to_vec()
+            }
+            None => pending
+                .iter()
+                .take(quantity as usize)
+                .map(|u| u.unit_id.clone())
+                .collect(),
+        };
+
+        let unit_status = condition
+            .map(|c| c.unit_status())
+            .unwrap_or(UnitStatus::Available);
+        let condition_str = condition.map(|c| c.as_str());
+
+        for unit_id in &released {
+            self.conn.execute(
+                "UPDATE units SET status = ?1, updated_at = ?2 WHERE id = ?3 AND status = 'loaned'",
+                params![unit_status.as_str(), now, unit_id],
+            )?;
+            self.conn.execute(
+                "UPDATE loan_item_units SET returned_at = ?1, return_condition = ?2
+                 WHERE loan_item_id = ?3 AND unit_id = ?4",
+                params![today, condition_str, item.id, unit_id],
+            )?;
+
+            match unit_status {
+                // As unidades danadas quedan cunha orde de reparación aberta
+                UnitStatus::InRepair => {
+                    let unit = self.get_unit_by_id(unit_id)?;
+                    self.insert_maintenance_order(
+                        &unit,
+                        Some(&item.loan_id),
+                        today,
+                        "Devolución con danos",
+                        None,
+                        notes,
+                    )?;
+                }
+                UnitStatus::Lost => {
+                    self.log_event(
+                        "UNIT_WRITTEN_OFF",
+                        serde_json::json!({
+                            "unitId": unit_id,
+                            "itemId": &item.item_id,
+                            "loanId": &item.loan_id,
+                            "reason": "lost",
+                            "notes": notes
+                        }),
+                        Some(&item.loan_id),
+                        None,
+                    )?;
+                }
+                _ => {}
+            }
+        }
+
+        self.conn.execute(
+            "UPDATE loan_items SET returned_quantity = returned_quantity + ?1, returned_at = ?2,
+                    return_condition = COALESCE(?3, return_condition), return_notes = COALESCE(?4, return_notes)
+             WHERE id = ?5",
+            params![quantity, today, condition_str, notes, item.id],
+        )?;
+        self.refresh_item_stock(&item.item_id)?;
+
+        self.log_event(
+            "STOCK_RELEASED",
+            serde_json::json!({
+                "itemId": &item.item_id,
+                "quantity": quantity,
+                "unitIds": &released,
+                "loanId": &item.loan_id
+            }),
+            Some(&item.loan_id),
+            None,
+        )?;
 
-        // Populate items for each loan
-        let mut loans_with_items = Vec::new();
-        for loan in loans {
-            let items = self.get_loan_items(&loan.id)?;
-            loans_with_items.push(Loan { items, ..loan });
-        }
+        Ok(released)
+    }
+
+    pub fn return_loan(&self, loan_id: &str, req: ReturnLoanRequest) -> Result<Loan> {
+        let ReturnLoanRequest { condition, notes } = req;
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let today = Local::now().naive_local().date();
+            let status = self.check_transition(loan_id, LoanTransition::Return)?;
+
+            // Restore stock of every line still out
+            let mut lines = vec![];
+            for item in self.get_loan_items(loan_id)? {
+                let outstanding = item.quantity - item.returned_quantity;
+                if outstanding > 0 {
+                    let released = self.release_loan_item(
+                        &item,
+                        outstanding,
+                        None,
+                        condition,
+                        notes.as_deref(),
+                    )?;
+                    lines.push(ReturnedLine::new(&item, outstanding, released));
+                }
+            }
+
+            // Update loan status
+            self.conn.execute(
+                "UPDATE loans SET status = ?1, actual_end_date = ?2, notes = COALESCE(?3, notes), updated_at = ?4 WHERE id = ?5",
+                params![status.as_str(), today, notes, now, loan_id],
+            )?;
+
+            // Log event
+            self.log_event(
+                "LOAN_RETURNED",
+                serde_json::json!({
+                    "loanId": loan_id,
+                    "condition": &condition,
+                    "notes": &notes,
+                    "lines": &lines
+                }),
+                Some(loan_id),
+                None,
+            )?;
 
-        Ok(loans_with_items)
+            self.get_loan_by_id(loan_id)
+        })
     }
 
-    pub fn return_loan(
-        &self,
-        loan_id: &str,
-        condition: Option<String>,
-        notes: Option<String>,
-    ) -> Result<Loan> {
-        let now = Local::now().naive_local();
-        let today = Local::now().naive_local().date();
+    // Devolución parcial: unha liña (ou parte dela). O préstamo só se pecha
+    // cando se devolveron todas as liñas.
+    pub fn return_loan_item(&self, loan_item_id: &str, req: ReturnLoanItemRequest) -> Result<Loan> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let today = now.date();
 
-        // Get loan items to restore stock
-        let items = self.get_loan_items(loan_id)?;
+            let loan_id: String = self.conn.query_row(
+                "SELECT loan_id FROM loan_items WHERE id = ?1",
+                [loan_item_id],
+                |row| row.get(0),
+            )?;
+            let returned_status = self.check_transition(&loan_id, LoanTransition::Return)?;
 
-        // Update loan status
-        self.conn.execute(
-            "UPDATE loans SET status = 'returned', actual_end_date = ?1, notes = COALESCE(?2, notes), updated_at = ?3 WHERE id = ?4",
-            params![today, notes, now, loan_id],
-        )?;
+            let items = self.get_loan_items(&loan_id)?;
+            let item = items
+                .iter()
+                .find(|i| i.id == loan_item_id)
+                .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            let quantity = req.quantity.unwrap_or(item.quantity - item.returned_quantity);
 
-        // Restore stock
-        for item in &items {
-            self.conn.execute(
-                "UPDATE items SET available_stock = available_stock + ?1, updated_at = ?2 WHERE id = ?3",
-                params![item.quantity, now, item.item_id],
+            let released = self.release_loan_item(
+                item,
+                quantity,
+                req.unit_ids.as_deref(),
+                req.condition,
+                req.notes.as_deref(),
             )?;
 
-            // Log stock released
             self.log_event(
-                "STOCK_RELEASED",
+                "LOAN_ITEM_RETURNED",
                 serde_json::json!({
+                    "loanId": &loan_id,
+                    "loanItemId": loan_item_id,
                     "itemId": &item.item_id,
-                    "quantity": item.quantity,
-                    "loanId": loan_id
+                    "quantity": quantity,
+                    "unitIds": &released,
+                    "condition": &req.condition,
+                    "notes": &req.notes
                 }),
-                Some(loan_id),
+                Some(&loan_id),
                 None,
             )?;
-        }
 
-        // Log event
-        self.log_event(
-            "LOAN_RETURNED",
-            serde_json::json!({
-                "loanId": loan_id,
-                "condition": &condition,
-                "notes": &notes
-            }),
-            Some(loan_id),
-            None,
-        )?;
+            let pending: i32 = self.conn.query_row(
+                "SELECT COALESCE(SUM(quantity - returned_quantity), 0) FROM loan_items WHERE loan_id = ?1",
+                [&loan_id],
+                |row| row.get(0),
+            )?;
+            if pending == 0 {
+                self.conn.execute(
+                    "UPDATE loans SET status = ?1, actual_end_date = ?2, updated_at = ?3 WHERE id = ?4",
+                    params![returned_status.as_str(), today, now, loan_id],
+                )?;
+                self.log_event(
+                    "LOAN_RETURNED",
+                    serde_json::json!({
+                        "loanId": &loan_id,
+                        "condition": &req.condition,
+                        "notes": &req.notes,
+                        "partial": true,
+                        "lines": [ReturnedLine::new(item, quantity, released)]
+                    }),
+                    Some(&loan_id),
+                    None,
+                )?;
+            }
 
-        self.get_loan_by_id(loan_id)
+            self.get_loan_by_id(&loan_id)
+        })
     }
 
     // Cancelar unha devolución (reabrir o préstamo)
     pub fn cancel_return(&self, loan_id: &str, reason: Option<String>) -> Result<Loan> {
-        let now = Local::now().naive_local();
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            let status = self.check_transition(loan_id, LoanTransition::CancelReturn)?;
 
-        // Get loan items to reserve stock again
-        let items = self.get_loan_items(loan_id)?;
+            let items = self.get_loan_items(loan_id)?;
+            let returned_event: Option<(String, String)> = self
+                .conn
+                .query_row(
+                    "SELECT id, data FROM events
+                     WHERE loan_id = ?1 AND event_type = 'LOAN_RETURNED' AND cancelled_by IS NULL
+                     ORDER BY rowid DESC LIMIT 1",
+                    [loan_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
 
-        // Update loan status back to active
-        self.conn.execute(
-            "UPDATE loans SET status = 'active', actual_end_date = NULL, updated_at = ?1 WHERE id = ?2",
-            params![now, loan_id],
+            // Só se desfai o que pechou esta devolución; o devolto antes en
+            // devolucións parciais segue devolto
+            let recorded = returned_event
+                .as_ref()
+                .and_then(|(_, data)| serde_json::from_str::<serde_json::Value>(data).ok())
+                .and_then(|data| serde_json::from_value::<Vec<ReturnedLine>>(data["lines"].clone()).ok());
+            let lines = match recorded {
+                Some(lines) => lines,
+                None => self.lines_returned_on_close(loan_id, &items)?,
+            };
+
+            // Update loan status back to active
+            self.conn.execute(
+                "UPDATE loans SET status = ?1, actual_end_date = NULL, updated_at = ?2 WHERE id = ?3",
+                params![status.as_str(), now, loan_id],
+            )?;
+
+            // Reserve stock again: as mesmas unidades teñen que seguir dispoñibles
+            for line in &lines {
+                let item = items
+                    .iter()
+                    .find(|i| i.id == line.loan_item_id)
+                    .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+                for unit_id in &line.unit_ids {
+                    let unit = self.get_unit_by_id(unit_id)?;
+                    let returned = item.units.iter().find(|u| &u.unit_id == unit_id);
+                    // As unidades que esta mesma devolución deu de baixa (danadas ou
+                    // perdidas) tamén se poden recuperar; as retiradas antes, nunca
+                    // chegan aquí porque non están nas liñas desta devolución
+                    let written_off_here = matches!(
+                        (returned.and_then(|u| u.return_condition), &unit.status),
+                        (Some(ReturnCondition::Damaged), UnitStatus::InRepair)
+                            | (Some(ReturnCondition::Lost), UnitStatus::Lost)
+                    );
+                    if unit.status != UnitStatus::Available && !written_off_here {
+                        return Err(SafError::Conflict(format!(
+                            "A unidade {} xa non está dispoñible",
+                            unit.tag
+                        )));
+                    }
+                    if written_off_here {
+                        // Só a orde que abriu esta devolución
+                        self.conn.execute(
+                            "UPDATE maintenance_orders SET closed_at = ?1, outcome = 'cancelled', updated_at = ?2
+                             WHERE unit_id = ?3 AND loan_id = ?4 AND opened_at = ?5 AND closed_at IS NULL",
+                            params![
+                                now.date(),
+                                now,
+                                unit_id,
+                                loan_id,
+                                returned.and_then(|u| u.returned_at)
+                            ],
+                        )?;
+                    }
+                    self.conn.execute(
+                        "UPDATE units SET status = 'loaned', updated_at = ?1 WHERE id = ?2",
+                        params![now, unit_id],
+                    )?;
+                    self.conn.execute(
+                        "UPDATE loan_item_units SET returned_at = NULL, return_condition = NULL
+                         WHERE loan_item_id = ?1 AND unit_id = ?2",
+                        params![item.id, unit_id],
+                    )?;
+                }
+                self.conn.execute(
+                    "UPDATE loan_items SET returned_quantity = MAX(returned_quantity - ?1, 0), returned_at = ?2,
+                            return_condition = ?3, return_notes = ?4
+                     WHERE id = ?5",
+                    params![
+                        line.quantity,
+                        line.previous_returned_at,
+                        line.previous_condition.map(|c| c.as_str()),
+                        line.previous_notes,
+                        item.id
+                    ],
+                )?;
+                self.refresh_item_stock(&item.item_id)?;
+
+                // Log stock reserved again
+                self.log_event(
+                    "STOCK_RESERVED",
+                    serde_json::json!({
+                        "itemId": &item.item_id,
+                        "quantity": line.quantity,
+                        "unitIds": &line.unit_ids,
+                        "loanId": loan_id,
+                        "reason": "return_cancelled"
+                    }),
+                    Some(loan_id),
+                    None,
+                )?;
+            }
+
+            // Log cancellation event
+            let cancellation_id = self.log_event(
+                "RETURN_CANCELLED",
+                serde_json::json!({
+                    "loanId": loan_id,
+                    "reason": &reason,
+                    "cancelledAt": now.to_string()
+                }),
+                Some(loan_id),
+                None,
+            )?;
+
+            // A devolución desfeita queda marcada como anulada
+            if let Some((event_id, _)) = &returned_event {
+                self.conn.execute(
+                    "UPDATE events SET cancelled_by = ?1, cancellation_reason = ?2 WHERE id = ?3",
+                    params![cancellation_id, reason, event_id],
+                )?;
+            }
+
+            self.get_loan_by_id(loan_id)
+        })
+    }
+
+    // Devolucións rexistradas antes de gardar as liñas no evento: tómase
+    // o devolto o mesmo día no que se pechou o préstamo
+    fn lines_returned_on_close(
+        &self,
+        loan_id: &str,
+        items: &[LoanItem],
+    ) -> Result<Vec<ReturnedLine>> {
+        let closed_on: Option<NaiveDate> = self.conn.query_row(
+            "SELECT actual_end_date FROM loans WHERE id = ?1",
+            [loan_id],
+            |row| row.get(0),
+        )?;
+
+        let mut lines = vec![];
+        for item in items {
+            let unit_ids: Vec<String> = item
+                .units
+                .iter()
+                .filter(|u| u.returned_at.is_some() && u.returned_at == closed_on)
+                .map(|u| u.unit_id.clone())
+                .collect();
+            let quantity = if item.units.is_empty() && item.returned_at == closed_on {
+                item.returned_quantity
+            } else {
+                unit_ids.len() as i32
+            };
+            if quantity == 0 {
+                continue;
+            }
+            // Se queda algo devolto de antes consérvase o que había na liña
+            let mut line = ReturnedLine::new(item, quantity, unit_ids);
+            if item.returned_quantity == quantity {
+                line.previous_returned_at = None;
+                line.previous_condition = None;
+                line.previous_notes = None;
+            }
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    // Unidades devoltas danadas ou perdidas nun período
+    pub fn get_damage_report(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<DamageReportEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT liu.returned_at, liu.return_condition, l.id, us.name, i.name, un.tag, li.return_notes
+             FROM loan_item_units liu
+             JOIN loan_items li ON liu.loan_item_id = li.id
+             JOIN loans l ON li.loan_id = l.id
+             JOIN users us ON l.user_id = us.id
+             JOIN items i ON li.item_id = i.id
+             JOIN units un ON liu.unit_id = un.id
+             WHERE liu.return_condition IN ('damaged', 'lost')
+               AND liu.returned_at BETWEEN ?1 AND ?2
+             ORDER BY liu.returned_at, i.name, un.tag",
         )?;
 
-        // Reserve stock again
-        for item in &items {
+        let entries = stmt
+            .query_map(params![from, to], |row| {
+                let condition_str: String = row.get(1)?;
+                Ok(DamageReportEntry {
+                    returned_at: row.get(0)?,
+                    condition: ReturnCondition::parse(&condition_str)
+                        .unwrap_or(ReturnCondition::Damaged),
+                    loan_id: row.get(2)?,
+                    user_name: row.get(3)?,
+                    item_name: row.get(4)?,
+                    unit_tag: row.get(5)?,
+                    notes: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(entries)
+    }
+
+    // Prorrogar un préstamo ata unha nova data prevista
+    pub fn extend_loan(
+        &self,
+        loan_id: &str,
+        new_end_date: NaiveDate,
+        reason: Option<String>,
+    ) -> Result<Loan> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+            self.check_transition(loan_id, LoanTransition::Extend)?;
+
+            let previous_end_date: NaiveDate = self.conn.query_row(
+                "SELECT expected_end_date FROM loans WHERE id = ?1",
+                [loan_id],
+                |row| row.get(0),
+            )?;
+            if new_end_date <= previous_end_date {
+                return Err(SafError::Validation(format!(
+                    "A nova data ({}) debe ser posterior á data prevista actual ({})",
+                    new_end_date, previous_end_date
+                )));
+            }
+
+            let id = Uuid::new_v4().to_string();
+            self.conn.execute(
+                "INSERT INTO loan_extensions (id, loan_id, previous_end_date, new_end_date, reason, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![id, loan_id, previous_end_date, new_end_date, reason, now],
+            )?;
             self.conn.execute(
-                "UPDATE items SET available_stock = available_stock - ?1, updated_at = ?2 WHERE id = ?3",
-                params![item.quantity, now, item.item_id],
+                "UPDATE loans SET expected_end_date = ?1, updated_at = ?2 WHERE id = ?3",
+                params![new_end_date, now, loan_id],
             )?;
 
-            // Log stock reserved again
             self.log_event(
-                "STOCK_RESERVED",
+                "LOAN_EXTENDED",
                 serde_json::json!({
-                    "itemId": &item.item_id,
-                    "quantity": item.quantity,
                     "loanId": loan_id,
-                    "reason": "return_cancelled"
+                    "previousEndDate": previous_end_date,
+                    "newEndDate": new_end_date,
+                    "reason": &reason
                 }),
                 Some(loan_id),
                 None,
             )?;
+
+            // Un préstamo atrasado pode deixar de selo
+            self.update_overdue_loans()?;
+
+            self.get_loan_by_id(loan_id)
+        })
+    }
+
+    pub fn get_loan_extensions(&self, loan_id: &str) -> Result<Vec<LoanExtension>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, loan_id, previous_end_date, new_end_date, reason, created_at
+             FROM loan_extensions
+             WHERE loan_id = ?1
+             ORDER BY created_at",
+        )?;
+
+        let extensions = stmt
+            .query_map([loan_id], |row| {
+                Ok(LoanExtension {
+                    id: row.get(0)?,
+                    loan_id: row.get(1)?,
+                    previous_end_date: row.get(2)?,
+                    new_end_date: row.get(3)?,
+                    reason: row.get(4)?,
+                    created_at: row.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(extensions)
+    }
+
+    pub fn update_overdue_loans(&self) -> Result<usize> {
+        self.unit_of_work(|| {
+            let today = Local::now().naive_local().date();
+
+            let mut stmt = self.conn.prepare(
+                "SELECT id, status, expected_end_date FROM loans WHERE status NOT IN ('returned', 'cancelled')",
+            )?;
+            let candidates = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, NaiveDate>(2)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let mut updated = 0;
+            for (loan_id, status_str, expected_end_date) in candidates {
+                let current = LoanStatus::parse(&status_str);
+                // Vencidos pasan a atrasados; os atrasados prorrogados volven a activos
+                let transition = if expected_end_date < today {
+                    LoanTransition::MarkOverdue
+                } else {
+                    LoanTransition::ClearOverdue
+                };
+                if let Ok(status) = LoanStatus::next(Some(&current), transition) {
+                    updated += self.conn.execute(
+                        "UPDATE loans SET status = ?1 WHERE id = ?2",
+                        params![status.as_str(), loan_id],
+                    )?;
+                }
+            }
+
+            Ok(updated)
+        })
+    }
+
+    // Event operations
+    // Unidades asociadas a liñas de préstamos aínda non devoltas
+    const OPEN_LOAN_UNITS: &'static str = "SELECT liu.unit_id FROM loan_item_units liu
+         JOIN loan_items li ON liu.loan_item_id = li.id
+         JOIN loans l ON li.loan_id = l.id
+         WHERE liu.returned_at IS NULL AND l.status NOT IN ('returned', 'cancelled')";
+
+    // Comproba os contadores de stock de cada artigo. En modo reparación
+    // corrixe o estado das unidades e os contadores, e rexistra o axuste
+    pub fn check_stock(&self, repair: bool) -> Result<StockCheckReport> {
+        self.unit_of_work(|| {
+            let now = Local::now().naive_local();
+
+            let items = {
+                let mut stmt = self.conn.prepare(
+                    "SELECT id, name, total_stock, available_stock FROM items ORDER BY name",
+                )?;
+                let rows = stmt
+                    .query_map([], |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, i32>(2)?,
+                            row.get::<_, i32>(3)?,
+                        ))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                rows
+            };
+
+            let mut drifts = vec![];
+            for (item_id, item_name, stored_total, stored_available) in &items {
+                if let Some(drift) =
+                    self.item_stock_drift(item_id, item_name, *stored_total, *stored_available)?
+                {
+                    if repair {
+                        self.repair_item_stock(&drift)?;
+                    }
+                    drifts.push(drift);
+                }
+            }
+
+            Ok(StockCheckReport {
+                checked_at: now,
+                items_checked: items.len() as i32,
+                drifts,
+                repaired: repair,
+            })
+        })
+    }
+
+    fn item_stock_drift(
+        &self,
+        item_id: &str,
+        item_name: &str,
+        stored_total: i32,
+        stored_available: i32,
+    ) -> Result<Option<StockDrift>> {
+        let (expected_total, expected_available, loaned_units, linked_units): (i32, i32, i32, i32) =
+            self.conn.query_row(
+                &format!(
+                    "SELECT
+                        COALESCE(SUM(status NOT IN ('retired', 'lost')), 0),
+                        COALESCE(SUM(status IN ('available', 'loaned') AND id NOT IN ({0})), 0),
+                        COALESCE(SUM(status = 'loaned'), 0),
+                        COALESCE(SUM(id IN ({0})), 0)
+                     FROM units WHERE item_id = ?1",
+                    Self::OPEN_LOAN_UNITS
+                ),
+                [item_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?;
+
+        let loaned_by_loans: i32 = self.conn.query_row(
+            "SELECT COALESCE(SUM(li.quantity - li.returned_quantity), 0)
+             FROM loan_items li
+             JOIN loans l ON li.loan_id = l.id
+             WHERE li.item_id = ?1 AND l.status NOT IN ('returned', 'cancelled')",
+            [item_id],
+            |row| row.get(0),
+        )?;
+
+        // Reservas menos liberacións, máis os axustes de reconciliacións anteriores
+        let loaned_by_events: i32 = self.conn.query_row(
+            "SELECT COALESCE(SUM(CASE event_type
+                    WHEN 'STOCK_RESERVED' THEN json_extract(data, '$.quantity')
+                    WHEN 'STOCK_RELEASED' THEN -json_extract(data, '$.quantity')
+                    ELSE json_extract(data, '$.eventAdjustment')
+                 END), 0)
+             FROM events
+             WHERE event_type IN ('STOCK_RESERVED', 'STOCK_RELEASED', 'STOCK_RECONCILED')
+               AND cancelled_by IS NULL
+               AND item_id = ?1",
+            [item_id],
+            |row| row.get(0),
+        )?;
+
+        let consistent = stored_total == expected_total
+            && stored_available == expected_available
+            && loaned_units == linked_units
+            && linked_units == loaned_by_loans
+            && loaned_by_events == loaned_by_loans;
+        if consistent {
+            return Ok(None);
+        }
+
+        Ok(Some(StockDrift {
+            item_id: item_id.to_string(),
+            item_name: item_name.to_string(),
+            stored_total,
+            stored_available,
+            expected_total,
+            expected_available,
+            loaned_by_loans,
+            loaned_by_events,
+            loaned_units,
+            linked_units,
+        }))
+    }
+
+    // As liñas abertas sen unidades asociadas non se poden corrixir aquí:
+    // quedan no informe ata que se devolvan
+    fn repair_item_stock(&self, drift: &StockDrift) -> Result<()> {
+        let now = Local::now().naive_local();
+
+        let marked_loaned = self.conn.execute(
+            &format!(
+                "UPDATE units SET status = 'loaned', updated_at = ?1
+                 WHERE item_id = ?2 AND status = 'available' AND id IN ({})",
+                Self::OPEN_LOAN_UNITS
+            ),
+            params![now, drift.item_id],
+        )?;
+        let marked_available = self.conn.execute(
+            &format!(
+                "UPDATE units SET status = 'available', updated_at = ?1
+                 WHERE item_id = ?2 AND status = 'loaned' AND id NOT IN ({})",
+                Self::OPEN_LOAN_UNITS
+            ),
+            params![now, drift.item_id],
+        )?;
+        self.refresh_item_stock(&drift.item_id)?;
+
+        let event_adjustment = drift.loaned_by_loans - drift.loaned_by_events;
+        let counters_changed = drift.stored_total != drift.expected_total
+            || drift.stored_available != drift.expected_available;
+        if marked_loaned == 0 && marked_available == 0 && event_adjustment == 0 && !counters_changed
+        {
+            return Ok(());
         }
 
-        // Log cancellation event
         self.log_event(
-            "RETURN_CANCELLED",
+            "STOCK_RECONCILED",
             serde_json::json!({
-                "loanId": loan_id,
-                "reason": &reason,
-                "cancelledAt": now.to_string()
+                "itemId": &drift.item_id,
+                "previousTotal": drift.stored_total,
+                "previousAvailable": drift.stored_available,
+                "newTotal": drift.expected_total,
+                "newAvailable": drift.expected_available,
+                "unitsMarkedLoaned": marked_loaned,
+                "unitsMarkedAvailable": marked_available,
+                "eventAdjustment": event_adjustment
             }),
-            Some(loan_id),
             None,
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    // Deixa constancia de que se xerou un documento dun préstamo (albarán,
+    // certificado de devolución, carta de recordatorio...)
+    pub fn log_loan_document(&self, event_type: &str, loan: &Loan, file: &str) -> Result<()> {
+        self.log_event(
+            event_type,
+            serde_json::json!({
+                "loanId": &loan.id,
+                "userName": &loan.user_name,
+                "items": loan.items.iter().map(|i| serde_json::json!({
+                    "itemName": &i.item_name,
+                    "quantity": i.quantity,
+                    "returnedQuantity": i.returned_quantity,
+                })).collect::<Vec<_>>(),
+                "file": file,
+            }),
+            Some(&loan.id),
+            Some(&loan.user_id),
+        )?;
+        Ok(())
+    }
+
+    // Carta de recordatorio a unha persoa polos seus préstamos atrasados
+    pub fn log_reminder_sent(&self, loans: &[&Loan], file: &str) -> Result<()> {
+        // Un evento por préstamo, para que a carta saia no historial de
+        // cada un; `letterId` une os da mesma carta
+        let letter_id = Uuid::new_v4().to_string();
+        let loan_ids: Vec<&str> = loans.iter().map(|l| l.id.as_str()).collect();
+        self.unit_of_work(|| {
+            for loan in loans {
+                self.log_event(
+                    "REMINDER_SENT",
+                    serde_json::json!({
+                        "letterId": &letter_id,
+                        "userName": &loan.user_name,
+                        "loanId": &loan.id,
+                        "loanIds": &loan_ids,
+                        "items": loan.items.iter()
+                            .filter(|i| i.returned_quantity < i.quantity)
+                            .map(|i| serde_json::json!({
+                                "itemName": &i.item_name,
+                                "quantity": i.quantity - i.returned_quantity,
+                            })).collect::<Vec<_>>(),
+                        "dueDate": loan.expected_end_date,
+                        "file": file,
+                    }),
+                    Some(&loan.id),
+                    Some(&loan.user_id),
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn log_event(
+        &self,
+        event_type: &str,
+        data: serde_json::Value,
+        loan_id: Option<&str>,
+        user_id: Option<&str>,
+    ) -> Result<String> {
+        let id = Uuid::new_v4().to_string();
+        // O artigo afectado vai no propio evento; gárdase aparte para poder filtrar
+        let item_id = data.get("itemId").and_then(|v| v.as_str());
+
+        self.conn.execute(
+            "INSERT INTO events (id, event_type, data, loan_id, user_id, item_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![id, event_type, data.to_string(), loan_id, user_id, item_id],
+        )?;
+
+        Ok(id)
+    }
+
+    fn map_event(row: &rusqlite::Row) -> rusqlite::Result<Event> {
+        let data_str: String = row.get(2)?;
+        let data = serde_json::from_str(&data_str).unwrap_or(serde_json::Value::Null);
+
+        Ok(Event {
+            id: row.get(0)?,
+            event_type: row.get(1)?,
+            data,
+            created_at: row.get(3)?,
+            loan_id: row.get(4)?,
+            user_id: row.get(5)?,
+            item_id: row.get(6)?,
+            cancelled_by: row.get(7)?,
+            cancellation_reason: row.get(8)?,
+        })
+    }
+
+    pub fn get_events(&self, limit: i64) -> Result<Vec<Event>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, event_type, data, created_at, loan_id, user_id, item_id,
+                    cancelled_by, cancellation_reason
+             FROM events
+             ORDER BY created_at DESC
+             LIMIT ?1",
+        )?;
+
+        let events = stmt
+            .query_map([limit], Self::map_event)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(events)
+    }
+
+    // Historial completo dun préstamo, en orde cronolóxica
+    pub fn get_events_by_loan(&self, loan_id: &str) -> Result<Vec<Event>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, event_type, data, created_at, loan_id, user_id, item_id,
+                    cancelled_by, cancellation_reason
+             FROM events
+             WHERE loan_id = ?1
+             ORDER BY rowid",
         )?;
 
-        self.get_loan_by_id(loan_id)
+        let events = stmt
+            .query_map([loan_id], Self::map_event)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(events)
+    }
+
+    // Consulta paxinada do rexistro de eventos, do máis recente ao máis antigo.
+    // Pagínase pola orde de inserción (rowid), que é estable aínda que varios
+    // eventos teñan o mesmo created_at.
+    pub fn query_events(&self, query: &EventQuery) -> Result<EventPage> {
+        let limit = query.limit.unwrap_or(50).clamp(1, 500);
+        let cursor = match &query.cursor {
+            Some(c) => Some(
+                c.parse::<i64>()
+                    .map_err(|_| SafError::Validation(format!("Cursor non válido: {}", c)))?,
+            ),
+            None => None,
+        };
+        let to = query.to.map(|d| d + chrono::Duration::days(1));
+
+        let mut conditions = vec![];
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+
+        if let Some(loan_id) = &query.loan_id {
+            conditions.push("loan_id = ?".to_string());
+            params.push(loan_id);
+        }
+        // Os eventos dos préstamos dunha persoa tamén forman parte do seu historial
+        if let Some(user_id) = &query.user_id {
+            conditions.push(
+                "(user_id = ? OR loan_id IN (SELECT id FROM loans WHERE user_id = ?))".to_string(),
+            );
+            params.push(user_id);
+            params.push(user_id);
+        }
+        if let Some(item_id) = &query.item_id {
+            conditions.push("item_id = ?".to_string());
+            params.push(item_id);
+        }
+        if let Some(types) = query.event_types.as_ref().filter(|t| !t.is_empty()) {
+            conditions.push(format!(
+                "event_type IN ({})",
+                vec!["?"; types.len()].join(", ")
+            ));
+            for t in types {
+                params.push(t);
+            }
+        }
+        if let Some(from) = &query.from {
+            conditions.push("created_at >= ?".to_string());
+            params.push(from);
+        }
+        if let Some(to) = &to {
+            conditions.push("created_at < ?".to_string());
+            params.push(to);
+        }
+        if query.hide_cancelled {
+            conditions.push("cancelled_by IS NULL".to_string());
+        }
+        if let Some(cursor) = &cursor {
+            conditions.push("rowid < ?".to_string());
+            params.push(cursor);
+        }
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        // Pídese un de máis para saber se hai outra páxina
+        let fetch = limit + 1;
+        params.push(&fetch);
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, event_type, data, created_at, loan_id, user_id, item_id,
+                    cancelled_by, cancellation_reason, rowid
+             FROM events
+             {}
+             ORDER BY rowid DESC
+             LIMIT ?",
+            where_clause
+        ))?;
+
+        let mut rows = stmt
+            .query_map(params.as_slice(), |row| {
+                Ok((Self::map_event(row)?, row.get::<_, i64>(9)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let next_cursor = if rows.len() as i64 > limit {
+            rows.truncate(limit as usize);
+            rows.last().map(|(_, rowid)| rowid.to_string())
+        } else {
+            None
+        };
+
+        Ok(EventPage {
+            events: rows.into_iter().map(|(event, _)| event).collect(),
+            next_cursor,
+        })
+    }
+
+    pub fn get_event_by_id(&self, id: &str) -> Result<Event> {
+        self.conn
+            .query_row(
+                "SELECT id, event_type, data, created_at, loan_id, user_id, item_id,
+                    cancelled_by, cancellation_reason
+             FROM events WHERE id = ?1",
+                [id],
+                Self::map_event,
+            )
+            .optional()?
+            .ok_or_else(|| SafError::NotFound(format!("Non se atopou o evento {}", id)))
+    }
+
+    // Anular un evento erróneo: desfanse os seus efectos, rexístrase un evento
+    // compensatorio EVENT_CANCELLED e o orixinal queda ligado a el por
+    // `cancelled_by`. Devolve o evento compensatorio.
+    pub fn cancel_event(&self, event_id: &str, reason: Option<String>) -> Result<Event> {
+        self.unit_of_work(|| {
+            let event = self.get_event_by_id(event_id)?;
+
+            if event.cancelled_by.is_some() {
+                return Err(SafError::Conflict(
+                    "Este evento xa está anulado".to_string(),
+                ));
+            }
+
+            let effects = match event.event_type.as_str() {
+                "STOCK_UPDATED" => self.revert_stock_update(&event)?,
+                "LOAN_CREATED" => self.void_loan(&event, reason.as_deref())?,
+                // cancel_return xa rexistra RETURN_CANCELLED e liga a devolución a el
+                "LOAN_RETURNED" => {
+                    let loan_id = Self::event_loan_id(&event)?;
+                    let latest_return: Option<String> = self
+                        .conn
+                        .query_row(
+                            "SELECT id FROM events
+                             WHERE loan_id = ?1 AND event_type = 'LOAN_RETURNED' AND cancelled_by IS NULL
+                             ORDER BY rowid DESC LIMIT 1",
+                            [&loan_id],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    if latest_return.as_deref() != Some(event.id.as_str()) {
+                        return Err(SafError::Conflict(
+                            "Só se pode anular a última devolución do préstamo".to_string(),
+                        ));
+                    }
+                    self.cancel_return(&loan_id, reason)?;
+                    let cancelled_by = self.get_event_by_id(&event.id)?.cancelled_by;
+                    return self.get_event_by_id(cancelled_by.as_deref().unwrap_or_default());
+                }
+                "LOAN_EXTENDED" => self.revert_loan_extension(&event)?,
+                other => {
+                    return Err(SafError::Validation(format!(
+                        "Os eventos de tipo {} non se poden anular",
+                        other
+                    )))
+                }
+            };
+
+            let mut data = serde_json::json!({
+                "eventId": &event.id,
+                "eventType": &event.event_type,
+                "reason": &reason,
+                "effects": effects
+            });
+            if let Some(item_id) = &event.item_id {
+                data["itemId"] = serde_json::json!(item_id);
+            }
+
+            let compensating_id = self.log_event(
+                "EVENT_CANCELLED",
+                data,
+                event.loan_id.as_deref(),
+                event.user_id.as_deref(),
+            )?;
+            self.conn.execute(
+                "UPDATE events SET cancelled_by = ?1, cancellation_reason = ?2 WHERE id = ?3",
+                params![compensating_id, reason, event.id],
+            )?;
+
+            self.get_event_by_id(&compensating_id)
+        })
     }
 
-    pub fn update_overdue_loans(&self) -> Result<usize> {
-        let today = Local::now().naive_local().date();
+    fn event_loan_id(event: &Event) -> Result<String> {
+        event
+            .loan_id
+            .clone()
+            .or_else(|| {
+                event.data["loanId"]
+                    .as_str()
+                    .or_else(|| event.data["id"].as_str())
+                    .map(String::from)
+            })
+            .ok_or_else(|| {
+                SafError::Validation("O evento non indica a que préstamo pertence".to_string())
+            })
+    }
 
-        let updated = self.conn.execute(
-            "UPDATE loans SET status = 'overdue' 
-             WHERE status = 'active' AND expected_end_date < ?1",
-            [today],
-        )?;
+    fn event_item_id(event: &Event) -> Result<String> {
+        event
+            .item_id
+            .clone()
+            .or_else(|| event.data["itemId"].as_str().map(String::from))
+            .ok_or_else(|| {
+                SafError::Validation("O evento non indica a que artigo pertence".to_string())
+            })
+    }
+
+    // Volver ao stock total anterior: retíranse as unidades engadidas ou
+    // recupéranse as que se deran de baixa
+    fn revert_stock_update(&self, event: &Event) -> Result<serde_json::Value> {
+        let now = Local::now().naive_local();
+        let item_id = &Self::event_item_id(event)?;
+        let (Some(previous_total), Some(new_total)) = (
+            event.data["previousTotal"].as_i64(),
+            event.data["newTotal"].as_i64(),
+        ) else {
+            return Err(SafError::Validation(
+                "O evento non garda o stock anterior e o novo".to_string(),
+            ));
+        };
+        let difference = (new_total - previous_total) as i32;
+
+        let mut retired_units: Vec<String> = Vec::new();
+        let mut restored_units: Vec<String> = Vec::new();
+        if difference > 0 {
+            let mut stmt = self.conn.prepare(
+                "SELECT id FROM units WHERE item_id = ?1 AND status = 'available'
+                 ORDER BY created_at DESC, tag DESC LIMIT ?2",
+            )?;
+            retired_units = stmt
+                .query_map(params![item_id, difference], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            if retired_units.len() < difference as usize {
+                return Err(SafError::Conflict(format!(
+                    "Non se pode anular: só hai {} unidades dispoñibles das {} engadidas",
+                    retired_units.len(),
+                    difference
+                )));
+            }
+            for unit_id in &retired_units {
+                self.conn.execute(
+                    "UPDATE units SET status = 'retired', updated_at = ?1 WHERE id = ?2",
+                    params![now, unit_id],
+                )?;
+            }
+        } else if difference < 0 {
+            let retired: Vec<String> = event.data["retiredUnits"]
+                .as_array()
+                .map(|ids| {
+                    ids.iter()
+                        .filter_map(|id| id.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default();
+            for unit_id in &retired {
+                let changed = self.conn.execute(
+                    "UPDATE units SET status = 'available', updated_at = ?1 WHERE id = ?2 AND status = 'retired'",
+                    params![now, unit_id],
+                )?;
+                if changed > 0 {
+                    restored_units.push(unit_id.clone());
+                }
+            }
+            // Os eventos anteriores ás unidades non gardan cales se retiraron
+            for _ in restored_units.len()..(-difference) as usize {
+                restored_units.push(self.insert_unit(item_id, None, None, None)?);
+            }
+        }
+
+        self.refresh_item_stock(item_id)?;
 
-        Ok(updated)
+        Ok(serde_json::json!({
+            "itemId": item_id,
+            "retiredUnits": retired_units,
+            "restoredUnits": restored_units
+        }))
     }
 
-    // Event operations
-    fn log_event(
-        &self,
-        event_type: &str,
-        data: serde_json::Value,
-        loan_id: Option<&str>,
-        user_id: Option<&str>,
-    ) -> Result<()> {
-        let id = Uuid::new_v4().to_string();
+    // Un préstamo creado por erro: as unidades pendentes volven ao stock
+    fn void_loan(&self, event: &Event, reason: Option<&str>) -> Result<serde_json::Value> {
+        let now = Local::now().naive_local();
+        let loan_id = Self::event_loan_id(event)?;
+        let status = self.check_transition(&loan_id, LoanTransition::Void)?;
+
+        let mut released = vec![];
+        for item in self.get_loan_items(&loan_id)? {
+            let outstanding = item.quantity - item.returned_quantity;
+            if outstanding > 0 {
+                released.extend(self.release_loan_item(&item, outstanding, None, None, reason)?);
+            }
+        }
 
         self.conn.execute(
-            "INSERT INTO events (id, event_type, data, loan_id, user_id) VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![id, event_type, data.to_string(), loan_id, user_id],
+            "UPDATE loans SET status = ?1, updated_at = ?2 WHERE id = ?3",
+            params![status.as_str(), now, loan_id],
         )?;
 
-        Ok(())
+        Ok(serde_json::json!({"loanId": loan_id, "releasedUnits": released}))
     }
 
-    pub fn get_events(&self, limit: i64) -> Result<Vec<Event>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, event_type, data, created_at, loan_id, user_id 
-             FROM events 
-             ORDER BY created_at DESC 
-             LIMIT ?1",
-        )?;
+    // Só se pode desfacer a última prórroga dun préstamo
+    fn revert_loan_extension(&self, event: &Event) -> Result<serde_json::Value> {
+        let now = Local::now().naive_local();
+        let loan_id = Self::event_loan_id(event)?;
+        self.check_transition(&loan_id, LoanTransition::Extend)?;
 
-        let events = stmt
-            .query_map([limit], |row| {
-                let data_str: String = row.get(2)?;
-                let data = serde_json::from_str(&data_str).unwrap_or(serde_json::Value::Null);
+        let extension: Option<(String, NaiveDate, NaiveDate)> = self
+            .conn
+            .query_row(
+                "SELECT e.id, e.previous_end_date, e.new_end_date
+                 FROM loan_extensions e
+                 JOIN loans l ON e.loan_id = l.id
+                 WHERE e.loan_id = ?1 AND e.new_end_date = l.expected_end_date
+                 ORDER BY e.created_at DESC LIMIT 1",
+                [&loan_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
 
-                Ok(Event {
-                    id: row.get(0)?,
-                    event_type: row.get(1)?,
-                    data,
-                    created_at: row.get(3)?,
-                    loan_id: row.get(4)?,
-                    user_id: row.get(5)?,
-                })
-            })?
-            .collect::<Result<Vec<_>>>()?;
+        let event_new_end = event.data["newEndDate"].as_str().unwrap_or_default();
+        let (extension_id, previous_end_date, new_end_date) = match extension {
+            Some(ext) if ext.2.to_string() == event_new_end => ext,
+            _ => {
+                return Err(SafError::Conflict(
+                    "Só se pode anular a última prórroga do préstamo".to_string(),
+                ))
+            }
+        };
 
-        Ok(events)
+        self.conn
+            .execute("DELETE FROM loan_extensions WHERE id = ?1", [&extension_id])?;
+        self.conn.execute(
+            "UPDATE loans SET expected_end_date = ?1, updated_at = ?2 WHERE id = ?3",
+            params![previous_end_date, now, loan_id],
+        )?;
+        self.update_overdue_loans()?;
+
+        Ok(serde_json::json!({
+            "loanId": loan_id,
+            "restoredEndDate": previous_end_date,
+            "removedEndDate": new_end_date
+        }))
     }
 
     // Dashboard stats
@@ -1099,11 +3877,22 @@ impl Database {
             |row| row.get(0),
         )?;
 
+        let units_in_maintenance: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM units WHERE status = 'in_repair'",
+            [],
+            |row| row.get(0),
+        )?;
+
         let total_users: i64 = self
             .conn
             .query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
 
-        let recent_loans = self.get_loans()?.into_iter().take(5).collect();
+        let recent_loans = self
+            .query_loans(&LoanQuery {
+                limit: Some(5),
+                ..Default::default()
+            })?
+            .loans;
         let recent_events = self.get_events(10)?;
 
         Ok(DashboardStats {
@@ -1112,9 +3901,1036 @@ impl Database {
             overdue_loans,
             total_items,
             total_items_available,
+            units_in_maintenance,
             total_users,
             recent_loans,
             recent_events,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> Database {
+        Database::new(":memory:").unwrap()
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn add_user(db: &Database, name: &str) -> User {
+        db.create_user(CreateUserRequest {
+            name: name.to_string(),
+            dni: None,
+            no_document: true,
+            address: "Rúa do Porto, 12".to_string(),
+            phone: None,
+            email: None,
+            notes: None,
+        })
+        .unwrap()
+    }
+
+    fn add_item(db: &Database, name: &str, stock: i32) -> Item {
+        db.create_item(CreateItemRequest {
+            name: name.to_string(),
+            description: None,
+            category: "Mobilidade".to_string(),
+            icon: "📦".to_string(),
+            total_stock: stock,
+            notes: None,
+        })
+        .unwrap()
+    }
+
+    fn loan_request(user: &User, items: &[(&Item, i32)]) -> CreateLoanRequest {
+        CreateLoanRequest {
+            user_id: user.id.clone(),
+            items: items
+                .iter()
+                .map(|(item, quantity)| LoanItemRequest {
+                    item_id: item.id.clone(),
+                    quantity: *quantity,
+                    unit_ids: None,
+                })
+                .collect(),
+            start_date: date("2026-01-01"),
+            expected_end_date: date("2026-02-01"),
+            notes: None,
+        }
+    }
+
+    // Fai fallar a seguinte escritura que cumpra a condición, para simular
+    // un erro a metade dunha operación
+    fn fail_on(db: &Database, write: &str) {
+        db.conn
+            .execute_batch(&format!(
+                "CREATE TEMP TRIGGER injected_failure {} BEGIN SELECT RAISE(ABORT, 'fallo inxectado'); END;",
+                write
+            ))
+            .unwrap();
+    }
+
+    // Estado de stock, préstamos e eventos para comparar antes e despois
+    fn snapshot(db: &Database) -> Vec<String> {
+        let queries = [
+            "SELECT id || ':' || total_stock || ':' || available_stock FROM items",
+            "SELECT id || ':' || status FROM units",
+            "SELECT id || ':' || status || ':' || COALESCE(actual_end_date, '') FROM loans",
+            "SELECT id || ':' || returned_quantity FROM loan_items",
+            "SELECT id || ':' || event_type || ':' || COALESCE(cancelled_by, '') FROM events",
+        ];
+        let mut rows = vec![];
+        for query in queries {
+            let mut stmt = db.conn.prepare(&format!("{} ORDER BY 1", query)).unwrap();
+            rows.extend(
+                stmt.query_map([], |row| row.get::<_, String>(0))
+                    .unwrap()
+                    .map(|row| row.unwrap()),
+            );
+        }
+        rows
+    }
+
+    fn assert_injected(result: Result<impl std::fmt::Debug>) {
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("fallo inxectado"), "{}", err);
+    }
+
+    #[test]
+    fn create_loan_rolls_back_when_a_line_fails() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 2);
+        let bed = add_item(&db, "Cama", 1);
+        let before = snapshot(&db);
+
+        fail_on(&db, "BEFORE INSERT ON loan_items");
+        assert_injected(db.create_loan(loan_request(&user, &[(&walker, 1), (&bed, 1)])));
+
+        assert_eq!(snapshot(&db), before);
+        assert!(db.conn.is_autocommit());
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 2);
+    }
+
+    #[test]
+    fn return_loan_rolls_back_when_closing_fails() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 2);
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 2)]))
+            .unwrap();
+        let before = snapshot(&db);
+
+        fail_on(&db, "BEFORE UPDATE ON loans");
+        assert_injected(db.return_loan(
+            &loan.id,
+            ReturnLoanRequest {
+                condition: None,
+                notes: None,
+            },
+        ));
+
+        assert_eq!(snapshot(&db), before);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 0);
+        assert_eq!(
+            db.get_loan_by_id(&loan.id).unwrap().status,
+            LoanStatus::Active
+        );
+    }
+
+    #[test]
+    fn cancel_return_rolls_back_when_reserving_fails() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 2);
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 2)]))
+            .unwrap();
+        db.return_loan(
+            &loan.id,
+            ReturnLoanRequest {
+                condition: None,
+                notes: None,
+            },
+        )
+        .unwrap();
+        let before = snapshot(&db);
+
+        fail_on(&db, "BEFORE UPDATE ON units");
+        assert_injected(db.cancel_return(&loan.id, None));
+
+        assert_eq!(snapshot(&db), before);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 2);
+        assert_eq!(
+            db.get_loan_by_id(&loan.id).unwrap().status,
+            LoanStatus::Returned
+        );
+    }
+
+    #[test]
+    fn add_default_items_rolls_back_after_first_item() {
+        let db = test_db();
+        let before = snapshot(&db);
+
+        fail_on(
+            &db,
+            "BEFORE INSERT ON items WHEN (SELECT COUNT(*) FROM items) >= 1",
+        );
+        assert_injected(db.add_default_items());
+
+        assert_eq!(snapshot(&db), before);
+        assert!(db.get_items().unwrap().is_empty());
+    }
+
+    #[test]
+    fn return_rejects_repeated_units() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 3);
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 2)]))
+            .unwrap();
+        let line = &loan.items[0];
+        let unit_id = line.units[0].unit_id.clone();
+        let before = snapshot(&db);
+
+        let err = db
+            .return_loan_item(
+                &line.id,
+                ReturnLoanItemRequest {
+                    quantity: Some(2),
+                    unit_ids: Some(vec![unit_id.clone(), unit_id]),
+                    condition: None,
+                    notes: None,
+                },
+            )
+            .unwrap_err();
+
+        assert!(matches!(err, SafError::Validation(_)), "{}", err);
+        assert_eq!(snapshot(&db), before);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 1);
+    }
+
+    #[test]
+    fn loan_rejects_repeated_units() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 3);
+        let unit_id = db.get_item_units(&walker.id).unwrap()[0].id.clone();
+        let mut req = loan_request(&user, &[(&walker, 2)]);
+        req.items[0].unit_ids = Some(vec![unit_id.clone(), unit_id]);
+
+        let err = db.create_loan(req).unwrap_err();
+
+        assert!(matches!(err, SafError::Validation(_)), "{}", err);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 3);
+    }
+
+    #[test]
+    fn update_item_trims_name() {
+        let db = test_db();
+        let walker = add_item(&db, "Andador", 1);
+
+        let updated = db
+            .update_item(
+                &walker.id,
+                UpdateItemRequest {
+                    name: Some("  Andador con rodas ".to_string()),
+                    description: None,
+                    category: None,
+                    icon: None,
+                    total_stock: None,
+                    notes: None,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(updated.name, "Andador con rodas");
+    }
+
+    // Os préstamos cargados en bloque teñen que coincidir co que se obtén
+    // préstamo a préstamo e persoa a persoa
+    #[test]
+    fn batched_loads_match_per_row() {
+        let db = test_db();
+        let ana = add_user(&db, "Ana");
+        let xose = add_user(&db, "Xosé");
+        let walker = add_item(&db, "Andador", 3);
+        let bed = add_item(&db, "Cama", 2);
+        let chair = add_item(&db, "Cadeira", 2);
+
+        db.create_loan(loan_request(&ana, &[(&walker, 2), (&bed, 1)]))
+            .unwrap();
+        let partial = db.create_loan(loan_request(&xose, &[(&chair, 2)])).unwrap();
+        db.return_loan_item(
+            &partial.items[0].id,
+            ReturnLoanItemRequest {
+                quantity: Some(1),
+                unit_ids: None,
+                condition: Some(ReturnCondition::Damaged),
+                notes: None,
+            },
+        )
+        .unwrap();
+        let returned = db.create_loan(loan_request(&ana, &[(&walker, 1)])).unwrap();
+        db.return_loan(
+            &returned.id,
+            ReturnLoanRequest {
+                condition: None,
+                notes: None,
+            },
+        )
+        .unwrap();
+
+        let loans = db.get_loans().unwrap();
+        assert_eq!(loans.len(), 3);
+        for loan in &loans {
+            assert_eq!(
+                serde_json::to_value(loan).unwrap(),
+                serde_json::to_value(db.get_loan_by_id(&loan.id).unwrap()).unwrap()
+            );
+        }
+
+        let mut loan_ids: Vec<String> = loans.iter().map(|l| l.id.clone()).collect();
+        loan_ids.push("non-existe".to_string());
+        let items = db.load_loan_items(&loan_ids).unwrap();
+        assert_eq!(items.len(), 3);
+        assert!(!items.contains_key("non-existe"));
+
+        let ids = vec![
+            ana.id.clone(),
+            xose.id.clone(),
+            "non-existe".to_string(),
+            ana.id.clone(),
+        ];
+        let users = db.get_users_by_ids(&ids).unwrap();
+        assert_eq!(users.len(), 2);
+        for id in &ids {
+            match db.get_user_by_id(id) {
+                Ok(user) => assert_eq!(
+                    serde_json::to_value(&users[id]).unwrap(),
+                    serde_json::to_value(user).unwrap()
+                ),
+                Err(SafError::NotFound(_)) => assert!(!users.contains_key(id)),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert!(db.get_users_by_ids(&[]).unwrap().is_empty());
+    }
+
+    // cargo test -- --ignored --nocapture load_50k_loans
+    #[test]
+    #[ignore]
+    fn load_50k_loans() {
+        let db = test_db();
+        let items: Vec<Item> = (0..20)
+            .map(|i| add_item(&db, &format!("Artigo {}", i), 5))
+            .collect();
+        db.conn
+            .execute_batch(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+                 INSERT INTO users (id, name, address) SELECT 'u' || i, 'Persoa ' || i, 'Rúa' FROM n;
+
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50000)
+                 INSERT INTO loans (id, user_id, start_date, expected_end_date, status)
+                 SELECT 'l' || i, 'u' || (i % 2000 + 1), date('2020-01-01', '+' || (i % 2000) || ' days'),
+                        date('2020-03-01', '+' || (i % 2000) || ' days'),
+                        CASE WHEN i % 3 = 0 THEN 'active' ELSE 'returned' END
+                 FROM n;",
+            )
+            .unwrap();
+        let mut stmt = db
+            .conn
+            .prepare(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50000)
+                 INSERT INTO loan_items (id, loan_id, item_id, quantity)
+                 SELECT 'li' || i || '-' || ?1, 'l' || i, ?2, 1 FROM n WHERE i % 20 = ?1 OR i % 7 = ?1",
+            )
+            .unwrap();
+        for (n, item) in items.iter().enumerate() {
+            stmt.execute(params![n as i64, item.id]).unwrap();
+        }
+        db.conn
+            .execute_batch(
+                "INSERT INTO loan_item_units (loan_item_id, unit_id)
+                 SELECT li.id, (SELECT MIN(u.id) FROM units u WHERE u.item_id = li.item_id)
+                 FROM loan_items li",
+            )
+            .unwrap();
+
+        let started = std::time::Instant::now();
+        let loans = db.get_loans().unwrap();
+        println!(
+            "get_loans: {} préstamos en {:?}",
+            loans.len(),
+            started.elapsed()
+        );
+        assert_eq!(loans.len(), 50000);
+
+        let started = std::time::Instant::now();
+        let mut query = LoanQuery {
+            limit: Some(50),
+            ..LoanQuery::default()
+        };
+        let mut pages = 0;
+        while pages < 100 {
+            let page = db.query_loans(&query).unwrap();
+            pages += 1;
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        println!(
+            "query_loans: {} páxinas de 50 en {:?}",
+            pages,
+            started.elapsed()
+        );
+
+        let started = std::time::Instant::now();
+        let page = db
+            .query_loans(&LoanQuery {
+                statuses: Some(vec![LoanStatus::Active]),
+                item_id: Some(items[3].id.clone()),
+                limit: Some(50),
+                ..LoanQuery::default()
+            })
+            .unwrap();
+        println!(
+            "query_loans filtrado: {} de {} en {:?}",
+            page.loans.len(),
+            page.total,
+            started.elapsed()
+        );
+    }
+
+    fn return_line(db: &Database, line: &LoanItem, quantity: i32) -> Loan {
+        db.return_loan_item(
+            &line.id,
+            ReturnLoanItemRequest {
+                quantity: Some(quantity),
+                unit_ids: None,
+                condition: None,
+                notes: None,
+            },
+        )
+        .unwrap()
+    }
+
+    fn close_loan(db: &Database, loan: &Loan, condition: Option<ReturnCondition>) -> Loan {
+        db.return_loan(
+            &loan.id,
+            ReturnLoanRequest {
+                condition,
+                notes: None,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn cancel_return_keeps_earlier_partial_returns() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 2);
+        let bed = add_item(&db, "Cama", 1);
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 1), (&bed, 1)]))
+            .unwrap();
+        let walker_unit = loan.items[0].units[0].unit_id.clone();
+
+        return_line(&db, &loan.items[0], 1);
+        close_loan(&db, &loan, None);
+        let reopened = db.cancel_return(&loan.id, None).unwrap();
+
+        assert_eq!(reopened.status, LoanStatus::Active);
+        assert_eq!(reopened.items[0].returned_quantity, 1);
+        assert!(reopened.items[0].returned_at.is_some());
+        assert_eq!(reopened.items[1].returned_quantity, 0);
+        assert!(reopened.items[1].returned_at.is_none());
+        assert_eq!(
+            db.get_unit_by_id(&walker_unit).unwrap().status,
+            UnitStatus::Available
+        );
+        assert_eq!(
+            db.get_unit_by_id(&reopened.items[1].units[0].unit_id)
+                .unwrap()
+                .status,
+            UnitStatus::Loaned
+        );
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 2);
+        assert_eq!(db.get_item_by_id(&bed.id).unwrap().available_stock, 0);
+
+        let reserved: Vec<serde_json::Value> = db
+            .get_events_by_loan(&loan.id)
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.event_type == "STOCK_RESERVED" && e.data["reason"] == "return_cancelled")
+            .map(|e| e.data)
+            .collect();
+        assert_eq!(reserved.len(), 1);
+        assert_eq!(reserved[0]["itemId"], bed.id.as_str());
+        assert_eq!(reserved[0]["quantity"], 1);
+    }
+
+    #[test]
+    fn cancel_return_after_unit_lent_again() {
+        let db = test_db();
+        let ana = add_user(&db, "Ana");
+        let xose = add_user(&db, "Xosé");
+        let walker = add_item(&db, "Andador", 1);
+        let bed = add_item(&db, "Cama", 1);
+        let loan = db
+            .create_loan(loan_request(&ana, &[(&walker, 1), (&bed, 1)]))
+            .unwrap();
+
+        return_line(&db, &loan.items[0], 1);
+        db.create_loan(loan_request(&xose, &[(&walker, 1)]))
+            .unwrap();
+        close_loan(&db, &loan, None);
+        let reopened = db.cancel_return(&loan.id, None).unwrap();
+
+        assert_eq!(reopened.status, LoanStatus::Active);
+        assert_eq!(reopened.items[0].returned_quantity, 1);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 0);
+        assert_eq!(db.get_item_by_id(&bed.id).unwrap().available_stock, 0);
+    }
+
+    #[test]
+    fn cancel_return_of_partly_returned_line() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 3);
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 2)]))
+            .unwrap();
+
+        let after_partial = return_line(&db, &loan.items[0], 1);
+        close_loan(&db, &loan, None);
+        let reopened = db.cancel_return(&loan.id, None).unwrap();
+
+        assert_eq!(reopened.items[0].returned_quantity, 1);
+        assert_eq!(
+            serde_json::to_value(&reopened.items[0].units).unwrap(),
+            serde_json::to_value(&after_partial.items[0].units).unwrap()
+        );
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 2);
+
+        // Unha devolución parcial que pecha o préstamo tamén se pode desfacer
+        return_line(&db, &reopened.items[0], 1);
+        let reopened = db.cancel_return(&loan.id, None).unwrap();
+        assert_eq!(reopened.items[0].returned_quantity, 1);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 2);
+    }
+
+    // Devolucións gardadas antes de rexistrar as liñas no evento
+    #[test]
+    fn cancel_return_without_recorded_lines() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 2);
+        let bed = add_item(&db, "Cama", 1);
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 1), (&bed, 1)]))
+            .unwrap();
+
+        return_line(&db, &loan.items[0], 1);
+        db.conn
+            .execute_batch(
+                "UPDATE loan_items SET returned_at = '2026-01-10' WHERE returned_at IS NOT NULL;
+                 UPDATE loan_item_units SET returned_at = '2026-01-10' WHERE returned_at IS NOT NULL;",
+            )
+            .unwrap();
+        close_loan(&db, &loan, None);
+        db.conn
+            .execute(
+                "UPDATE events SET data = json_remove(data, '$.lines') WHERE event_type = 'LOAN_RETURNED'",
+                [],
+            )
+            .unwrap();
+        let reopened = db.cancel_return(&loan.id, None).unwrap();
+
+        assert_eq!(reopened.items[0].returned_quantity, 1);
+        assert_eq!(reopened.items[0].returned_at, Some(date("2026-01-10")));
+        assert_eq!(reopened.items[1].returned_quantity, 0);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 2);
+        assert_eq!(db.get_item_by_id(&bed.id).unwrap().available_stock, 0);
+    }
+
+    #[test]
+    fn cancel_return_keeps_units_written_off_earlier() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 2);
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 2)]))
+            .unwrap();
+        let damaged = loan.items[0].units[0].unit_id.clone();
+        let lost = loan.items[0].units[1].unit_id.clone();
+
+        db.return_loan_item(
+            &loan.items[0].id,
+            ReturnLoanItemRequest {
+                quantity: Some(1),
+                unit_ids: Some(vec![damaged.clone()]),
+                condition: Some(ReturnCondition::Damaged),
+                notes: None,
+            },
+        )
+        .unwrap();
+        close_loan(&db, &loan, Some(ReturnCondition::Lost));
+        assert_eq!(db.get_unit_by_id(&lost).unwrap().status, UnitStatus::Lost);
+
+        let reopened = db.cancel_return(&loan.id, None).unwrap();
+
+        assert_eq!(
+            db.get_unit_by_id(&damaged).unwrap().status,
+            UnitStatus::InRepair
+        );
+        assert_eq!(db.get_unit_by_id(&lost).unwrap().status, UnitStatus::Loaned);
+        assert_eq!(reopened.items[0].returned_quantity, 1);
+        assert_eq!(
+            reopened.items[0].return_condition,
+            Some(ReturnCondition::Damaged)
+        );
+        let open_orders: i64 = db
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM maintenance_orders WHERE unit_id = ?1 AND closed_at IS NULL",
+                [&damaged],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(open_orders, 1);
+        let item = db.get_item_by_id(&walker.id).unwrap();
+        assert_eq!((item.total_stock, item.available_stock), (2, 0));
+    }
+
+    fn reconciled_events(db: &Database, item: &Item) -> Vec<serde_json::Value> {
+        db.get_events(1000)
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.event_type == "STOCK_RECONCILED" && e.item_id.as_ref() == Some(&item.id))
+            .map(|e| e.data)
+            .collect()
+    }
+
+    #[test]
+    fn check_stock_reports_and_repairs_counters() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 3);
+        add_item(&db, "Cama", 1);
+        db.create_loan(loan_request(&user, &[(&walker, 1)]))
+            .unwrap();
+        assert!(db.check_stock(false).unwrap().drifts.is_empty());
+
+        db.conn
+            .execute(
+                "UPDATE items SET available_stock = 3 WHERE id = ?1",
+                [&walker.id],
+            )
+            .unwrap();
+
+        let report = db.check_stock(false).unwrap();
+        assert_eq!(report.items_checked, 2);
+        assert!(!report.repaired);
+        assert_eq!(report.drifts.len(), 1);
+        let drift = &report.drifts[0];
+        assert_eq!(drift.item_id, walker.id);
+        assert_eq!((drift.stored_available, drift.expected_available), (3, 2));
+        // Sen reparar non se toca nada
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 3);
+        assert!(reconciled_events(&db, &walker).is_empty());
+
+        let report = db.check_stock(true).unwrap();
+        assert!(report.repaired);
+        assert_eq!(report.drifts.len(), 1);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().available_stock, 2);
+        let reconciled = reconciled_events(&db, &walker);
+        assert_eq!(reconciled.len(), 1);
+        assert_eq!(reconciled[0]["previousAvailable"], 3);
+        assert_eq!(reconciled[0]["newAvailable"], 2);
+        assert!(db.check_stock(false).unwrap().drifts.is_empty());
+    }
+
+    #[test]
+    fn check_stock_repairs_unit_status() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 2);
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 1)]))
+            .unwrap();
+        let unit_id = loan.items[0].units[0].unit_id.clone();
+
+        db.conn
+            .execute(
+                "UPDATE units SET status = 'available' WHERE id = ?1",
+                [&unit_id],
+            )
+            .unwrap();
+
+        let drift = db.check_stock(false).unwrap().drifts.remove(0);
+        assert_eq!((drift.loaned_units, drift.linked_units), (0, 1));
+        assert_eq!(drift.loaned_by_loans, 1);
+        assert_eq!(
+            db.get_unit_by_id(&unit_id).unwrap().status,
+            UnitStatus::Available
+        );
+
+        db.check_stock(true).unwrap();
+        assert_eq!(
+            db.get_unit_by_id(&unit_id).unwrap().status,
+            UnitStatus::Loaned
+        );
+        let reconciled = reconciled_events(&db, &walker);
+        assert_eq!(reconciled.len(), 1);
+        assert_eq!(reconciled[0]["unitsMarkedLoaned"], 1);
+        assert!(db.check_stock(false).unwrap().drifts.is_empty());
+    }
+
+    fn stock_update_event(db: &Database, item: &Item) -> Event {
+        db.get_events(1000)
+            .unwrap()
+            .into_iter()
+            .find(|e| e.event_type == "STOCK_UPDATED" && e.item_id.as_ref() == Some(&item.id))
+            .unwrap()
+    }
+
+    #[test]
+    fn cancel_stock_update_uses_event_item_id() {
+        let db = test_db();
+        let walker = add_item(&db, "Andador", 2);
+        db.update_item_stock(&walker.id, 1).unwrap();
+        let event = stock_update_event(&db, &walker);
+        db.conn
+            .execute(
+                "UPDATE events SET data = json_remove(data, '$.itemId') WHERE id = ?1",
+                [&event.id],
+            )
+            .unwrap();
+
+        db.cancel_event(&event.id, None).unwrap();
+
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().total_stock, 2);
+    }
+
+    #[test]
+    fn cancel_stock_update_without_item_rejected() {
+        let db = test_db();
+        let walker = add_item(&db, "Andador", 2);
+        db.update_item_stock(&walker.id, 1).unwrap();
+        let event = stock_update_event(&db, &walker);
+        db.conn
+            .execute(
+                "UPDATE events SET item_id = NULL, data = json_remove(data, '$.itemId') WHERE id = ?1",
+                [&event.id],
+            )
+            .unwrap();
+        let before = snapshot(&db);
+
+        let err = db.cancel_event(&event.id, None).unwrap_err();
+
+        assert!(matches!(err, SafError::Validation(_)), "{}", err);
+        assert_eq!(snapshot(&db), before);
+        assert_eq!(db.get_item_by_id(&walker.id).unwrap().total_stock, 1);
+    }
+
+    #[test]
+    fn add_attachment_rejects_large_files_before_reading() {
+        let db = test_db();
+        let walker = add_item(&db, "Andador", 1);
+        let path = std::env::temp_dir().join(format!("saf-anexo-{}.pdf", Uuid::new_v4()));
+        // Ficheiro disperso: ocupa o tamaño sen escribir os datos
+        std::fs::File::create(&path)
+            .unwrap()
+            .set_len(crate::attachments::MAX_ATTACHMENT_SIZE + 1)
+            .unwrap();
+
+        let result = db.add_attachment(AddAttachmentRequest {
+            item_id: walker.id.clone(),
+            source_path: path.to_string_lossy().to_string(),
+            kind: None,
+            notes: None,
+        });
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(SafError::Validation(_))));
+        assert!(db.get_attachments(&walker.id).unwrap().is_empty());
+    }
+
+    fn person(
+        name: &str,
+        address: &str,
+        phone: Option<&str>,
+        notes: Option<&str>,
+    ) -> CreateUserRequest {
+        CreateUserRequest {
+            name: name.to_string(),
+            dni: None,
+            no_document: true,
+            address: address.to_string(),
+            phone: phone.map(String::from),
+            email: None,
+            notes: notes.map(String::from),
+        }
+    }
+
+    fn user_names(users: Vec<User>) -> Vec<String> {
+        users.into_iter().map(|u| u.name).collect()
+    }
+
+    fn item_names(items: Vec<Item>) -> Vec<String> {
+        items.into_iter().map(|i| i.name).collect()
+    }
+
+    #[test]
+    fn search_ignores_accents() {
+        let db = test_db();
+        add_user(&db, "Xoán Núñez");
+        add_user(&db, "Ana Pérez");
+
+        assert_eq!(
+            user_names(db.search_users("Nuñez").unwrap()),
+            ["Xoán Núñez"]
+        );
+        assert_eq!(
+            user_names(db.search_users("nunez").unwrap()),
+            ["Xoán Núñez"]
+        );
+        assert_eq!(user_names(db.search_users("xoan").unwrap()), ["Xoán Núñez"]);
+    }
+
+    #[test]
+    fn search_needs_every_word() {
+        let db = test_db();
+        add_item(&db, "Cadeira de rodas", 1);
+        add_item(&db, "Cadeira de ducha", 1);
+        add_item(&db, "Andador con rodas", 1);
+
+        assert_eq!(
+            item_names(db.search_items("cadeira rodas").unwrap()),
+            ["Cadeira de rodas"]
+        );
+        assert_eq!(
+            item_names(db.search_items("cad rod").unwrap()),
+            ["Cadeira de rodas"]
+        );
+    }
+
+    #[test]
+    fn search_includes_notes() {
+        let db = test_db();
+        db.create_user(person(
+            "Ana",
+            "Rúa Nova, 3",
+            None,
+            Some("Terceiro sen ascensor"),
+        ))
+        .unwrap();
+        add_user(&db, "Xosé");
+        let walker = add_item(&db, "Andador", 1);
+        db.update_item(
+            &walker.id,
+            UpdateItemRequest {
+                name: None,
+                description: None,
+                category: None,
+                icon: None,
+                total_stock: None,
+                notes: Some("Revisar os freos".to_string()),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(user_names(db.search_users("ascensor").unwrap()), ["Ana"]);
+        assert_eq!(item_names(db.search_items("freos").unwrap()), ["Andador"]);
+    }
+
+    #[test]
+    fn search_index_follows_edits() {
+        let db = test_db();
+        let user = add_user(&db, "Ana Pérez");
+        let walker = add_item(&db, "Andador", 1);
+
+        db.update_user(
+            &user.id,
+            UpdateUserRequest {
+                name: Some("Ana Souto".to_string()),
+                dni: None,
+                no_document: false,
+                address: None,
+                phone: None,
+                email: None,
+                notes: None,
+            },
+        )
+        .unwrap();
+        db.update_item(
+            &walker.id,
+            UpdateItemRequest {
+                name: Some("Andador con rodas".to_string()),
+                description: None,
+                category: None,
+                icon: None,
+                total_stock: None,
+                notes: None,
+            },
+        )
+        .unwrap();
+        let loan = db
+            .create_loan(loan_request(&user, &[(&walker, 1)]))
+            .unwrap();
+        db.return_loan(
+            &loan.id,
+            ReturnLoanRequest {
+                condition: None,
+                notes: Some("Mudouse a Lugo".to_string()),
+            },
+        )
+        .unwrap();
+
+        assert!(db.search_users("Pérez").unwrap().is_empty());
+        assert_eq!(user_names(db.search_users("souto").unwrap()), ["Ana Souto"]);
+        assert_eq!(
+            item_names(db.search_items("rodas").unwrap()),
+            ["Andador con rodas"]
+        );
+        let loans = db.global_search("lugo", None).unwrap().loans;
+        assert_eq!(loans.len(), 1);
+        assert_eq!(loans[0].loan_id, loan.id);
+    }
+
+    #[test]
+    fn global_search_groups_results() {
+        let db = test_db();
+        let ana = db
+            .create_user(person(
+                "Ana",
+                "Rúa do Hórreo, 5",
+                Some("982 12 34 56"),
+                None,
+            ))
+            .unwrap();
+        db.create_user(person("Xosé", "Praza Maior, 1", Some("600 000 000"), None))
+            .unwrap();
+        let walker = add_item(&db, "Andador", 3);
+        let bed = add_item(&db, "Cama articulada", 1);
+        let mut with_note = loan_request(&ana, &[(&walker, 1)]);
+        with_note.notes = Some("Levar tamén un andador ao centro de día".to_string());
+        let by_note = db.create_loan(with_note).unwrap();
+        let by_item = db.create_loan(loan_request(&ana, &[(&walker, 1)])).unwrap();
+        db.create_loan(loan_request(&ana, &[(&bed, 1)])).unwrap();
+
+        let street = db.global_search("horreo", None).unwrap();
+        assert_eq!(street.users.len(), 1);
+        assert_eq!(street.users[0].user.id, ana.id);
+        assert!(street.items.is_empty() && street.loans.is_empty());
+
+        // Só díxitos: búscanse como fragmento do teléfono
+        let phone = db.global_search("1234", None).unwrap();
+        assert_eq!(phone.users.len(), 1);
+        assert_eq!(phone.users[0].user.id, ana.id);
+
+        let loans = db.global_search("andador", None).unwrap();
+        assert_eq!(loans.items.len(), 1);
+        assert_eq!(loans.items[0].item.id, walker.id);
+        let found: Vec<(&str, &LoanMatch)> = loans
+            .loans
+            .iter()
+            .map(|h| (h.loan_id.as_str(), &h.matched))
+            .collect();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, by_note.id);
+        assert!(matches!(found[0].1, LoanMatch::Notes));
+        assert_eq!(found[1].0, by_item.id);
+        assert!(matches!(found[1].1, LoanMatch::ItemName));
+
+        let by_note_only = db.global_search("centro", None).unwrap();
+        assert_eq!(by_note_only.loans.len(), 1);
+        assert_eq!(by_note_only.loans[0].loan_id, by_note.id);
+    }
+
+    #[test]
+    fn loan_category_filter_survives_rename_and_merge() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 2);
+        let chair = db
+            .create_item(CreateItemRequest {
+                name: "Cadeira de ducha".to_string(),
+                description: None,
+                category: "Baño".to_string(),
+                icon: "🚿".to_string(),
+                total_stock: 1,
+                notes: None,
+            })
+            .unwrap();
+        db.create_loan(loan_request(&user, &[(&walker, 1)]))
+            .unwrap();
+        db.create_loan(loan_request(&user, &[(&chair, 1)])).unwrap();
+        let in_category = |category_id: &str| {
+            db.query_loans(&LoanQuery {
+                category_id: Some(category_id.to_string()),
+                ..LoanQuery::default()
+            })
+            .unwrap()
+            .total
+        };
+
+        assert_eq!(in_category(&walker.category_id), 1);
+        db.update_category(
+            &walker.category_id,
+            UpdateCategoryRequest {
+                name: Some("Axudas á mobilidade".to_string()),
+                icon: None,
+                sort_order: None,
+                default_loan_days: None,
+                active: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(in_category(&walker.category_id), 1);
+
+        db.merge_categories(&chair.category_id, &walker.category_id)
+            .unwrap();
+        assert_eq!(in_category(&walker.category_id), 2);
+    }
+
+    #[test]
+    fn reminder_shows_in_every_loan_history() {
+        let db = test_db();
+        let user = add_user(&db, "Ana");
+        let walker = add_item(&db, "Andador", 1);
+        let bed = add_item(&db, "Cama", 1);
+        let first = db
+            .create_loan(loan_request(&user, &[(&walker, 1)]))
+            .unwrap();
+        let second = db.create_loan(loan_request(&user, &[(&bed, 1)])).unwrap();
+
+        db.log_reminder_sent(&[&first, &second], "recordatorio.pdf")
+            .unwrap();
+
+        let reminders = |loan: &Loan| -> Vec<serde_json::Value> {
+            db.get_events_by_loan(&loan.id)
+                .unwrap()
+                .into_iter()
+                .filter(|e| e.event_type == "REMINDER_SENT")
+                .map(|e| e.data)
+                .collect()
+        };
+        let (a, b) = (reminders(&first), reminders(&second));
+        assert_eq!((a.len(), b.len()), (1, 1));
+        assert_eq!(a[0]["letterId"], b[0]["letterId"]);
+        assert_eq!(a[0]["file"], "recordatorio.pdf");
+        assert_eq!(a[0]["items"][0]["itemName"], "Andador");
+        assert_eq!(b[0]["items"][0]["itemName"], "Cama");
+        assert_eq!(a[0]["loanIds"].as_array().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::error::{Result, SafError};
+
+// Letras de control do DNI/NIE: a letra é a posición (número mod 23)
+const CONTROL_LETTERS: &[u8; 23] = b"TRWAGMYFPDXBNJZSQVHLCKE";
+
+// Forma canónica dun DNI ou NIE: sen espazos, puntos nin guións, en
+// maiúsculas ("12345678Z", "X1234567L"). Esixe os oito díxitos do DNI ou
+// os sete do NIE e comproba a letra de control.
+pub fn normalize_dni(input: &str) -> Result<String> {
+    let cleaned: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '.' && *c != '-')
+        .collect::<String>()
+        .to_uppercase();
+
+    if cleaned.is_empty() {
+        return Err(SafError::Validation("O DNI/NIE está baleiro".to_string()));
+    }
+
+    let (prefix, rest) = match cleaned.chars().next() {
+        Some(c @ ('X' | 'Y' | 'Z')) => (Some(c), &cleaned[1..]),
+        _ => (None, cleaned.as_str()),
+    };
+
+    let invalid_format = || {
+        SafError::Validation(format!(
+            "O DNI/NIE '{}' non ten un formato válido (p.ex. 12345678Z ou X1234567L)",
+            input.trim()
+        ))
+    };
+
+    let letter = rest.chars().last().ok_or_else(invalid_format)?;
+    let digits = &rest[..rest.len() - letter.len_utf8()];
+    let expected_digits = if prefix.is_some() { 7 } else { 8 };
+    if !letter.is_ascii_alphabetic()
+        || digits.len() != expected_digits
+        || !digits.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid_format());
+    }
+
+    let number: u32 = match prefix {
+        Some('X') | None => digits.parse(),
+        Some('Y') => format!("1{}", digits).parse(),
+        Some(_) => format!("2{}", digits).parse(),
+    }
+    .map_err(|_| invalid_format())?;
+
+    let expected = CONTROL_LETTERS[(number % 23) as usize] as char;
+    if letter != expected {
+        return Err(SafError::Validation(format!(
+            "A letra do DNI/NIE '{}' non é correcta (debería ser {})",
+            input.trim(),
+            expected
+        )));
+    }
+
+    Ok(match prefix {
+        Some(p) => format!("{}{}{}", p, digits, letter),
+        None => format!("{}{}", digits, letter),
+    })
+}
+
+// DNI dunha petición: None só se se indicou explicitamente que non ten documento
+pub fn document_from_request(dni: Option<&str>, no_document: bool) -> Result<Option<String>> {
+    let dni = dni.map(str::trim).filter(|d| !d.is_empty());
+    match (dni, no_document) {
+        (Some(_), true) => Err(SafError::Validation(
+            "Indicouse un DNI/NIE pero tamén que a persoa non ten documento".to_string(),
+        )),
+        (Some(dni), false) => Ok(Some(normalize_dni(dni)?)),
+        (None, true) => Ok(None),
+        (None, false) => Err(SafError::Validation(
+            "Indique o DNI/NIE ou marque que a persoa non ten documento".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_invalid(input: &str) {
+        let err = normalize_dni(input).unwrap_err();
+        assert!(matches!(err, SafError::Validation(_)), "{}: {}", input, err);
+    }
+
+    #[test]
+    fn valid_dni() {
+        assert_eq!(normalize_dni("12345678Z").unwrap(), "12345678Z");
+    }
+
+    #[test]
+    fn valid_nie_prefixes() {
+        assert_eq!(normalize_dni("X1234567L").unwrap(), "X1234567L");
+        assert_eq!(normalize_dni("Y1234567X").unwrap(), "Y1234567X");
+        assert_eq!(normalize_dni("Z1234567R").unwrap(), "Z1234567R");
+    }
+
+    #[test]
+    fn cleans_case_and_separators() {
+        assert_eq!(normalize_dni("12345678z").unwrap(), "12345678Z");
+        assert_eq!(normalize_dni(" 12 345 678 Z ").unwrap(), "12345678Z");
+        assert_eq!(normalize_dni("12.345.678-Z").unwrap(), "12345678Z");
+        assert_eq!(normalize_dni("x-1234567-l").unwrap(), "X1234567L");
+    }
+
+    #[test]
+    fn wrong_letter_rejected() {
+        assert_invalid("12345678A");
+        assert_invalid("X1234567Z");
+    }
+
+    #[test]
+    fn wrong_digit_count_rejected() {
+        assert_invalid("1234567Z");
+        assert_invalid("123456789Z");
+        assert_invalid("X123456L");
+        assert_invalid("X12345678L");
+    }
+
+    #[test]
+    fn malformed_rejected() {
+        assert_invalid("");
+        assert_invalid(" - ");
+        assert_invalid("12345678");
+        assert_invalid("1234A678Z");
+    }
+
+    #[test]
+    fn explicit_no_document() {
+        assert_eq!(document_from_request(None, true).unwrap(), None);
+        assert_eq!(document_from_request(Some("  "), true).unwrap(), None);
+    }
+
+    #[test]
+    fn document_required_unless_declared() {
+        assert!(document_from_request(None, false).is_err());
+        assert!(document_from_request(Some("12345678Z"), true).is_err());
+        assert_eq!(
+            document_from_request(Some("12345678z"), false).unwrap(),
+            Some("12345678Z".to_string())
+        );
+    }
+}
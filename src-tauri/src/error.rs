@@ -0,0 +1,133 @@
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+use crate::models::InvalidLoanTransition;
+
+pub type Result<T> = std::result::Result<T, SafError>;
+
+// Erros da aplicación. Chegan ao frontend como `{ code, message, details }`
+// para que a interface poida distinguilos sen analizar o texto.
+#[derive(Debug, thiserror::Error)]
+pub enum SafError {
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("{0}")]
+    Validation(String),
+
+    #[error(
+        "Non hai stock suficiente de {item_name}: solicitados {requested}, dispoñibles {available}"
+    )]
+    StockUnavailable {
+        item_id: String,
+        item_name: String,
+        requested: i32,
+        available: i32,
+    },
+
+    #[error(transparent)]
+    InvalidTransition(#[from] InvalidLoanTransition),
+
+    #[error("Erro de entrada/saída: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Erro na base de datos: {0}")]
+    Database(rusqlite::Error),
+
+    #[error("Erro ao exportar: {0}")]
+    Export(String),
+
+    #[error("Erro interno de bloqueo: {0}")]
+    Lock(String),
+}
+
+impl SafError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SafError::NotFound(_) => "NOT_FOUND",
+            SafError::Conflict(_) => "CONFLICT",
+            SafError::Validation(_) => "VALIDATION",
+            SafError::StockUnavailable { .. } => "STOCK_UNAVAILABLE",
+            SafError::InvalidTransition(_) => "INVALID_TRANSITION",
+            SafError::Io(_) => "IO",
+            SafError::Database(_) => "DATABASE",
+            SafError::Export(_) => "EXPORT",
+            SafError::Lock(_) => "LOCK",
+        }
+    }
+
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            SafError::StockUnavailable {
+                item_id,
+                item_name,
+                requested,
+                available,
+            } => Some(serde_json::json!({
+                "itemId": item_id,
+                "itemName": item_name,
+                "requested": requested,
+                "available": available
+            })),
+            SafError::InvalidTransition(e) => Some(serde_json::json!({
+                "from": e.from.as_ref().map(|s| s.as_str()),
+                "transition": e.transition
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for SafError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                SafError::NotFound("Non se atopou o rexistro solicitado".to_string())
+            }
+            rusqlite::Error::SqliteFailure(err, msg)
+                if err.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                SafError::Conflict(format!(
+                    "Os datos entran en conflito cos xa gardados ({})",
+                    msg.unwrap_or_default()
+                ))
+            }
+            e => SafError::Database(e),
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for SafError {
+    fn from(e: zip::result::ZipError) -> Self {
+        SafError::Io(std::io::Error::other(e))
+    }
+}
+
+// As exportacións devolven Box<dyn Error>; os erros propios consérvanse
+impl From<Box<dyn std::error::Error>> for SafError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        match e.downcast::<SafError>() {
+            Ok(e) => *e,
+            Err(e) => SafError::Export(e.to_string()),
+        }
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for SafError {
+    fn from(e: std::sync::PoisonError<T>) -> Self {
+        SafError::Lock(e.to_string())
+    }
+}
+
+impl Serialize for SafError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("SafError", 3)?;
+        s.serialize_field("code", self.code())?;
+        s.serialize_field("message", &self.to_string())?;
+        s.serialize_field("details", &self.details())?;
+        s.end()
+    }
+}
@@ -5,9 +5,65 @@ use xlsxwriter::{Format, Workbook};
 use crate::database::Database;
 use crate::models::*;
 
+// "Cama eléctrica, Coxín x2"
+fn items_summary(loan: &Loan) -> String {
+    loan.items
+        .iter()
+        .map(|i| {
+            if i.quantity > 1 {
+                format!("{} x{}", i.item_name, i.quantity)
+            } else {
+                i.item_name.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Estado da devolución dunha liña: a data, "pendente" ou "1/2 (data)"
+fn line_return_text(item: &LoanItem, date_format: &str) -> String {
+    let date = item
+        .returned_at
+        .map(|d| d.format(date_format).to_string())
+        .unwrap_or_default();
+    if item.returned_quantity >= item.quantity {
+        date
+    } else if item.returned_quantity == 0 {
+        "pendente".to_string()
+    } else {
+        format!("{}/{} ({})", item.returned_quantity, item.quantity, date)
+    }
+}
+
+// Algunha liña devolveuse nunha data distinta á de peche do préstamo
+fn has_partial_returns(loan: &Loan) -> bool {
+    loan.items
+        .iter()
+        .any(|i| i.returned_quantity > 0 && i.returned_at != loan.actual_end_date)
+}
+
+// Persoas distintas dunha lista de préstamos, para cargalas dunha vez
+fn user_ids(loans: &[Loan]) -> Vec<String> {
+    let mut ids: Vec<String> = loans.iter().map(|l| l.user_id.clone()).collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+// Préstamos comezados no ano indicado
+fn year_query(year: i32) -> LoanQuery {
+    LoanQuery {
+        start_from: chrono::NaiveDate::from_ymd_opt(year, 1, 1),
+        start_to: chrono::NaiveDate::from_ymd_opt(year, 12, 31),
+        ..Default::default()
+    }
+}
+
+// Exporta os préstamos que cumpren `query` (os mesmos que se ven na lista)
 pub fn export_loans_to_excel<P: AsRef<Path>>(
     db: &Database,
     path: P,
+    query: &LoanQuery,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let workbook = Workbook::new(path.as_ref().to_str().unwrap())?;
 
@@ -33,8 +89,17 @@ pub fn export_loans_to_excel<P: AsRef<Path>>(
     sheet1.write_string(0, 6, "Data Devolución", Some(&header_format))?;
     sheet1.write_string(0, 7, "Estado", Some(&header_format))?;
     sheet1.write_string(0, 8, "Notas", Some(&header_format))?;
-
-    let loans = db.get_loans()?;
+    sheet1.write_string(0, 9, "Renovacións", Some(&header_format))?;
+
+    // A páxina da pantalla non se aplica: expórtanse todos os resultados
+    let query = LoanQuery {
+        offset: None,
+        cursor: None,
+        limit: None,
+        ..query.clone()
+    };
+    let loans = db.query_loans(&query)?.loans;
+    let loan_users = db.get_users_by_ids(&user_ids(&loans))?;
 
     for (i, loan) in loans.iter().enumerate() {
         let row = (i + 1) as u32;
@@ -43,17 +108,12 @@ pub fn export_loans_to_excel<P: AsRef<Path>>(
         sheet1.write_string(row, 1, &loan.user_name, None)?;
 
         // Get user DNI
-        if let Ok(user) = db.get_user_by_id(&loan.user_id) {
-            sheet1.write_string(row, 2, &user.dni, None)?;
+        if let Some(user) = loan_users.get(&loan.user_id) {
+            sheet1.write_string(row, 2, user.dni.as_deref().unwrap_or_default(), None)?;
         }
 
         // Artigos
-        let items_text = loan
-            .items
-            .iter()
-            .map(|i| i.item_name.clone())
-            .collect::<Vec<_>>()
-            .join(", ");
+        let items_text = items_summary(loan);
         sheet1.write_string(row, 3, &items_text, None)?;
 
         sheet1.write_string(row, 4, &loan.start_date.to_string(), None)?;
@@ -69,12 +129,14 @@ pub fn export_loans_to_excel<P: AsRef<Path>>(
             LoanStatus::Pending => "Pendente",
             LoanStatus::Returned => "Devolto",
             LoanStatus::Overdue => "Atrasado",
+            LoanStatus::Cancelled => "Anulado",
         };
         sheet1.write_string(row, 7, status_text, None)?;
 
         if let Some(notes) = &loan.notes {
             sheet1.write_string(row, 8, notes, None)?;
         }
+        sheet1.write_number(row, 9, loan.extension_count as f64, None)?;
     }
 
     // Sheet 2: Usuarios
@@ -93,7 +155,7 @@ pub fn export_loans_to_excel<P: AsRef<Path>>(
         let row = (i + 1) as u32;
 
         sheet2.write_string(row, 0, &user.name, None)?;
-        sheet2.write_string(row, 1, &user.dni, None)?;
+        sheet2.write_string(row, 1, user.dni.as_deref().unwrap_or_default(), None)?;
         sheet2.write_string(row, 2, &user.address, None)?;
 
         if let Some(phone) = &user.phone {
@@ -138,6 +200,39 @@ pub fn export_loans_to_excel<P: AsRef<Path>>(
         }
     }
 
+    // Sheet 4: Unidades
+    let mut sheet4 = workbook.add_worksheet(Some("Unidades"))?;
+
+    sheet4.write_string(0, 0, "Etiqueta", Some(&header_format))?;
+    sheet4.write_string(0, 1, "Artigo", Some(&header_format))?;
+    sheet4.write_string(0, 2, "Estado", Some(&header_format))?;
+    sheet4.write_string(0, 3, "Data Adquisición", Some(&header_format))?;
+    sheet4.write_string(0, 4, "Notas", Some(&header_format))?;
+
+    let mut row: u32 = 1;
+    for item in &items {
+        for unit in db.get_item_units(&item.id)? {
+            let status_text = match unit.status {
+                UnitStatus::Available => "Dispoñible",
+                UnitStatus::Loaned => "En préstamo",
+                UnitStatus::InRepair => "En reparación",
+                UnitStatus::Lost => "Perdida",
+                UnitStatus::Retired => "De baixa",
+            };
+
+            sheet4.write_string(row, 0, &unit.tag, None)?;
+            sheet4.write_string(row, 1, &unit.item_name, None)?;
+            sheet4.write_string(row, 2, status_text, None)?;
+            if let Some(date) = unit.acquisition_date {
+                sheet4.write_string(row, 3, &date.to_string(), None)?;
+            }
+            if let Some(notes) = &unit.notes {
+                sheet4.write_string(row, 4, notes, None)?;
+            }
+            row += 1;
+        }
+    }
+
     workbook.close()?;
 
     Ok(())
@@ -179,19 +274,9 @@ pub fn export_annual_report<P: AsRef<Path>>(
     )?;
 
     // Get all loans for the year
-    let loans = db.get_loans()?;
-    let year_loans: Vec<_> = loans
-        .iter()
-        .filter(|l| {
-            let start_year = l
-                .start_date
-                .format("%Y")
-                .to_string()
-                .parse::<i32>()
-                .unwrap_or(0);
-            start_year == year
-        })
-        .collect();
+    let loans = db.query_loans(&year_query(year))?.loans;
+    let year_loans: Vec<_> = loans.iter().collect();
+    let users = db.get_users_by_ids(&user_ids(&loans))?;
 
     // Group by user - only users who had loans
     let mut user_loans: std::collections::HashMap<String, Vec<&crate::models::Loan>> =
@@ -213,6 +298,8 @@ pub fn export_annual_report<P: AsRef<Path>>(
     sheet.write_string(row, 4, "Artigos", Some(&header_format))?;
     sheet.write_string(row, 5, "Estado", Some(&header_format))?;
     sheet.write_string(row, 6, "Notas", Some(&header_format))?;
+    sheet.write_string(row, 7, "Devolución por artigo", Some(&header_format))?;
+    sheet.write_string(row, 8, "Renovacións", Some(&header_format))?;
     row += 1;
 
     // Sort users by name
@@ -225,25 +312,20 @@ pub fn export_annual_report<P: AsRef<Path>>(
 
     for (user_id, loans) in sorted_users {
         let user_name = loans.first().map(|l| l.user_name.as_str()).unwrap_or("?");
-        let user_dni = db
-            .get_user_by_id(user_id)
-            .ok()
-            .map(|u| u.dni)
+        let user_dni = users
+            .get(user_id.as_str())
+            .and_then(|u| u.dni.clone())
             .unwrap_or_default();
 
         for loan in loans {
-            let items_text = loan
-                .items
-                .iter()
-                .map(|i| i.item_name.clone())
-                .collect::<Vec<_>>()
-                .join(", ");
+            let items_text = items_summary(loan);
 
             let status_text = match loan.status {
                 LoanStatus::Active => "Activo",
                 LoanStatus::Pending => "Pendente",
                 LoanStatus::Returned => "Devolto",
                 LoanStatus::Overdue => "Atrasado",
+                LoanStatus::Cancelled => "Anulado",
             };
 
             sheet.write_string(row, 0, user_name, None)?;
@@ -257,6 +339,14 @@ pub fn export_annual_report<P: AsRef<Path>>(
             if let Some(notes) = &loan.notes {
                 sheet.write_string(row, 6, notes, None)?;
             }
+            let line_returns = loan
+                .items
+                .iter()
+                .map(|i| format!("{}: {}", i.item_name, line_return_text(i, "%Y-%m-%d")))
+                .collect::<Vec<_>>()
+                .join("; ");
+            sheet.write_string(row, 7, &line_returns, None)?;
+            sheet.write_number(row, 8, loan.extension_count as f64, None)?;
             row += 1;
         }
     }
@@ -288,8 +378,91 @@ pub fn export_annual_report<P: AsRef<Path>>(
     sheet.set_column(4, 4, 40.0, None)?;
     sheet.set_column(5, 5, 12.0, None)?;
     sheet.set_column(6, 6, 30.0, None)?;
+    sheet.set_column(7, 7, 40.0, None)?;
+
+    workbook.close()?;
+    Ok(())
+}
+
+// Perdas e danos rexistrados nas devolucións dun período
+pub fn export_damage_report<P: AsRef<Path>>(
+    db: &Database,
+    path: P,
+    from: chrono::NaiveDate,
+    to: chrono::NaiveDate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = path.as_ref().to_str().ok_or_else(|| {
+        crate::error::SafError::Export(format!(
+            "A ruta {} non é válida para gardar o informe",
+            path.as_ref().display()
+        ))
+    })?;
+    let workbook = Workbook::new(path)?;
+
+    let mut header_format = Format::new();
+    header_format.set_bold();
+    header_format.set_bg_color(FormatColor::Custom(0x1A6B5A));
+    header_format.set_font_color(FormatColor::Custom(0xFFFFFF));
+
+    let mut title_format = Format::new();
+    title_format.set_bold();
+    title_format.set_font_size(14.0);
+
+    let mut sheet = workbook.add_worksheet(Some("Perdas e danos"))?;
+
+    sheet.write_string(
+        0,
+        0,
+        &format!(
+            "Perdas e danos - {} a {}",
+            from.format("%d/%m/%Y"),
+            to.format("%d/%m/%Y")
+        ),
+        Some(&title_format),
+    )?;
+
+    let mut row: u32 = 2;
+    sheet.write_string(row, 0, "Data devolución", Some(&header_format))?;
+    sheet.write_string(row, 1, "Estado", Some(&header_format))?;
+    sheet.write_string(row, 2, "Artigo", Some(&header_format))?;
+    sheet.write_string(row, 3, "Etiqueta", Some(&header_format))?;
+    sheet.write_string(row, 4, "Usuaria/o", Some(&header_format))?;
+    sheet.write_string(row, 5, "Préstamo", Some(&header_format))?;
+    sheet.write_string(row, 6, "Notas", Some(&header_format))?;
+    row += 1;
+
+    let entries = db.get_damage_report(from, to)?;
+    for entry in &entries {
+        sheet.write_string(row, 0, &entry.returned_at.to_string(), None)?;
+        sheet.write_string(row, 1, entry.condition.label(), None)?;
+        sheet.write_string(row, 2, &entry.item_name, None)?;
+        sheet.write_string(row, 3, &entry.unit_tag, None)?;
+        sheet.write_string(row, 4, &entry.user_name, None)?;
+        sheet.write_string(row, 5, &entry.loan_id, None)?;
+        if let Some(notes) = &entry.notes {
+            sheet.write_string(row, 6, notes, None)?;
+        }
+        row += 1;
+    }
+
+    let damaged = entries
+        .iter()
+        .filter(|e| e.condition == ReturnCondition::Damaged)
+        .count();
+    let lost = entries
+        .iter()
+        .filter(|e| e.condition == ReturnCondition::Lost)
+        .count();
+
+    row += 1;
+    sheet.write_string(row, 0, "Danadas:", Some(&header_format))?;
+    sheet.write_number(row, 1, damaged as f64, None)?;
+    row += 1;
+    sheet.write_string(row, 0, "Perdidas:", Some(&header_format))?;
+    sheet.write_number(row, 1, lost as f64, None)?;
 
     workbook.close()?;
+
     Ok(())
 }
 
@@ -303,18 +476,9 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
     use std::fs::File;
     use std::io::BufWriter;
 
-    let loans = db.get_loans()?;
-    let year_loans: Vec<_> = loans
-        .iter()
-        .filter(|l| {
-            l.start_date
-                .format("%Y")
-                .to_string()
-                .parse::<i32>()
-                .unwrap_or(0)
-                == year
-        })
-        .collect();
+    let loans = db.query_loans(&year_query(year))?.loans;
+    let year_loans: Vec<_> = loans.iter().collect();
+    let users = db.get_users_by_ids(&user_ids(&loans))?;
 
     let mut user_loans: std::collections::HashMap<String, Vec<&crate::models::Loan>> =
         std::collections::HashMap::new();
@@ -340,8 +504,7 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
 
     fn draw_user_section(
         layer: &PdfLayerReference,
-        db: &Database,
-        user_id: &str,
+        user: Option<&User>,
         user_name: &str,
         loans: &[&Loan],
         x: Mm,
@@ -356,8 +519,6 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
             return true;
         }
 
-        let user = db.get_user_by_id(user_id).ok();
-
         layer.use_text(
             "________________________________________________________________________________",
             8.0,
@@ -370,10 +531,10 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
         layer.use_text(user_name, 12.0, x, *y, font_bold);
         *y -= Mm(6.0);
 
-        if let Some(ref u) = user {
+        if let Some(u) = user {
             let mut info_parts = Vec::new();
-            if !u.dni.is_empty() {
-                info_parts.push(format!("DNI: {}", u.dni));
+            if let Some(dni) = &u.dni {
+                info_parts.push(format!("DNI: {}", dni));
             }
             if let Some(ref phone) = u.phone {
                 if !phone.is_empty() {
@@ -392,7 +553,7 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
         *y -= Mm(2.0);
 
         let col_widths = [Mm(22.0), Mm(22.0), Mm(55.0), Mm(22.0), Mm(35.0), Mm(20.0)];
-        let headers = ["Inicio", "Devol.", "Artigos", "Estado", "Notas", ""];
+        let headers = ["Inicio", "Devol.", "Artigos", "Estado", "Notas", "Renov."];
         let mut col_x = x;
         for (i, header) in headers.iter().enumerate() {
             layer.use_text(*header, 7.0, col_x, *y, font_bold);
@@ -405,12 +566,7 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
                 return true;
             }
 
-            let items_text: String = loan
-                .items
-                .iter()
-                .map(|i| i.item_name.clone())
-                .collect::<Vec<_>>()
-                .join(", ");
+            let items_text = items_summary(loan);
             let items_display = if items_text.len() > 32 {
                 format!("{}...", &items_text[..29])
             } else {
@@ -422,6 +578,7 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
                 LoanStatus::Pending => "Pendente",
                 LoanStatus::Returned => "Devolto",
                 LoanStatus::Overdue => "Atrasado",
+                LoanStatus::Cancelled => "Anulado",
             };
             let end_date = loan
                 .actual_end_date
@@ -440,12 +597,13 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
                 })
                 .unwrap_or_default();
 
-            let row_data: [String; 5] = [
+            let row_data: [String; 6] = [
                 loan.start_date.format("%d/%m/%y").to_string(),
                 end_date,
                 items_display,
                 status_text.to_string(),
                 notes_display,
+                loan.extension_count.to_string(),
             ];
 
             let mut col_x = x;
@@ -454,6 +612,20 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
                 col_x += col_widths[i];
             }
             *y -= Mm(4.0);
+
+            // Devolucións parciais: unha liña por artigo coa súa data
+            if has_partial_returns(loan) {
+                for item in &loan.items {
+                    let line = format!(
+                        "- {} x{}: {}",
+                        item.item_name,
+                        item.quantity,
+                        line_return_text(item, "%d/%m/%y")
+                    );
+                    layer.use_text(&line, 6.5, x + col_widths[0], *y, font_italic);
+                    *y -= Mm(3.5);
+                }
+            }
         }
 
         *y -= Mm(8.0);
@@ -517,7 +689,11 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
     );
     y_pos -= Mm(6.0);
 
-    let total_items: usize = year_loans.iter().map(|l| l.items.len()).sum();
+    let total_items: i32 = year_loans
+        .iter()
+        .flat_map(|l| l.items.iter())
+        .map(|i| i.quantity)
+        .sum();
     let active_loans = year_loans
         .iter()
         .filter(|l| l.status == LoanStatus::Active)
@@ -589,8 +765,7 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
 
             let needs_new_page = draw_user_section(
                 &current_layer_ref,
-                db,
-                user_id,
+                users.get(user_id),
                 user_name,
                 &u_loans,
                 margin_left,
@@ -642,3 +817,757 @@ pub fn export_annual_report_pdf<P: AsRef<Path>>(
     doc.save(&mut BufWriter::new(File::create(path)?))?;
     Ok(())
 }
+
+// Tipos de letra dos documentos PDF dun préstamo
+struct PdfFonts {
+    regular: printpdf::IndirectFontRef,
+    bold: printpdf::IndirectFontRef,
+    oblique: printpdf::IndirectFontRef,
+}
+
+impl PdfFonts {
+    fn new(doc: &printpdf::PdfDocumentReference) -> Result<Self, printpdf::Error> {
+        use printpdf::BuiltinFont;
+        Ok(Self {
+            regular: doc.add_builtin_font(BuiltinFont::Helvetica)?,
+            bold: doc.add_builtin_font(BuiltinFont::HelveticaBold)?,
+            oblique: doc.add_builtin_font(BuiltinFont::HelveticaOblique)?,
+        })
+    }
+}
+
+const PDF_RULE: &str =
+    "________________________________________________________________________________";
+
+// Texto recortado a `max` caracteres (sen partir letras con til)
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() > max {
+        format!("{}...", text.chars().take(max - 3).collect::<String>())
+    } else {
+        text.to_string()
+    }
+}
+
+// Cabeceira común: concello, servizo, título e data de xeración
+fn draw_letterhead(
+    layer: &printpdf::PdfLayerReference,
+    fonts: &PdfFonts,
+    title: &str,
+    subtitle: &str,
+    x: printpdf::Mm,
+    y: &mut printpdf::Mm,
+) {
+    use printpdf::Mm;
+
+    layer.use_text("CONCELLO DE BARREIROS", 18.0, x, *y, &fonts.bold);
+    *y -= Mm(7.0);
+    layer.use_text(
+        "Servizo de Axuda ao Fogar (SAF)",
+        11.0,
+        x,
+        *y,
+        &fonts.regular,
+    );
+    *y -= Mm(3.0);
+    layer.use_text(PDF_RULE, 8.0, x, *y, &fonts.regular);
+    *y -= Mm(10.0);
+
+    layer.use_text(title, 14.0, x, *y, &fonts.bold);
+    *y -= Mm(6.0);
+    layer.use_text(subtitle, 9.0, x, *y, &fonts.oblique);
+    *y -= Mm(10.0);
+}
+
+// Título dunha sección seguido dunha liña
+fn draw_section_title(
+    layer: &printpdf::PdfLayerReference,
+    fonts: &PdfFonts,
+    title: &str,
+    x: printpdf::Mm,
+    y: &mut printpdf::Mm,
+) {
+    use printpdf::Mm;
+
+    layer.use_text(title, 10.0, x, *y, &fonts.bold);
+    *y -= Mm(2.0);
+    layer.use_text(PDF_RULE, 8.0, x, *y, &fonts.regular);
+    *y -= Mm(6.0);
+}
+
+// Pares "Etiqueta: valor" en dúas columnas
+fn draw_fields(
+    layer: &printpdf::PdfLayerReference,
+    fonts: &PdfFonts,
+    fields: &[(&str, String)],
+    x: printpdf::Mm,
+    y: &mut printpdf::Mm,
+) {
+    use printpdf::Mm;
+
+    for (label, value) in fields {
+        layer.use_text(*label, 9.0, x, *y, &fonts.bold);
+        layer.use_text(truncate(value, 80), 9.0, x + Mm(40.0), *y, &fonts.regular);
+        *y -= Mm(5.5);
+    }
+    *y -= Mm(4.0);
+}
+
+fn beneficiary_fields(user: &User) -> Vec<(&'static str, String)> {
+    vec![
+        ("Nome:", user.name.clone()),
+        (
+            "DNI/NIE:",
+            user.dni
+                .clone()
+                .unwrap_or_else(|| "sen documento".to_string()),
+        ),
+        ("Enderezo:", user.address.clone()),
+        ("Teléfono:", user.phone.clone().unwrap_or_default()),
+    ]
+}
+
+// Recadros para asinar, un por cada etiqueta, repartidos no ancho da páxina
+fn draw_signature_boxes(
+    layer: &printpdf::PdfLayerReference,
+    fonts: &PdfFonts,
+    labels: &[&str],
+    x: printpdf::Mm,
+    y: &mut printpdf::Mm,
+) {
+    use printpdf::{Line, Mm, Point};
+
+    let gap = Mm(10.0);
+    let width = Mm((170.0 - 10.0 * (labels.len() as f64 - 1.0)) / labels.len() as f64);
+    let height = Mm(35.0);
+
+    layer.set_outline_thickness(0.5);
+    for (i, label) in labels.iter().enumerate() {
+        let left = x + Mm((width.0 + gap.0) * i as f64);
+        let top = *y;
+        let bottom = top - height;
+        layer.add_shape(Line {
+            points: vec![
+                (Point::new(left, top), false),
+                (Point::new(left + width, top), false),
+                (Point::new(left + width, bottom), false),
+                (Point::new(left, bottom), false),
+            ],
+            is_closed: true,
+            has_stroke: true,
+            ..Default::default()
+        });
+        layer.use_text(*label, 8.0, left + Mm(3.0), top - Mm(5.0), &fonts.bold);
+        layer.use_text(
+            "Nome e DNI:",
+            7.0,
+            left + Mm(3.0),
+            bottom + Mm(9.0),
+            &fonts.oblique,
+        );
+        layer.use_text(
+            "Data:",
+            7.0,
+            left + Mm(3.0),
+            bottom + Mm(4.0),
+            &fonts.oblique,
+        );
+    }
+    *y -= height + Mm(8.0);
+}
+
+fn draw_footer(layer: &printpdf::PdfLayerReference, fonts: &PdfFonts, x: printpdf::Mm) {
+    use printpdf::Mm;
+
+    layer.use_text(PDF_RULE, 8.0, x, Mm(15.0), &fonts.regular);
+    layer.use_text(
+        "Concello de Barreiros - Servizo de Axuda ao Fogar",
+        7.0,
+        x,
+        Mm(10.0),
+        &fonts.oblique,
+    );
+}
+
+// Condicións que acepta a persoa beneficiaria ao recibir o material
+const DELIVERY_CONDITIONS: &[&str] = &[
+    "1. O material préstase de balde e segue sendo propiedade do Concello de Barreiros.",
+    "2. Só se pode usar no domicilio indicado e pola persoa beneficiaria.",
+    "3. A persoa beneficiaria coidará do material e avisará ao SAF de calquera avaría.",
+    "4. Non se pode ceder, vender nin modificar o material.",
+    "5. O material devolverase limpo e completo na data prevista ou cando deixe de",
+    "    ser necesario. Pódese solicitar unha prórroga antes desa data.",
+    "6. A perda ou os danos por mal uso poden supoñer a reposición do material.",
+];
+
+// Albarán de entrega dun préstamo para asinar no domicilio. Queda
+// rexistrado como evento DELIVERY_RECEIPT_ISSUED.
+pub fn export_delivery_receipt_pdf<P: AsRef<Path>>(
+    db: &Database,
+    path: P,
+    loan_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use printpdf::*;
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    let loan = db.get_loan_by_id(loan_id)?;
+    let user = db.get_user_by_id(&loan.user_id)?;
+
+    let (doc, page1, layer1) =
+        PdfDocument::new("Albarán de entrega", Mm(210.0), Mm(297.0), "Layer 1");
+    let fonts = PdfFonts::new(&doc)?;
+    let layer = doc.get_page(page1).get_layer(layer1);
+
+    let x = Mm(20.0);
+    let mut y = Mm(277.0);
+
+    draw_letterhead(
+        &layer,
+        &fonts,
+        "ALBARÁN DE ENTREGA DE MATERIAL",
+        &format!(
+            "Préstamo {}   |   Xerado o {}",
+            &loan.id[..8.min(loan.id.len())],
+            chrono::Local::now().format("%d/%m/%Y ás %H:%M")
+        ),
+        x,
+        &mut y,
+    );
+
+    draw_section_title(&layer, &fonts, "PERSOA BENEFICIARIA", x, &mut y);
+    draw_fields(&layer, &fonts, &beneficiary_fields(&user), x, &mut y);
+
+    draw_section_title(&layer, &fonts, "DATAS", x, &mut y);
+    draw_fields(
+        &layer,
+        &fonts,
+        &[
+            ("Entrega:", loan.start_date.format("%d/%m/%Y").to_string()),
+            (
+                "Devolución prevista:",
+                loan.expected_end_date.format("%d/%m/%Y").to_string(),
+            ),
+        ],
+        x,
+        &mut y,
+    );
+
+    draw_section_title(&layer, &fonts, "MATERIAL ENTREGADO", x, &mut y);
+    layer.use_text("Artigo", 8.0, x, y, &fonts.bold);
+    layer.use_text("Cantidade", 8.0, x + Mm(80.0), y, &fonts.bold);
+    layer.use_text("Unidades", 8.0, x + Mm(105.0), y, &fonts.bold);
+    y -= Mm(5.0);
+    // Deixase sitio para condicións e sinaturas; o resto resúmese nunha liña
+    let max_rows = 10;
+    for item in loan.items.iter().take(max_rows) {
+        let tags = item
+            .units
+            .iter()
+            .map(|u| u.tag.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        layer.use_text(truncate(&item.item_name, 45), 9.0, x, y, &fonts.regular);
+        layer.use_text(
+            item.quantity.to_string(),
+            9.0,
+            x + Mm(80.0),
+            y,
+            &fonts.regular,
+        );
+        layer.use_text(truncate(&tags, 40), 9.0, x + Mm(105.0), y, &fonts.regular);
+        y -= Mm(5.0);
+    }
+    if loan.items.len() > max_rows {
+        layer.use_text(
+            format!("... e {} artigos máis", loan.items.len() - max_rows),
+            8.0,
+            x,
+            y,
+            &fonts.oblique,
+        );
+        y -= Mm(5.0);
+    }
+    if let Some(notes) = loan.notes.as_deref().filter(|n| !n.trim().is_empty()) {
+        y -= Mm(1.0);
+        layer.use_text(
+            format!("Observacións: {}", truncate(notes, 90)),
+            8.0,
+            x,
+            y,
+            &fonts.oblique,
+        );
+        y -= Mm(5.0);
+    }
+    y -= Mm(5.0);
+
+    draw_section_title(&layer, &fonts, "CONDICIÓNS DE USO", x, &mut y);
+    for line in DELIVERY_CONDITIONS {
+        layer.use_text(*line, 8.0, x, y, &fonts.regular);
+        y -= Mm(4.5);
+    }
+    y -= Mm(6.0);
+
+    draw_signature_boxes(
+        &layer,
+        &fonts,
+        &[
+            "Entrega (persoal do SAF)",
+            "Recibe (beneficiaria/o ou representante)",
+        ],
+        x,
+        &mut y,
+    );
+    draw_footer(&layer, &fonts, x);
+
+    doc.save(&mut BufWriter::new(File::create(&path)?))?;
+    db.log_loan_document(
+        "DELIVERY_RECEIPT_ISSUED",
+        &loan,
+        &path.as_ref().to_string_lossy(),
+    )?;
+    Ok(())
+}
+
+// Estado dunha liña devolta: o da liña ou, se as unidades volveron en
+// estados distintos, o reconto de cada un ("1 Bo estado, 1 Danado")
+fn line_condition_text(item: &LoanItem) -> String {
+    let mut counts: Vec<(ReturnCondition, usize)> = vec![];
+    for condition in item.units.iter().filter_map(|u| u.return_condition) {
+        match counts.iter_mut().find(|(c, _)| *c == condition) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((condition, 1)),
+        }
+    }
+    match counts.as_slice() {
+        [] => item
+            .return_condition
+            .map(|c| c.label().to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        [(condition, _)] => condition.label().to_string(),
+        _ => counts
+            .iter()
+            .map(|(c, n)| format!("{} {}", n, c.label()))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+// Certificado de devolución para a familia: que se devolveu, cando e en
+// que estado, co pendente se a devolución foi parcial. Queda rexistrado
+// como evento RETURN_RECEIPT_ISSUED.
+pub fn export_return_receipt_pdf<P: AsRef<Path>>(
+    db: &Database,
+    path: P,
+    loan_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use printpdf::*;
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    let loan = db.get_loan_by_id(loan_id)?;
+    if loan.items.iter().all(|i| i.returned_quantity == 0) {
+        return Err(Box::new(crate::error::SafError::Validation(
+            "Este préstamo aínda non ten material devolto".to_string(),
+        )));
+    }
+    let user = db.get_user_by_id(&loan.user_id)?;
+    let pending: Vec<&LoanItem> = loan
+        .items
+        .iter()
+        .filter(|i| i.returned_quantity < i.quantity)
+        .collect();
+
+    // As notas da devolución gárdanse en cada liña; se son as mesmas (unha
+    // devolución completa) móstranse unha vez
+    let mut notes: Vec<&str> = loan
+        .items
+        .iter()
+        .filter_map(|i| i.return_notes.as_deref())
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .collect();
+    notes.dedup();
+    let notes_per_line = notes.len() > 1;
+
+    let (doc, page1, layer1) =
+        PdfDocument::new("Certificado de devolución", Mm(210.0), Mm(297.0), "Layer 1");
+    let fonts = PdfFonts::new(&doc)?;
+    let layer = doc.get_page(page1).get_layer(layer1);
+
+    let x = Mm(20.0);
+    let mut y = Mm(277.0);
+
+    draw_letterhead(
+        &layer,
+        &fonts,
+        if pending.is_empty() {
+            "CERTIFICADO DE DEVOLUCIÓN DE MATERIAL"
+        } else {
+            "CERTIFICADO DE DEVOLUCIÓN PARCIAL DE MATERIAL"
+        },
+        &format!(
+            "Préstamo {}   |   Xerado o {}",
+            &loan.id[..8.min(loan.id.len())],
+            chrono::Local::now().format("%d/%m/%Y ás %H:%M")
+        ),
+        x,
+        &mut y,
+    );
+
+    draw_section_title(&layer, &fonts, "PERSOA BENEFICIARIA", x, &mut y);
+    draw_fields(&layer, &fonts, &beneficiary_fields(&user), x, &mut y);
+
+    draw_section_title(&layer, &fonts, "DATAS", x, &mut y);
+    let mut dates = vec![("Entrega:", loan.start_date.format("%d/%m/%Y").to_string())];
+    match loan.actual_end_date {
+        Some(end) => dates.push(("Devolución:", end.format("%d/%m/%Y").to_string())),
+        None => dates.push((
+            "Devolución prevista:",
+            loan.expected_end_date.format("%d/%m/%Y").to_string(),
+        )),
+    }
+    draw_fields(&layer, &fonts, &dates, x, &mut y);
+
+    draw_section_title(&layer, &fonts, "MATERIAL DEVOLTO", x, &mut y);
+    let columns = [
+        ("Artigo", Mm(0.0)),
+        ("Devolto", Mm(62.0)),
+        ("Data", Mm(82.0)),
+        ("Estado", Mm(110.0)),
+    ];
+    for (header, offset) in columns {
+        layer.use_text(header, 8.0, x + offset, y, &fonts.bold);
+    }
+    y -= Mm(5.0);
+
+    // Por debaixo disto só caben o pendente, as notas e as sinaturas
+    let min_y = Mm(105.0);
+    let returned: Vec<&LoanItem> = loan
+        .items
+        .iter()
+        .filter(|i| i.returned_quantity > 0)
+        .collect();
+    for (n, item) in returned.iter().enumerate() {
+        if y < min_y {
+            layer.use_text(
+                format!("... e {} artigos máis", returned.len() - n),
+                8.0,
+                x,
+                y,
+                &fonts.oblique,
+            );
+            y -= Mm(5.0);
+            break;
+        }
+        let date = item
+            .returned_at
+            .map(|d| d.format("%d/%m/%Y").to_string())
+            .unwrap_or_default();
+        let row = [
+            truncate(&item.item_name, 38),
+            format!("{} de {}", item.returned_quantity, item.quantity),
+            date,
+            truncate(&line_condition_text(item), 40),
+        ];
+        for (text, (_, offset)) in row.iter().zip(columns) {
+            layer.use_text(text.as_str(), 9.0, x + offset, y, &fonts.regular);
+        }
+        y -= Mm(4.5);
+
+        // Unidades con etiqueta: cada unha co seu estado
+        let units = item
+            .units
+            .iter()
+            .filter(|u| u.returned_at.is_some())
+            .map(|u| match u.return_condition {
+                Some(c) => format!("{} ({})", u.tag, c.label()),
+                None => u.tag.clone(),
+            })
+            .collect::<Vec<_>>();
+        if !units.is_empty() {
+            layer.use_text(
+                truncate(&format!("Unidades: {}", units.join(", ")), 110),
+                7.5,
+                x + Mm(4.0),
+                y,
+                &fonts.oblique,
+            );
+            y -= Mm(4.0);
+        }
+        if notes_per_line {
+            if let Some(line_notes) = item
+                .return_notes
+                .as_deref()
+                .filter(|n| !n.trim().is_empty())
+            {
+                layer.use_text(
+                    truncate(&format!("Observacións: {}", line_notes.trim()), 110),
+                    7.5,
+                    x + Mm(4.0),
+                    y,
+                    &fonts.oblique,
+                );
+                y -= Mm(4.0);
+            }
+        }
+        y -= Mm(1.0);
+    }
+    y -= Mm(4.0);
+
+    if !pending.is_empty() {
+        draw_section_title(&layer, &fonts, "PENDENTE DE DEVOLVER", x, &mut y);
+        let text = pending
+            .iter()
+            .map(|i| format!("{} x{}", i.item_name, i.quantity - i.returned_quantity))
+            .collect::<Vec<_>>()
+            .join(", ");
+        layer.use_text(truncate(&text, 100), 9.0, x, y, &fonts.regular);
+        y -= Mm(5.0);
+        layer.use_text(
+            format!(
+                "Data prevista de devolución: {}",
+                loan.expected_end_date.format("%d/%m/%Y")
+            ),
+            8.0,
+            x,
+            y,
+            &fonts.oblique,
+        );
+        y -= Mm(9.0);
+    }
+
+    if !notes_per_line {
+        if let Some(note) = notes.first() {
+            draw_section_title(&layer, &fonts, "OBSERVACIÓNS", x, &mut y);
+            layer.use_text(truncate(note, 100), 9.0, x, y, &fonts.regular);
+            y -= Mm(9.0);
+        }
+    }
+
+    layer.use_text(
+        "O Servizo de Axuda ao Fogar certifica que recibiu o material indicado no estado descrito.",
+        8.0,
+        x,
+        y,
+        &fonts.regular,
+    );
+    y -= Mm(8.0);
+
+    draw_signature_boxes(
+        &layer,
+        &fonts,
+        &[
+            "Recibe (persoal do SAF)",
+            "Entrega (beneficiaria/o ou representante)",
+        ],
+        x,
+        &mut y,
+    );
+    draw_footer(&layer, &fonts, x);
+
+    doc.save(&mut BufWriter::new(File::create(&path)?))?;
+    db.log_loan_document(
+        "RETURN_RECEIPT_ISSUED",
+        &loan,
+        &path.as_ref().to_string_lossy(),
+    )?;
+    Ok(())
+}
+
+// Parte unha liña de texto en anacos de ata `width` caracteres sen cortar
+// palabras. Nas liñas sangradas (listas) as seguintes van un pouco máis dentro.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let indent: String = text.chars().take_while(|c| c.is_whitespace()).collect();
+    let hanging = if indent.is_empty() { "" } else { "  " };
+    let width = width.saturating_sub(indent.chars().count() + hanging.len());
+
+    let mut lines: Vec<String> = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    lines.push(line);
+
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| match i {
+            0 => format!("{}{}", indent, line),
+            _ => format!("{}{}{}", indent, hanging, line),
+        })
+        .collect()
+}
+
+// Unha carta de recordatorio a partir de `layer`, engadindo páxinas se o
+// texto non cabe nunha
+fn draw_reminder_letter(
+    doc: &printpdf::PdfDocumentReference,
+    layer: printpdf::PdfLayerReference,
+    fonts: &PdfFonts,
+    letter: &crate::reminders::ReminderLetter,
+    template: &str,
+    today: chrono::NaiveDate,
+) {
+    use printpdf::Mm;
+
+    let x = Mm(20.0);
+    let mut y = Mm(277.0);
+    let mut layer = layer;
+
+    draw_letterhead(
+        &layer,
+        fonts,
+        "RECORDATORIO DE DEVOLUCIÓN DE MATERIAL",
+        &format!("Barreiros, {}", crate::reminders::long_date(today)),
+        x,
+        &mut y,
+    );
+
+    // Destinatario/a, á dereita para os sobres con ventá
+    let recipient_x = x + Mm(95.0);
+    layer.use_text(letter.user_name, 10.0, recipient_x, y, &fonts.bold);
+    y -= Mm(5.0);
+    if let Some(user) = letter.user {
+        if !user.address.is_empty() {
+            layer.use_text(
+                truncate(&user.address, 45),
+                10.0,
+                recipient_x,
+                y,
+                &fonts.regular,
+            );
+            y -= Mm(5.0);
+        }
+    }
+    y -= Mm(12.0);
+
+    let body = letter.render(template, today);
+    let lines = body
+        .lines()
+        .flat_map(|line| {
+            if line.trim().is_empty() {
+                vec![String::new()]
+            } else {
+                wrap(line, 95)
+            }
+        })
+        .chain([String::new(), "O Servizo de Axuda ao Fogar".to_string()]);
+    for line in lines {
+        if y < Mm(30.0) {
+            draw_footer(&layer, fonts, x);
+            let (page, page_layer) = doc.add_page(Mm(210.0), Mm(297.0), "Layer 1");
+            layer = doc.get_page(page).get_layer(page_layer);
+            y = Mm(277.0);
+        }
+        if line.is_empty() {
+            y -= Mm(3.0);
+        } else {
+            layer.use_text(line, 10.0, x, y, &fonts.regular);
+            y -= Mm(5.0);
+        }
+    }
+    draw_footer(&layer, fonts, x);
+}
+
+// Nome de ficheiro seguro a partir do nome da persoa: "Ana_Perez"
+fn file_slug(name: &str) -> String {
+    name.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric() || *c == '-')
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+// Cartas de recordatorio de todos os préstamos atrasados, unha por persoa.
+// Con `one_file_per_user` `path` é un cartafol e cada carta vai no seu
+// PDF; se non, todas van nun só PDF. Cada carta queda rexistrada como
+// evento REMINDER_SENT. Devolve os ficheiros xerados.
+pub fn export_reminder_letters<P: AsRef<Path>>(
+    db: &Database,
+    path: P,
+    template: &str,
+    one_file_per_user: bool,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    use printpdf::*;
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    crate::reminders::validate_template(template)?;
+    let today = chrono::Local::now().date_naive();
+    let query = LoanQuery {
+        overdue_by_days: Some(0),
+        sort: LoanSortKey::UserName,
+        ascending: true,
+        ..Default::default()
+    };
+    let loans = db.query_loans(&query)?.loans;
+    if loans.is_empty() {
+        return Err(Box::new(crate::error::SafError::Validation(
+            "Non hai préstamos atrasados".to_string(),
+        )));
+    }
+    let users = db.get_users_by_ids(&user_ids(&loans))?;
+    let letters = crate::reminders::group_by_user(&loans, &users);
+
+    let mut files = vec![];
+    if one_file_per_user {
+        std::fs::create_dir_all(&path)?;
+        for letter in &letters {
+            let slug = file_slug(letter.user_name);
+            let mut file = path
+                .as_ref()
+                .join(format!("recordatorio_{}_{}.pdf", slug, today));
+            // Dúas persoas co mesmo nome non se sobrescriben
+            let mut n = 1;
+            while files.contains(&file.to_string_lossy().to_string()) {
+                n += 1;
+                file = path
+                    .as_ref()
+                    .join(format!("recordatorio_{}_{}_{}.pdf", slug, today, n));
+            }
+
+            let (doc, page1, layer1) =
+                PdfDocument::new("Recordatorio", Mm(210.0), Mm(297.0), "Layer 1");
+            let fonts = PdfFonts::new(&doc)?;
+            let layer = doc.get_page(page1).get_layer(layer1);
+            draw_reminder_letter(&doc, layer, &fonts, letter, template, today);
+            doc.save(&mut BufWriter::new(File::create(&file)?))?;
+
+            let file = file.to_string_lossy().to_string();
+            db.log_reminder_sent(&letter.loans, &file)?;
+            files.push(file);
+        }
+    } else {
+        let (doc, page1, layer1) =
+            PdfDocument::new("Recordatorios", Mm(210.0), Mm(297.0), "Layer 1");
+        let fonts = PdfFonts::new(&doc)?;
+        for (i, letter) in letters.iter().enumerate() {
+            let layer = if i == 0 {
+                doc.get_page(page1).get_layer(layer1)
+            } else {
+                let (page, layer) = doc.add_page(Mm(210.0), Mm(297.0), "Layer 1");
+                doc.get_page(page).get_layer(layer)
+            };
+            draw_reminder_letter(&doc, layer, &fonts, letter, template, today);
+        }
+        doc.save(&mut BufWriter::new(File::create(&path)?))?;
+
+        let file = path.as_ref().to_string_lossy().to_string();
+        for letter in &letters {
+            db.log_reminder_sent(&letter.loans, &file)?;
+        }
+        files.push(file);
+    }
+
+    Ok(files)
+}
@@ -4,12 +4,18 @@ use std::path::PathBuf;
 use std::sync::Mutex;
 use tauri::Manager;
 
+mod attachments;
 mod backup;
 mod commands;
 mod database;
+mod dni;
+mod error;
 mod excel;
 mod lock;
+mod migrations;
 mod models;
+mod reminders;
+mod scheduler;
 
 use database::Database;
 use lock::FileLock;
@@ -40,6 +46,14 @@ fn main() {
                 lock: Mutex::new(Some(file_lock)),
             });
 
+            // Tarefas en segundo plano
+            let overdue_interval =
+                std::time::Duration::from_secs(get_overdue_check_minutes(&app_handle) * 60);
+            scheduler::Scheduler::new()
+                .add(scheduler::overdue_job(overdue_interval))
+                .add(scheduler::stock_check_job())
+                .start(app_handle.clone());
+
             // Get main window and setup cleanup on close
             let main_window = app.get_window("main").unwrap();
             let app_handle_clone = app_handle.clone();
@@ -65,31 +79,71 @@ fn main() {
             commands::update_user,
             commands::delete_user,
             commands::search_users,
+            commands::get_invalid_dnis,
             // Item commands
             commands::get_items,
             commands::get_default_items,
             commands::add_default_items,
             commands::create_item,
+            commands::update_item,
             commands::update_item_stock,
             commands::search_items,
             commands::delete_item,
+            // Category commands
+            commands::get_categories,
+            commands::create_category,
+            commands::update_category,
+            commands::merge_categories,
+            commands::retire_category,
+            // Attachment commands
+            commands::get_attachments,
+            commands::add_attachment,
+            commands::get_attachment_thumbnail,
+            commands::export_attachment,
+            commands::delete_attachment,
+            // Unit commands
+            commands::get_item_units,
+            commands::add_unit,
+            commands::update_unit,
+            commands::retire_unit,
+            commands::get_unit_loans,
+            // Maintenance commands
+            commands::get_maintenance_orders,
+            commands::open_maintenance_order,
+            commands::update_maintenance_order,
+            commands::close_maintenance_order,
             // Loan commands
             commands::get_loans,
+            commands::query_loans,
             commands::get_loan_by_id,
             commands::create_loan,
             commands::return_loan,
+            commands::return_loan_item,
             commands::cancel_return,
+            commands::deliver_loan,
+            commands::extend_loan,
+            commands::get_loan_extensions,
             commands::get_active_loans,
             commands::get_overdue_loans,
             // Dashboard
             commands::get_dashboard_stats,
+            commands::check_stock,
             // Events
             commands::get_events,
             commands::get_events_by_loan,
+            commands::query_events,
+            commands::cancel_event,
+            // Search
+            commands::global_search,
             // Excel export
             commands::export_to_excel,
             commands::export_annual_report,
             commands::export_annual_report_pdf,
+            commands::export_delivery_receipt_pdf,
+            commands::export_return_receipt_pdf,
+            commands::export_reminder_letters,
+            commands::get_damage_report,
+            commands::export_damage_report,
             // Backup
             commands::create_backup,
             commands::restore_backup,
@@ -101,6 +155,12 @@ fn main() {
             commands::get_db_location,
             commands::set_db_location,
             commands::has_db_location_configured,
+            commands::get_schema_version,
+            commands::get_overdue_check_interval,
+            commands::set_overdue_check_interval,
+            commands::get_reminder_template,
+            commands::get_reminder_placeholders,
+            commands::set_reminder_template,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -135,3 +195,21 @@ fn get_db_path(app_handle: &tauri::AppHandle) -> PathBuf {
 
     app_data_dir.join("saf_database.db")
 }
+
+pub const DEFAULT_OVERDUE_CHECK_MINUTES: u64 = 60;
+
+// Minutos entre comprobacións de préstamos atrasados (settings.json)
+pub fn get_overdue_check_minutes(app_handle: &tauri::AppHandle) -> u64 {
+    let settings_path = app_handle
+        .path_resolver()
+        .app_data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("settings.json");
+
+    std::fs::read_to_string(settings_path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+        .and_then(|settings| settings.get("overdue_check_minutes")?.as_u64())
+        .filter(|minutes| *minutes > 0)
+        .unwrap_or(DEFAULT_OVERDUE_CHECK_MINUTES)
+}
@@ -0,0 +1,762 @@
+use chrono::{Local, NaiveDate};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use uuid::Uuid;
+
+use crate::database::{next_unit_tag, DEFAULT_ITEMS};
+use crate::dni::normalize_dni;
+use crate::error::SafError;
+
+// Cada migración ten un número de versión consecutivo. A versión aplicada
+// gárdase en `PRAGMA user_version`, e cada migración execútase nunha
+// transacción propia xunto coa actualización da versión.
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    pub up: fn(&Connection) -> Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Esquema inicial",
+        up: initial_schema,
+    },
+    Migration {
+        version: 2,
+        description: "Columna users.active",
+        up: add_users_active,
+    },
+    Migration {
+        version: 3,
+        description: "Unidades físicas dos artigos",
+        up: add_units,
+    },
+    Migration {
+        version: 4,
+        description: "Devolucións parciais por liña",
+        up: add_partial_returns,
+    },
+    Migration {
+        version: 5,
+        description: "Prórrogas de préstamos",
+        up: add_loan_extensions,
+    },
+    Migration {
+        version: 6,
+        description: "Estado de devolución por unidade",
+        up: add_unit_return_condition,
+    },
+    Migration {
+        version: 7,
+        description: "Ordes de mantemento",
+        up: add_maintenance_orders,
+    },
+    Migration {
+        version: 8,
+        description: "Índices para consultar eventos",
+        up: add_event_indexes,
+    },
+    Migration {
+        version: 9,
+        description: "DNI/NIE opcional e normalizado",
+        up: normalize_user_dnis,
+    },
+    Migration {
+        version: 10,
+        description: "Índice de busca de texto completo",
+        up: add_search_index,
+    },
+    Migration {
+        version: 11,
+        description: "Índices para ordenar préstamos",
+        up: add_loan_indexes,
+    },
+    Migration {
+        version: 12,
+        description: "Táboa de categorías",
+        up: add_categories,
+    },
+    Migration {
+        version: 13,
+        description: "Anexos dos artigos",
+        up: add_attachments,
+    },
+];
+
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn current_version(conn: &Connection) -> Result<i32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+pub fn run(conn: &Connection) -> crate::error::Result<()> {
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    // Non abrimos bases de datos creadas por unha versión máis nova da aplicación
+    if current > latest {
+        return Err(SafError::Conflict(format!(
+            "A base de datos ten a versión de esquema {} pero esta aplicación só soporta ata a {}. Actualice a aplicación.",
+            current, latest
+        )));
+    }
+
+    // As migracións que recrean táboas (DROP + RENAME) non poden ter as
+    // claves foráneas activas, e o pragma non ten efecto dentro dunha
+    // transacción: desactívanse mentres duran e compróbanse antes de
+    // confirmar cada migración.
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let result = run_pending(conn, current);
+    conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+    result
+}
+
+fn run_pending(conn: &Connection, current: i32) -> crate::error::Result<()> {
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.unchecked_transaction()?;
+        (migration.up)(&tx)?;
+
+        let violation: Option<(String, String)> = tx
+            .query_row("PRAGMA foreign_key_check", [], |row| {
+                Ok((row.get(0)?, row.get(2)?))
+            })
+            .optional()?;
+        if let Some((table, parent)) = violation {
+            return Err(SafError::Conflict(format!(
+                "A migración {} ({}) deixa filas de {} sen a súa referencia en {}",
+                migration.version, migration.description, table, parent
+            )));
+        }
+
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+// As bases de datos anteriores ao sistema de migracións teñen user_version = 0
+// e xa conteñen estas táboas, por iso se mantén o IF NOT EXISTS.
+fn initial_schema(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS users (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            dni TEXT UNIQUE NOT NULL,
+            address TEXT NOT NULL,
+            phone TEXT,
+            email TEXT,
+            notes TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+
+        CREATE TABLE IF NOT EXISTS items (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            description TEXT,
+            category TEXT NOT NULL,
+            icon TEXT NOT NULL,
+            total_stock INTEGER NOT NULL DEFAULT 0,
+            available_stock INTEGER NOT NULL DEFAULT 0,
+            notes TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+
+        CREATE TABLE IF NOT EXISTS loans (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            start_date DATE NOT NULL,
+            expected_end_date DATE NOT NULL,
+            actual_end_date DATE,
+            status TEXT NOT NULL DEFAULT 'active',
+            notes TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        );
+
+        -- Loan items table (many-to-many)
+        CREATE TABLE IF NOT EXISTS loan_items (
+            id TEXT PRIMARY KEY,
+            loan_id TEXT NOT NULL,
+            item_id TEXT NOT NULL,
+            quantity INTEGER NOT NULL DEFAULT 1,
+            FOREIGN KEY (loan_id) REFERENCES loans(id) ON DELETE CASCADE,
+            FOREIGN KEY (item_id) REFERENCES items(id)
+        );
+
+        -- Events table (Event Sourcing)
+        CREATE TABLE IF NOT EXISTS events (
+            id TEXT PRIMARY KEY,
+            event_type TEXT NOT NULL,
+            data TEXT NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            loan_id TEXT,
+            user_id TEXT,
+            cancelled_by TEXT,
+            cancellation_reason TEXT
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_loans_user_id ON loans(user_id);
+        CREATE INDEX IF NOT EXISTS idx_loans_status ON loans(status);
+        CREATE INDEX IF NOT EXISTS idx_loan_items_loan_id ON loan_items(loan_id);
+        CREATE INDEX IF NOT EXISTS idx_events_created_at ON events(created_at);",
+    )
+}
+
+fn add_users_active(conn: &Connection) -> Result<()> {
+    // As bases de datos creadas antes das migracións poden ter xa a columna
+    let has_active = conn.prepare("SELECT active FROM users LIMIT 0").is_ok();
+    if !has_active {
+        conn.execute(
+            "ALTER TABLE users ADD COLUMN active INTEGER NOT NULL DEFAULT 1",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn add_units(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE units (
+            id TEXT PRIMARY KEY,
+            item_id TEXT NOT NULL,
+            tag TEXT UNIQUE NOT NULL,
+            status TEXT NOT NULL DEFAULT 'available',
+            acquisition_date DATE,
+            notes TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (item_id) REFERENCES items(id)
+        );
+
+        -- Unidades concretas entregadas en cada liña de préstamo
+        CREATE TABLE loan_item_units (
+            loan_item_id TEXT NOT NULL,
+            unit_id TEXT NOT NULL,
+            PRIMARY KEY (loan_item_id, unit_id),
+            FOREIGN KEY (loan_item_id) REFERENCES loan_items(id) ON DELETE CASCADE,
+            FOREIGN KEY (unit_id) REFERENCES units(id)
+        );
+
+        CREATE INDEX idx_units_item_id ON units(item_id);
+        CREATE INDEX idx_loan_item_units_unit_id ON loan_item_units(unit_id);",
+    )?;
+
+    let now = Local::now().naive_local();
+
+    // Crear unha unidade por cada existencia dos artigos actuais
+    let items = {
+        let mut stmt = conn.prepare("SELECT id, name, total_stock, available_stock FROM items")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i32>(2)?,
+                    row.get::<_, i32>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        rows
+    };
+
+    for (item_id, name, total, available) in items {
+        let loaned = total - available;
+        for i in 0..total {
+            let status = if i < loaned { "loaned" } else { "available" };
+            conn.execute(
+                "INSERT INTO units (id, item_id, tag, status, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+                params![
+                    Uuid::new_v4().to_string(),
+                    item_id,
+                    next_unit_tag(conn, &name)?,
+                    status,
+                    now
+                ],
+            )?;
+        }
+    }
+
+    // Asociar as unidades prestadas ás liñas dos préstamos abertos
+    let lines = {
+        let mut stmt = conn.prepare(
+            "SELECT li.id, li.item_id, li.quantity
+             FROM loan_items li
+             JOIN loans l ON li.loan_id = l.id
+             WHERE l.status != 'returned'
+             ORDER BY l.created_at",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i32>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        rows
+    };
+
+    for (loan_item_id, item_id, quantity) in lines {
+        conn.execute(
+            "INSERT INTO loan_item_units (loan_item_id, unit_id)
+             SELECT ?1, u.id FROM units u
+             WHERE u.item_id = ?2 AND u.status = 'loaned'
+               AND NOT EXISTS (SELECT 1 FROM loan_item_units x WHERE x.unit_id = u.id)
+             ORDER BY u.tag
+             LIMIT ?3",
+            params![loan_item_id, item_id, quantity],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn add_partial_returns(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE loan_items ADD COLUMN returned_quantity INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE loan_items ADD COLUMN returned_at DATE;
+        ALTER TABLE loan_items ADD COLUMN return_condition TEXT;
+        ALTER TABLE loan_items ADD COLUMN return_notes TEXT;
+        ALTER TABLE loan_item_units ADD COLUMN returned_at DATE;
+
+        -- Os préstamos xa devoltos teñen todas as súas liñas devoltas
+        UPDATE loan_items
+        SET returned_quantity = quantity,
+            returned_at = (SELECT actual_end_date FROM loans WHERE loans.id = loan_items.loan_id)
+        WHERE loan_id IN (SELECT id FROM loans WHERE status = 'returned');
+
+        UPDATE loan_item_units
+        SET returned_at = (SELECT returned_at FROM loan_items WHERE loan_items.id = loan_item_units.loan_item_id)
+        WHERE loan_item_id IN (SELECT id FROM loan_items WHERE returned_quantity > 0);",
+    )
+}
+
+fn add_loan_extensions(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE loan_extensions (
+            id TEXT PRIMARY KEY,
+            loan_id TEXT NOT NULL,
+            previous_end_date DATE NOT NULL,
+            new_end_date DATE NOT NULL,
+            reason TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (loan_id) REFERENCES loans(id) ON DELETE CASCADE
+        );
+
+        CREATE INDEX idx_loan_extensions_loan_id ON loan_extensions(loan_id);",
+    )
+}
+
+fn add_unit_return_condition(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE loan_item_units ADD COLUMN return_condition TEXT;
+
+        CREATE INDEX idx_loan_item_units_returned_at ON loan_item_units(returned_at);",
+    )
+}
+
+fn add_maintenance_orders(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE maintenance_orders (
+            id TEXT PRIMARY KEY,
+            item_id TEXT NOT NULL,
+            unit_id TEXT NOT NULL,
+            loan_id TEXT,
+            opened_at DATE NOT NULL,
+            description TEXT NOT NULL,
+            cost REAL,
+            closed_at DATE,
+            outcome TEXT,
+            notes TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (item_id) REFERENCES items(id),
+            FOREIGN KEY (unit_id) REFERENCES units(id)
+        );
+
+        CREATE INDEX idx_maintenance_orders_item_id ON maintenance_orders(item_id);
+        CREATE INDEX idx_maintenance_orders_unit_id ON maintenance_orders(unit_id);",
+    )?;
+
+    // As unidades devoltas danadas antes desta versión quedan cunha orde aberta
+    let damaged = {
+        let mut stmt = conn.prepare(
+            "SELECT u.id, u.item_id, li.loan_id, liu.returned_at, li.return_notes
+             FROM units u
+             JOIN loan_item_units liu ON liu.unit_id = u.id AND liu.return_condition = 'damaged'
+             JOIN loan_items li ON liu.loan_item_id = li.id
+             WHERE u.status = 'in_repair'
+             ORDER BY liu.returned_at DESC",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<NaiveDate>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        rows
+    };
+
+    let now = Local::now().naive_local();
+    let mut seen = std::collections::HashSet::new();
+    for (unit_id, item_id, loan_id, returned_at, notes) in damaged {
+        if !seen.insert(unit_id.clone()) {
+            continue;
+        }
+        conn.execute(
+            "INSERT INTO maintenance_orders (id, item_id, unit_id, loan_id, opened_at, description, notes, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, 'Devolución con danos', ?6, ?7, ?7)",
+            params![
+                Uuid::new_v4().to_string(),
+                item_id,
+                unit_id,
+                loan_id,
+                returned_at.unwrap_or(now.date()),
+                notes,
+                now
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn add_event_indexes(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE events ADD COLUMN item_id TEXT;
+
+        UPDATE events SET item_id = json_extract(data, '$.itemId')
+        WHERE json_valid(data) AND json_type(data, '$.itemId') = 'text';
+
+        CREATE INDEX idx_events_loan_id ON events(loan_id);
+        CREATE INDEX idx_events_user_id ON events(user_id);
+        CREATE INDEX idx_events_item_id ON events(item_id);",
+    )
+}
+
+fn normalize_user_dnis(conn: &Connection) -> Result<()> {
+    // SQLite non permite quitar o NOT NULL cun ALTER: recréase a táboa.
+    // `run` desactiva as claves foráneas para que o DROP non toque loans.
+    conn.execute_batch(
+        "CREATE TABLE users_new (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            dni TEXT UNIQUE,
+            address TEXT NOT NULL,
+            phone TEXT,
+            email TEXT,
+            notes TEXT,
+            active INTEGER NOT NULL DEFAULT 1,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+
+        INSERT INTO users_new (id, name, dni, address, phone, email, notes, active, created_at, updated_at)
+        SELECT id, name, dni, address, phone, email, notes, active, created_at, updated_at FROM users;
+
+        DROP TABLE users;
+        ALTER TABLE users_new RENAME TO users;",
+    )?;
+
+    // Pasar á forma canónica os DNI válidos que non colidan con outro.
+    // Os restantes quedan como estaban e aparecen en get_invalid_dnis.
+    let users = {
+        let mut stmt = conn.prepare("SELECT id, dni FROM users")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        rows
+    };
+
+    for (id, dni) in users {
+        let Ok(canonical) = normalize_dni(&dni) else {
+            continue;
+        };
+        if canonical == dni {
+            continue;
+        }
+        let taken: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM users WHERE dni = ?1)",
+            [&canonical],
+            |row| row.get(0),
+        )?;
+        if !taken {
+            conn.execute(
+                "UPDATE users SET dni = ?1 WHERE id = ?2",
+                params![canonical, id],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn add_search_index(conn: &Connection) -> Result<()> {
+    // Táboas FTS5 con copia propia do texto (as táboas orixinais non teñen
+    // rowid estable). `remove_diacritics 2` fai que "Nuñez" atope "Núñez".
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE users_fts USING fts5(
+            user_id UNINDEXED, name, dni, address, phone, notes,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        CREATE VIRTUAL TABLE items_fts USING fts5(
+            item_id UNINDEXED, name, description, category, notes,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        CREATE VIRTUAL TABLE loans_fts USING fts5(
+            loan_id UNINDEXED, notes,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+
+        CREATE TRIGGER users_fts_insert AFTER INSERT ON users BEGIN
+            INSERT INTO users_fts (user_id, name, dni, address, phone, notes)
+            VALUES (new.id, new.name, new.dni, new.address, new.phone, new.notes);
+        END;
+        CREATE TRIGGER users_fts_update AFTER UPDATE OF name, dni, address, phone, notes ON users BEGIN
+            DELETE FROM users_fts WHERE user_id = old.id;
+            INSERT INTO users_fts (user_id, name, dni, address, phone, notes)
+            VALUES (new.id, new.name, new.dni, new.address, new.phone, new.notes);
+        END;
+        CREATE TRIGGER users_fts_delete AFTER DELETE ON users BEGIN
+            DELETE FROM users_fts WHERE user_id = old.id;
+        END;
+
+        CREATE TRIGGER items_fts_insert AFTER INSERT ON items BEGIN
+            INSERT INTO items_fts (item_id, name, description, category, notes)
+            VALUES (new.id, new.name, new.description, new.category, new.notes);
+        END;
+        CREATE TRIGGER items_fts_update AFTER UPDATE OF name, description, category, notes ON items BEGIN
+            DELETE FROM items_fts WHERE item_id = old.id;
+            INSERT INTO items_fts (item_id, name, description, category, notes)
+            VALUES (new.id, new.name, new.description, new.category, new.notes);
+        END;
+        CREATE TRIGGER items_fts_delete AFTER DELETE ON items BEGIN
+            DELETE FROM items_fts WHERE item_id = old.id;
+        END;
+
+        CREATE TRIGGER loans_fts_insert AFTER INSERT ON loans BEGIN
+            INSERT INTO loans_fts (loan_id, notes) VALUES (new.id, new.notes);
+        END;
+        CREATE TRIGGER loans_fts_update AFTER UPDATE OF notes ON loans BEGIN
+            DELETE FROM loans_fts WHERE loan_id = old.id;
+            INSERT INTO loans_fts (loan_id, notes) VALUES (new.id, new.notes);
+        END;
+        CREATE TRIGGER loans_fts_delete AFTER DELETE ON loans BEGIN
+            DELETE FROM loans_fts WHERE loan_id = old.id;
+        END;
+
+        INSERT INTO users_fts (user_id, name, dni, address, phone, notes)
+        SELECT id, name, dni, address, phone, notes FROM users;
+        INSERT INTO items_fts (item_id, name, description, category, notes)
+        SELECT id, name, description, category, notes FROM items;
+        INSERT INTO loans_fts (loan_id, notes) SELECT id, notes FROM loans;",
+    )
+}
+
+fn add_loan_indexes(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE INDEX idx_loans_created_at ON loans(created_at);
+        CREATE INDEX idx_loans_start_date ON loans(start_date);
+        CREATE INDEX idx_loans_expected_end_date ON loans(expected_end_date);
+        CREATE INDEX idx_loan_items_item_id ON loan_items(item_id);",
+    )
+}
+
+fn add_categories(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE categories (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            icon TEXT NOT NULL DEFAULT '📦',
+            sort_order INTEGER NOT NULL DEFAULT 0,
+            default_loan_days INTEGER,
+            active INTEGER NOT NULL DEFAULT 1,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+
+        ALTER TABLE items ADD COLUMN category_id TEXT REFERENCES categories(id);
+        CREATE INDEX idx_items_category_id ON items(category_id);",
+    )?;
+
+    // Primeiro as categorías dos artigos por defecto, na súa orde, e despois
+    // as que só existen nos artigos. Os nomes que só se diferencian en
+    // maiúsculas ou espazos quedan nunha única categoría.
+    let mut names: Vec<(String, String)> = vec![];
+    for (_, _, category, icon) in DEFAULT_ITEMS {
+        if !names
+            .iter()
+            .any(|(n, _)| n.to_lowercase() == category.to_lowercase())
+        {
+            names.push((category.to_string(), icon.to_string()));
+        }
+    }
+    let existing = {
+        let mut stmt = conn.prepare(
+            "SELECT category, MIN(icon) FROM items GROUP BY category ORDER BY MIN(created_at)",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        rows
+    };
+    for (category, icon) in existing {
+        let name = category.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !name.is_empty()
+            && !names
+                .iter()
+                .any(|(n, _)| n.to_lowercase() == name.to_lowercase())
+        {
+            names.push((name, icon));
+        }
+    }
+
+    let now = Local::now().naive_local();
+    let mut ids: Vec<(String, String)> = vec![];
+    for (order, (name, icon)) in names.iter().enumerate() {
+        let id = Uuid::new_v4().to_string();
+        conn.execute(
+            "INSERT INTO categories (id, name, icon, sort_order, active, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, 1, ?5, ?5)",
+            params![id, name, icon, order as i64, now],
+        )?;
+        ids.push((id, name.clone()));
+    }
+
+    // Cada artigo queda ligado á súa categoría e co nome exacto dela; os
+    // que non tiñan categoría van a "Outros"
+    let items = {
+        let mut stmt = conn.prepare("SELECT id, category FROM items")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        rows
+    };
+    for (item_id, category) in items {
+        let normalized = category
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        let Some((category_id, name)) = ids
+            .iter()
+            .find(|(_, name)| name.to_lowercase() == normalized)
+            .or_else(|| ids.iter().find(|(_, name)| name == "Outros"))
+        else {
+            continue;
+        };
+        conn.execute(
+            "UPDATE items SET category_id = ?1, category = ?2 WHERE id = ?3",
+            params![category_id, name, item_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn add_attachments(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE attachments (
+            id TEXT PRIMARY KEY,
+            item_id TEXT NOT NULL REFERENCES items(id) ON DELETE CASCADE,
+            kind TEXT NOT NULL DEFAULT 'other',
+            file_name TEXT NOT NULL,
+            mime_type TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            has_thumbnail INTEGER NOT NULL DEFAULT 0,
+            notes TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+
+        CREATE INDEX idx_attachments_item_id ON attachments(item_id);
+        CREATE INDEX idx_attachments_sha256 ON attachments(sha256);",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Base de datos en memoria co esquema dunha versión anterior
+    fn database_at(version: i32) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON").unwrap();
+        for migration in MIGRATIONS.iter().filter(|m| m.version <= version) {
+            (migration.up)(&conn).unwrap();
+        }
+        conn.pragma_update(None, "user_version", version).unwrap();
+        conn
+    }
+
+    #[test]
+    fn users_rebuild_keeps_loans() {
+        let conn = database_at(8);
+        conn.execute_batch(
+            "INSERT INTO users (id, name, dni, address) VALUES ('u1', 'Ana', '12345678-z', 'Rúa');
+             INSERT INTO items (id, name, category, icon, total_stock, available_stock)
+             VALUES ('i1', 'Andador', 'Mobilidade', 'x', 1, 0);
+             INSERT INTO loans (id, user_id, start_date, expected_end_date, status)
+             VALUES ('l1', 'u1', '2024-01-01', '2024-02-01', 'active');
+             INSERT INTO loan_items (id, loan_id, item_id, quantity) VALUES ('li1', 'l1', 'i1', 1);",
+        )
+        .unwrap();
+
+        run(&conn).unwrap();
+
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        let (user_id, dni): (String, String) = conn
+            .query_row(
+                "SELECT u.id, u.dni FROM loans l JOIN users u ON u.id = l.user_id WHERE l.id = 'l1'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(user_id, "u1");
+        assert_eq!(dni, "12345678Z");
+
+        // As claves foráneas volven quedar activas
+        let foreign_keys: bool = conn
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+        assert!(conn
+            .execute("DELETE FROM users WHERE id = 'u1'", [])
+            .is_err());
+    }
+
+    #[test]
+    fn dangling_reference_aborts_migration() {
+        let conn = database_at(8);
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+             INSERT INTO loans (id, user_id, start_date, expected_end_date, status)
+             VALUES ('l1', 'nobody', '2024-01-01', '2024-02-01', 'active');
+             PRAGMA foreign_keys = ON;",
+        )
+        .unwrap();
+
+        assert!(run(&conn).is_err());
+        assert_eq!(current_version(&conn).unwrap(), 8);
+    }
+}
@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 pub struct User {
     pub id: String,
     pub name: String,
-    pub dni: String,
+    pub dni: Option<String>,
     pub address: String,
     pub phone: Option<String>,
     pub email: Option<String>,
@@ -18,7 +18,10 @@ pub struct User {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateUserRequest {
     pub name: String,
-    pub dni: String,
+    #[serde(default)]
+    pub dni: Option<String>,
+    #[serde(default)]
+    pub no_document: bool,
     pub address: String,
     pub phone: Option<String>,
     pub email: Option<String>,
@@ -29,18 +32,31 @@ pub struct CreateUserRequest {
 pub struct UpdateUserRequest {
     pub name: Option<String>,
     pub dni: Option<String>,
+    // Quitar o DNI dunha persoa que non ten documento
+    #[serde(default)]
+    pub no_document: bool,
     pub address: Option<String>,
     pub phone: Option<String>,
     pub email: Option<String>,
     pub notes: Option<String>,
 }
 
+// Persoa cun DNI/NIE gardado que non é válido ou que non está en forma canónica
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvalidDniEntry {
+    pub user_id: String,
+    pub name: String,
+    pub dni: String,
+    pub reason: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Item {
     pub id: String,
     pub name: String,
     pub description: Option<String>,
     pub category: String,
+    pub category_id: String,
     pub icon: String,
     pub total_stock: i32,
     pub available_stock: i32,
@@ -59,7 +75,6 @@ pub struct CreateItemRequest {
     pub notes: Option<String>,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateItemRequest {
     pub name: Option<String>,
@@ -70,6 +85,276 @@ pub struct UpdateItemRequest {
     pub notes: Option<String>,
 }
 
+// Categoría de artigos. `default_loan_days` é a duración por defecto dos
+// préstamos dos seus artigos; as retiradas non se poden usar en artigos novos.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Category {
+    pub id: String,
+    pub name: String,
+    pub icon: String,
+    pub sort_order: i32,
+    pub default_loan_days: Option<i32>,
+    pub active: bool,
+    pub item_count: i64,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateCategoryRequest {
+    pub name: String,
+    pub icon: Option<String>,
+    pub sort_order: Option<i32>,
+    pub default_loan_days: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateCategoryRequest {
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub sort_order: Option<i32>,
+    pub default_loan_days: Option<i32>,
+    pub active: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentKind {
+    Photo,
+    Manual,
+    Certificate,
+    Other,
+}
+
+impl AttachmentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttachmentKind::Photo => "photo",
+            AttachmentKind::Manual => "manual",
+            AttachmentKind::Certificate => "certificate",
+            AttachmentKind::Other => "other",
+        }
+    }
+
+    pub fn parse(s: &str) -> AttachmentKind {
+        match s {
+            "photo" => AttachmentKind::Photo,
+            "manual" => AttachmentKind::Manual,
+            "certificate" => AttachmentKind::Certificate,
+            _ => AttachmentKind::Other,
+        }
+    }
+}
+
+// Foto ou documento dun artigo. O contido está no cartafol de anexos co
+// nome `sha256`; varios anexos poden compartir o mesmo ficheiro.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub item_id: String,
+    pub kind: AttachmentKind,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub sha256: String,
+    pub has_thumbnail: bool,
+    pub notes: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddAttachmentRequest {
+    pub item_id: String,
+    pub source_path: String,
+    pub kind: Option<AttachmentKind>,
+    pub notes: Option<String>,
+}
+
+// Cada unidade física dun artigo, identificada pola súa etiqueta de inventario
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Unit {
+    pub id: String,
+    pub item_id: String,
+    pub item_name: String,
+    pub tag: String,
+    pub status: UnitStatus,
+    pub acquisition_date: Option<NaiveDate>,
+    pub notes: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitStatus {
+    Available,
+    Loaned,
+    InRepair,
+    Lost,
+    Retired,
+}
+
+impl UnitStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnitStatus::Available => "available",
+            UnitStatus::Loaned => "loaned",
+            UnitStatus::InRepair => "in_repair",
+            UnitStatus::Lost => "lost",
+            UnitStatus::Retired => "retired",
+        }
+    }
+
+    pub fn parse(s: &str) -> UnitStatus {
+        match s {
+            "loaned" => UnitStatus::Loaned,
+            "in_repair" => UnitStatus::InRepair,
+            "lost" => UnitStatus::Lost,
+            "retired" => UnitStatus::Retired,
+            _ => UnitStatus::Available,
+        }
+    }
+}
+
+// Estado no que volve unha unidade prestada
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReturnCondition {
+    Good,
+    NeedsCleaning,
+    Damaged,
+    Lost,
+}
+
+impl ReturnCondition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReturnCondition::Good => "good",
+            ReturnCondition::NeedsCleaning => "needs_cleaning",
+            ReturnCondition::Damaged => "damaged",
+            ReturnCondition::Lost => "lost",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<ReturnCondition> {
+        match s {
+            "good" => Some(ReturnCondition::Good),
+            "needs_cleaning" => Some(ReturnCondition::NeedsCleaning),
+            "damaged" => Some(ReturnCondition::Damaged),
+            "lost" => Some(ReturnCondition::Lost),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ReturnCondition::Good => "Bo estado",
+            ReturnCondition::NeedsCleaning => "Precisa limpeza",
+            ReturnCondition::Damaged => "Danado",
+            ReturnCondition::Lost => "Perdido",
+        }
+    }
+
+    // Estado da unidade tras a devolución: as danadas van a reparación e
+    // as perdidas danse de baixa, e ningunha das dúas se pode volver prestar
+    pub fn unit_status(&self) -> UnitStatus {
+        match self {
+            ReturnCondition::Good | ReturnCondition::NeedsCleaning => UnitStatus::Available,
+            ReturnCondition::Damaged => UnitStatus::InRepair,
+            ReturnCondition::Lost => UnitStatus::Lost,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateUnitRequest {
+    pub item_id: String,
+    pub tag: Option<String>,
+    pub acquisition_date: Option<NaiveDate>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateUnitRequest {
+    pub tag: Option<String>,
+    pub acquisition_date: Option<NaiveDate>,
+    pub notes: Option<String>,
+}
+
+// Orde de mantemento ou reparación dunha unidade. Mentres está aberta a
+// unidade non está dispoñible para préstamo.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceOrder {
+    pub id: String,
+    pub item_id: String,
+    pub item_name: String,
+    pub unit_id: String,
+    pub unit_tag: String,
+    pub loan_id: Option<String>,
+    pub opened_at: NaiveDate,
+    pub description: String,
+    pub cost: Option<f64>,
+    pub closed_at: Option<NaiveDate>,
+    pub outcome: Option<MaintenanceOutcome>,
+    pub notes: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceOutcome {
+    Repaired,
+    WrittenOff,
+    Cancelled,
+}
+
+impl MaintenanceOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaintenanceOutcome::Repaired => "repaired",
+            MaintenanceOutcome::WrittenOff => "written_off",
+            MaintenanceOutcome::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<MaintenanceOutcome> {
+        match s {
+            "repaired" => Some(MaintenanceOutcome::Repaired),
+            "written_off" => Some(MaintenanceOutcome::WrittenOff),
+            "cancelled" => Some(MaintenanceOutcome::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+// Sen unit_id cóllese a primeira unidade dispoñible do artigo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateMaintenanceOrderRequest {
+    pub item_id: String,
+    #[serde(default)]
+    pub unit_id: Option<String>,
+    pub opened_at: Option<NaiveDate>,
+    pub description: String,
+    pub cost: Option<f64>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateMaintenanceOrderRequest {
+    pub description: Option<String>,
+    pub cost: Option<f64>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloseMaintenanceOrderRequest {
+    pub outcome: MaintenanceOutcome,
+    pub closed_at: Option<NaiveDate>,
+    pub cost: Option<f64>,
+    pub notes: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Loan {
     pub id: String,
@@ -80,11 +365,65 @@ pub struct Loan {
     pub actual_end_date: Option<NaiveDate>,
     pub status: LoanStatus,
     pub notes: Option<String>,
+    pub extension_count: i32,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub items: Vec<LoanItem>,
 }
 
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoanSortKey {
+    #[default]
+    CreatedAt,
+    StartDate,
+    ExpectedEndDate,
+    UserName,
+}
+
+// Filtros para listar préstamos. Os rangos de datas inclúen os extremos.
+// Pódese paxinar con `offset` ou co `next_cursor` da páxina anterior; sen
+// `limit` devólvense todos.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoanQuery {
+    #[serde(default)]
+    pub statuses: Option<Vec<LoanStatus>>,
+    pub start_from: Option<NaiveDate>,
+    pub start_to: Option<NaiveDate>,
+    pub due_from: Option<NaiveDate>,
+    pub due_to: Option<NaiveDate>,
+    pub user_id: Option<String>,
+    pub item_id: Option<String>,
+    pub category_id: Option<String>,
+    // Sen devolver e con máis de N días de atraso
+    pub overdue_by_days: Option<i64>,
+    #[serde(default)]
+    pub sort: LoanSortKey,
+    #[serde(default)]
+    pub ascending: bool,
+    pub offset: Option<i64>,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoanPage {
+    pub loans: Vec<Loan>,
+    pub total: i64,
+    pub next_cursor: Option<String>,
+}
+
+// Cada prórroga garda a data prevista anterior e a nova
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoanExtension {
+    pub id: String,
+    pub loan_id: String,
+    pub previous_end_date: NaiveDate,
+    pub new_end_date: NaiveDate,
+    pub reason: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum LoanStatus {
@@ -92,6 +431,7 @@ pub enum LoanStatus {
     Pending,
     Returned,
     Overdue,
+    Cancelled,
 }
 
 impl LoanStatus {
@@ -101,10 +441,85 @@ impl LoanStatus {
             LoanStatus::Pending => "pending",
             LoanStatus::Returned => "returned",
             LoanStatus::Overdue => "overdue",
+            LoanStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(s: &str) -> LoanStatus {
+        match s {
+            "pending" => LoanStatus::Pending,
+            "returned" => LoanStatus::Returned,
+            "overdue" => LoanStatus::Overdue,
+            "cancelled" => LoanStatus::Cancelled,
+            _ => LoanStatus::Active,
+        }
+    }
+
+    // Máquina de estados dos préstamos. `current` é None cando o préstamo
+    // aínda non existe (só se permite crealo).
+    pub fn next(
+        current: Option<&LoanStatus>,
+        transition: LoanTransition,
+    ) -> Result<LoanStatus, InvalidLoanTransition> {
+        use LoanStatus::*;
+        use LoanTransition::*;
+
+        match (current, transition) {
+            (None, Create) => Ok(Active),
+            (Some(Pending), Deliver) => Ok(Active),
+            (Some(Active), Return) | (Some(Overdue), Return) => Ok(Returned),
+            (Some(Returned), CancelReturn) => Ok(Active),
+            (Some(Active), MarkOverdue) => Ok(Overdue),
+            (Some(Overdue), ClearOverdue) => Ok(Active),
+            (Some(status @ (Pending | Active | Overdue)), Extend) => Ok(status.clone()),
+            (Some(Pending | Active | Overdue), Void) => Ok(Cancelled),
+            _ => Err(InvalidLoanTransition {
+                from: current.cloned(),
+                transition,
+            }),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoanTransition {
+    Create,
+    Deliver,
+    Return,
+    CancelReturn,
+    MarkOverdue,
+    ClearOverdue,
+    Extend,
+    Void,
+}
+
+impl LoanTransition {
+    pub fn label(&self) -> &'static str {
+        match self {
+            LoanTransition::Create => "crear",
+            LoanTransition::Deliver => "entregar",
+            LoanTransition::Return => "devolver",
+            LoanTransition::CancelReturn => "cancelar a devolución de",
+            LoanTransition::MarkOverdue => "marcar como atrasado",
+            LoanTransition::ClearOverdue => "quitar o atraso de",
+            LoanTransition::Extend => "prorrogar",
+            LoanTransition::Void => "anular",
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error(
+    "Non se pode {} un préstamo en estado '{}'",
+    transition.label(),
+    from.as_ref().map(|s| s.as_str()).unwrap_or("inexistente")
+)]
+pub struct InvalidLoanTransition {
+    pub from: Option<LoanStatus>,
+    pub transition: LoanTransition,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoanItem {
     pub id: String,
@@ -112,21 +527,90 @@ pub struct LoanItem {
     pub item_id: String,
     pub item_name: String,
     pub quantity: i32,
+    pub returned_quantity: i32,
+    pub returned_at: Option<NaiveDate>,
+    pub return_condition: Option<ReturnCondition>,
+    pub return_notes: Option<String>,
+    pub units: Vec<LoanUnit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoanUnit {
+    pub unit_id: String,
+    pub tag: String,
+    pub returned_at: Option<NaiveDate>,
+    pub return_condition: Option<ReturnCondition>,
+}
+
+// Liña que pechou unha devolución, co estado que tiña antes. Gárdase no
+// evento LOAN_RETURNED para que `cancel_return` desfaga só iso.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReturnedLine {
+    pub loan_item_id: String,
+    pub quantity: i32,
+    pub unit_ids: Vec<String>,
+    pub previous_returned_at: Option<NaiveDate>,
+    pub previous_condition: Option<ReturnCondition>,
+    pub previous_notes: Option<String>,
+}
+
+impl ReturnedLine {
+    pub fn new(item: &LoanItem, quantity: i32, unit_ids: Vec<String>) -> Self {
+        Self {
+            loan_item_id: item.id.clone(),
+            quantity,
+            unit_ids,
+            previous_returned_at: item.returned_at,
+            previous_condition: item.return_condition,
+            previous_notes: item.return_notes.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateLoanRequest {
     pub user_id: String,
-    pub item_ids: Vec<String>,
+    pub items: Vec<LoanItemRequest>,
     pub start_date: NaiveDate,
     pub expected_end_date: NaiveDate,
     pub notes: Option<String>,
 }
 
-#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoanItemRequest {
+    pub item_id: String,
+    pub quantity: i32,
+    // Unidades concretas a prestar; se non se indican, asígnanse automaticamente
+    #[serde(default)]
+    pub unit_ids: Option<Vec<String>>,
+}
+
+// Devolución parcial dunha liña: por defecto devólvese todo o pendente
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReturnLoanItemRequest {
+    pub quantity: Option<i32>,
+    #[serde(default)]
+    pub unit_ids: Option<Vec<String>>,
+    pub condition: Option<ReturnCondition>,
+    pub notes: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReturnLoanRequest {
-    pub condition: Option<String>,
+    pub condition: Option<ReturnCondition>,
+    pub notes: Option<String>,
+}
+
+// Unha unidade devolta danada ou perdida
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DamageReportEntry {
+    pub returned_at: NaiveDate,
+    pub condition: ReturnCondition,
+    pub loan_id: String,
+    pub user_name: String,
+    pub item_name: String,
+    pub unit_tag: String,
     pub notes: Option<String>,
 }
 
@@ -138,6 +622,79 @@ pub struct Event {
     pub created_at: NaiveDateTime,
     pub loan_id: Option<String>,
     pub user_id: Option<String>,
+    pub item_id: Option<String>,
+    pub cancelled_by: Option<String>,
+    pub cancellation_reason: Option<String>,
+}
+
+// Filtros para consultar o rexistro de eventos. O cursor é o `next_cursor`
+// da páxina anterior.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventQuery {
+    pub loan_id: Option<String>,
+    pub user_id: Option<String>,
+    pub item_id: Option<String>,
+    #[serde(default)]
+    pub event_types: Option<Vec<String>>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub hide_cancelled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    pub next_cursor: Option<String>,
+}
+
+// Resultados da busca global, agrupados por tipo. `snippet` é o fragmento
+// onde apareceu o texto buscado, marcado entre [ ].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GlobalSearchResults {
+    pub users: Vec<UserSearchHit>,
+    pub items: Vec<ItemSearchHit>,
+    pub loans: Vec<LoanSearchHit>,
+    pub events: Vec<EventSearchHit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserSearchHit {
+    pub user: User,
+    pub snippet: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemSearchHit {
+    pub item: Item,
+    pub snippet: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoanMatch {
+    Notes,
+    ItemName,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoanSearchHit {
+    pub loan_id: String,
+    pub user_id: String,
+    pub user_name: String,
+    pub status: LoanStatus,
+    pub start_date: NaiveDate,
+    pub expected_end_date: NaiveDate,
+    pub matched: LoanMatch,
+    pub snippet: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventSearchHit {
+    pub event: Event,
+    pub snippet: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -147,6 +704,7 @@ pub struct DashboardStats {
     pub overdue_loans: i64,
     pub total_items: i64,
     pub total_items_available: i64,
+    pub units_in_maintenance: i64,
     pub total_users: i64,
     pub recent_loans: Vec<Loan>,
     pub recent_events: Vec<Event>,
@@ -159,3 +717,176 @@ pub struct BackupInfo {
     pub created_at: NaiveDateTime,
     pub path: String,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaInfo {
+    pub current_version: i32,
+    pub latest_version: i32,
+}
+
+// Diferenza entre os contadores gardados dun artigo e os que se deducen
+// das súas unidades, dos préstamos abertos e do rexistro de eventos
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StockDrift {
+    pub item_id: String,
+    pub item_name: String,
+    pub stored_total: i32,
+    pub stored_available: i32,
+    pub expected_total: i32,
+    pub expected_available: i32,
+    pub loaned_by_loans: i32,
+    pub loaned_by_events: i32,
+    pub loaned_units: i32,
+    pub linked_units: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StockCheckReport {
+    pub checked_at: NaiveDateTime,
+    pub items_checked: i32,
+    pub drifts: Vec<StockDrift>,
+    pub repaired: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoanStatus::*;
+    use super::LoanTransition::*;
+    use super::*;
+    use crate::error::SafError;
+
+    fn allowed(from: Option<LoanStatus>, transition: LoanTransition, to: LoanStatus) {
+        assert_eq!(LoanStatus::next(from.as_ref(), transition).unwrap(), to);
+    }
+
+    fn rejected(from: Option<LoanStatus>, transition: LoanTransition) {
+        let err = SafError::from(LoanStatus::next(from.as_ref(), transition).unwrap_err());
+        match err {
+            SafError::InvalidTransition(e) => {
+                assert_eq!(e.from, from);
+                assert_eq!(e.transition, transition);
+            }
+            other => panic!("agardábase InvalidTransition: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn create_starts_active() {
+        allowed(None, Create, Active);
+    }
+
+    #[test]
+    fn deliver_pending() {
+        allowed(Some(Pending), Deliver, Active);
+    }
+
+    #[test]
+    fn return_active() {
+        allowed(Some(Active), Return, Returned);
+    }
+
+    #[test]
+    fn return_overdue() {
+        allowed(Some(Overdue), Return, Returned);
+    }
+
+    #[test]
+    fn cancel_return_of_returned() {
+        allowed(Some(Returned), CancelReturn, Active);
+    }
+
+    #[test]
+    fn mark_active_overdue() {
+        allowed(Some(Active), MarkOverdue, Overdue);
+    }
+
+    #[test]
+    fn clear_overdue() {
+        allowed(Some(Overdue), ClearOverdue, Active);
+    }
+
+    #[test]
+    fn extend_keeps_pending() {
+        allowed(Some(Pending), Extend, Pending);
+    }
+
+    #[test]
+    fn extend_keeps_active() {
+        allowed(Some(Active), Extend, Active);
+    }
+
+    #[test]
+    fn extend_keeps_overdue() {
+        allowed(Some(Overdue), Extend, Overdue);
+    }
+
+    #[test]
+    fn void_open_loans() {
+        allowed(Some(Pending), Void, Cancelled);
+        allowed(Some(Active), Void, Cancelled);
+        allowed(Some(Overdue), Void, Cancelled);
+    }
+
+    #[test]
+    fn create_existing_rejected() {
+        rejected(Some(Active), Create);
+    }
+
+    #[test]
+    fn missing_loan_rejected() {
+        rejected(None, Return);
+        rejected(None, Extend);
+    }
+
+    #[test]
+    fn deliver_active_rejected() {
+        rejected(Some(Active), Deliver);
+    }
+
+    #[test]
+    fn return_returned_rejected() {
+        rejected(Some(Returned), Return);
+    }
+
+    #[test]
+    fn return_pending_rejected() {
+        rejected(Some(Pending), Return);
+    }
+
+    #[test]
+    fn return_cancelled_rejected() {
+        rejected(Some(Cancelled), Return);
+    }
+
+    #[test]
+    fn cancel_return_active_rejected() {
+        rejected(Some(Active), CancelReturn);
+    }
+
+    #[test]
+    fn mark_overdue_twice_rejected() {
+        rejected(Some(Overdue), MarkOverdue);
+        rejected(Some(Returned), MarkOverdue);
+    }
+
+    #[test]
+    fn clear_overdue_active_rejected() {
+        rejected(Some(Active), ClearOverdue);
+    }
+
+    #[test]
+    fn extend_closed_rejected() {
+        rejected(Some(Returned), Extend);
+        rejected(Some(Cancelled), Extend);
+    }
+
+    #[test]
+    fn void_returned_rejected() {
+        rejected(Some(Returned), Void);
+    }
+
+    #[test]
+    fn void_cancelled_rejected() {
+        rejected(Some(Cancelled), Void);
+    }
+}
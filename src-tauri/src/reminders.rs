@@ -0,0 +1,155 @@
+use chrono::{Datelike, NaiveDate};
+
+use crate::error::{Result, SafError};
+use crate::models::{Loan, User};
+
+// Cartas de recordatorio para préstamos atrasados. O texto sae dun modelo
+// que se pode cambiar (clave `reminder_template` de settings.json) cos
+// marcadores entre chaves, p.ex. "{nome}".
+pub const DEFAULT_TEMPLATE: &str = "Estimado/a {nome}:
+
+Segundo os nosos rexistros, o material que o Servizo de Axuda ao Fogar lle prestou tiña que devolverse o {data_devolucion} e a día de hoxe aínda non consta a súa devolución:
+
+{artigos}
+
+Pregámoslle que o devolva canto antes nas dependencias do Concello ou que se poña en contacto connosco se aínda o necesita, para tramitar unha prórroga do préstamo.
+
+Se xa o devolveu, non teña en conta esta carta.
+
+Atentamente,";
+
+// Marcadores admitidos no modelo e o que se pon no seu lugar
+pub const PLACEHOLDERS: &[(&str, &str)] = &[
+    ("nome", "Nome da persoa"),
+    ("enderezo", "Enderezo"),
+    ("artigos", "Material pendente, un por liña"),
+    ("data_devolucion", "Data de devolución máis antiga"),
+    ("dias_atraso", "Días de atraso"),
+    ("data", "Data da carta"),
+];
+
+// Un modelo válido non está baleiro e só usa marcadores coñecidos
+pub fn validate_template(template: &str) -> Result<()> {
+    if template.trim().is_empty() {
+        return Err(SafError::Validation(
+            "O modelo da carta non pode estar baleiro".to_string(),
+        ));
+    }
+
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            return Err(SafError::Validation(
+                "O modelo ten unha chave '{' sen pechar".to_string(),
+            ));
+        };
+        let name = &rest[start + 1..start + len];
+        if !PLACEHOLDERS.iter().any(|(p, _)| *p == name) {
+            return Err(SafError::Validation(format!(
+                "Marcador descoñecido {{{}}}. Pódense usar: {}",
+                name,
+                PLACEHOLDERS
+                    .iter()
+                    .map(|(p, _)| format!("{{{}}}", p))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+        rest = &rest[start + len + 1..];
+    }
+    Ok(())
+}
+
+// Unha carta por persoa con todos os seus préstamos atrasados
+pub struct ReminderLetter<'a> {
+    pub user: Option<&'a User>,
+    pub user_name: &'a str,
+    pub loans: Vec<&'a Loan>,
+}
+
+impl ReminderLetter<'_> {
+    fn due_date(&self) -> Option<NaiveDate> {
+        self.loans.iter().map(|l| l.expected_end_date).min()
+    }
+
+    // O material aínda sen devolver, cada un coa súa data prevista
+    fn items_text(&self) -> String {
+        self.loans
+            .iter()
+            .flat_map(|loan| {
+                loan.items
+                    .iter()
+                    .filter(|i| i.returned_quantity < i.quantity)
+                    .map(move |i| {
+                        format!(
+                            "   - {} x{} (devolución prevista o {})",
+                            i.item_name,
+                            i.quantity - i.returned_quantity,
+                            loan.expected_end_date.format("%d/%m/%Y")
+                        )
+                    })
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn render(&self, template: &str, today: NaiveDate) -> String {
+        let due = self.due_date().unwrap_or(today);
+        let values = [
+            ("nome", self.user_name.to_string()),
+            (
+                "enderezo",
+                self.user.map(|u| u.address.clone()).unwrap_or_default(),
+            ),
+            ("artigos", self.items_text()),
+            ("data_devolucion", due.format("%d/%m/%Y").to_string()),
+            ("dias_atraso", (today - due).num_days().max(0).to_string()),
+            ("data", long_date(today)),
+        ];
+        values
+            .iter()
+            .fold(template.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{}}}", name), value)
+            })
+    }
+}
+
+// Agrupa os préstamos por persoa, na orde na que veñen; os de cada
+// persoa van do máis atrasado ao menos
+pub fn group_by_user<'a>(
+    loans: &'a [Loan],
+    users: &'a std::collections::HashMap<String, User>,
+) -> Vec<ReminderLetter<'a>> {
+    let mut letters: Vec<ReminderLetter> = vec![];
+    for loan in loans {
+        match letters
+            .iter_mut()
+            .find(|l| l.loans[0].user_id == loan.user_id)
+        {
+            Some(letter) => letter.loans.push(loan),
+            None => letters.push(ReminderLetter {
+                user: users.get(&loan.user_id),
+                user_name: &loan.user_name,
+                loans: vec![loan],
+            }),
+        }
+    }
+    for letter in &mut letters {
+        letter.loans.sort_by_key(|l| l.expected_end_date);
+    }
+    letters
+}
+
+// "18 de outubro de 2026"
+pub fn long_date(date: NaiveDate) -> String {
+    const MONTHS: [&str; 12] = [
+        "xaneiro", "febreiro", "marzo", "abril", "maio", "xuño", "xullo", "agosto", "setembro",
+        "outubro", "novembro", "decembro",
+    ];
+    format!(
+        "{} de {} de {}",
+        date.day(),
+        MONTHS[date.month0() as usize],
+        date.year()
+    )
+}
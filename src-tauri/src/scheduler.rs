@@ -0,0 +1,145 @@
+use chrono::{Local, NaiveDateTime, NaiveTime};
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+use crate::error::Result;
+use crate::AppState;
+
+// Evento que recibe o frontend cando o planificador cambia estados de préstamos
+pub const LOAN_STATUSES_CHANGED: &str = "loan-statuses-changed";
+
+// Evento co erro dunha tarefa que fallou, para que a interface o amose
+pub const SCHEDULED_TASK_FAILED: &str = "scheduled-task-failed";
+
+// Evento co informe da comprobación de stock cando atopa desfases
+pub const STOCK_DRIFT_DETECTED: &str = "stock-drift-detected";
+
+// Cada canto se revisa se hai tarefas pendentes. Dormir en anacos curtos
+// evita perder a medianoite se o equipo suspende ou cambia a hora.
+const TICK: Duration = Duration::from_secs(30);
+
+pub enum Trigger {
+    // Unha vez ao arrancar
+    Startup,
+    // Cada día á hora indicada (hora local)
+    Daily(NaiveTime),
+    // Cada certo tempo desde a última execución
+    Every(Duration),
+}
+
+pub type JobFn = Box<dyn Fn(&AppHandle) -> Result<()> + Send>;
+
+pub struct Job {
+    pub name: &'static str,
+    pub triggers: Vec<Trigger>,
+    pub run: JobFn,
+}
+
+// Executor de tarefas periódicas nun fío propio. As tarefas (detección de
+// atrasos, copias automáticas, recordatorios...) rexístranse con `add`
+// antes de `start`.
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(mut self, job: Job) -> Self {
+        self.jobs.push(job);
+        self
+    }
+
+    pub fn start(self, app_handle: AppHandle) {
+        std::thread::spawn(move || {
+            let mut last_runs: Vec<Option<NaiveDateTime>> = vec![None; self.jobs.len()];
+            let mut started = false;
+
+            loop {
+                let now = Local::now().naive_local();
+                for (job, last_run) in self.jobs.iter().zip(last_runs.iter_mut()) {
+                    if !is_due(&job.triggers, *last_run, now, started) {
+                        continue;
+                    }
+                    *last_run = Some(now);
+                    if let Err(e) = (job.run)(&app_handle) {
+                        let _ = app_handle.emit_all(
+                            SCHEDULED_TASK_FAILED,
+                            serde_json::json!({ "job": job.name, "error": e }),
+                        );
+                    }
+                }
+                started = true;
+                std::thread::sleep(TICK);
+            }
+        });
+    }
+}
+
+fn is_due(
+    triggers: &[Trigger],
+    last_run: Option<NaiveDateTime>,
+    now: NaiveDateTime,
+    started: bool,
+) -> bool {
+    // Unha tarefa que nunca se executou ponse ao día no primeiro ciclo
+    triggers.iter().any(|trigger| match trigger {
+        Trigger::Startup => !started,
+        // Pasou a hora de hoxe e aínda non se executou despois dela
+        Trigger::Daily(time) => {
+            let today_at = now.date().and_time(*time);
+            now >= today_at && last_run.is_none_or(|last| last < today_at)
+        }
+        Trigger::Every(interval) => last_run.is_none_or(|last| {
+            (now - last)
+                .to_std()
+                .is_ok_and(|elapsed| elapsed >= *interval)
+        }),
+    })
+}
+
+// Detección de préstamos atrasados: ao arrancar, á medianoite e cada
+// `interval`. Se cambia algún estado avísase ao frontend.
+pub fn overdue_job(interval: Duration) -> Job {
+    Job {
+        name: "overdue",
+        triggers: vec![
+            Trigger::Startup,
+            Trigger::Daily(NaiveTime::MIN),
+            Trigger::Every(interval),
+        ],
+        run: Box::new(|app_handle| {
+            let state = app_handle.state::<AppState>();
+            let _lock = state.lock.lock()?;
+            let updated = state.db.lock()?.update_overdue_loans()?;
+            if updated > 0 {
+                let _ = app_handle.emit_all(
+                    LOAN_STATUSES_CHANGED,
+                    serde_json::json!({ "updated": updated }),
+                );
+            }
+            Ok(())
+        }),
+    }
+}
+
+// Comprobación do stock ao arrancar e cada noite. Só informa: os desfases
+// envíanse ao frontend e a corrección faise co comando `check_stock`.
+pub fn stock_check_job() -> Job {
+    Job {
+        name: "stock_check",
+        triggers: vec![Trigger::Startup, Trigger::Daily(NaiveTime::MIN)],
+        run: Box::new(|app_handle| {
+            let state = app_handle.state::<AppState>();
+            let _lock = state.lock.lock()?;
+            let report = state.db.lock()?.check_stock(false)?;
+            if !report.drifts.is_empty() {
+                let _ = app_handle.emit_all(STOCK_DRIFT_DETECTED, &report);
+            }
+            Ok(())
+        }),
+    }
+}
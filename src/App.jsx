@@ -1,6 +1,7 @@
 import { useState, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/tauri";
 import { save, open as tauriOpen } from "@tauri-apps/api/dialog";
+import { listen } from "@tauri-apps/api/event";
 
 // Fuzzy search helper
 const fuzzyMatch = (text, query) => {
@@ -10,6 +11,9 @@ const fuzzyMatch = (text, query) => {
   return t.includes(q);
 };
 
+// Os comandos devolven erros como { code, message, details }
+const errorMessage = (err) => (err && err.message) || String(err);
+
 // --- Shared Components ---
 const StatusBadge = ({ status }) => {
   const map = {
@@ -71,6 +75,7 @@ export default function SAFApp() {
   const [showReturn, setShowReturn] = useState(null);
   const [showEvents, setShowEvents] = useState(false);
   const [toast, setToast] = useState(null);
+  const [stockDrift, setStockDrift] = useState(null);
   const [showDbConfig, setShowDbConfig] = useState(false);
   const [dbLocation, setDbLocation] = useState(null);
   const [needsDbConfig, setNeedsDbConfig] = useState(false);
@@ -115,6 +120,35 @@ export default function SAFApp() {
 
   useEffect(() => { loadAll(); }, []);
 
+  // O planificador do backend avisa cando marca préstamos como atrasados
+  useEffect(() => {
+    const unlisten = listen("loan-statuses-changed", () => loadAll());
+    return () => { unlisten.then(f => f()); };
+  }, []);
+
+  // A comprobación de stock do backend avisa dos desfases sen corrixilos
+  useEffect(() => {
+    const unlisten = listen("stock-drift-detected", (event) => setStockDrift(event.payload));
+    return () => { unlisten.then(f => f()); };
+  }, []);
+
+  const repairStock = async () => {
+    try {
+      const report = await invoke("check_stock", { repair: true });
+      showToast(`Stock corrixido en ${report.drifts.length} artigos`);
+      setStockDrift(null);
+      loadAll();
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  // Erros das tarefas programadas (atrasos, copias automáticas...)
+  useEffect(() => {
+    const unlisten = listen("scheduled-task-failed", (event) => {
+      showToast(`Erro na tarefa programada ${event.payload.job}: ${errorMessage(event.payload.error)}`);
+    });
+    return () => { unlisten.then(f => f()); };
+  }, []);
+
   const getUserName = (id) => users.find(u => u.id === id)?.name || "—";
 
   // Compute stats from items (units by type)
@@ -131,13 +165,13 @@ export default function SAFApp() {
     try {
       await invoke("create_user", {
         req: {
-          name: form.name, dni: form.dni, address: form.address || "",
+          name: form.name, dni: form.noDocument ? null : form.dni, no_document: form.noDocument, address: form.address || "",
           phone: form.phone || null, email: null, notes: form.notes || null,
         }
       });
       showToast(`Usuaria/o ${form.name} engadido`);
       loadAll();
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const createLoan = async (userId, selectedItems, notes) => {
@@ -145,7 +179,7 @@ export default function SAFApp() {
       await invoke("create_loan", {
         req: {
           user_id: userId,
-          item_ids: selectedItems.map(i => i.id),
+          items: selectedItems.map(i => ({ item_id: i.id, quantity: i.quantity || 1 })),
           start_date: new Date().toISOString().split("T")[0],
           expected_end_date: (() => { const d = new Date(); d.setMonth(d.getMonth() + 1); return d.toISOString().split("T")[0]; })(),
           notes: notes || null,
@@ -153,7 +187,7 @@ export default function SAFApp() {
       });
       showToast("Préstamo creado correctamente");
       loadAll();
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const returnLoan = async (loanId) => {
@@ -161,7 +195,33 @@ export default function SAFApp() {
       await invoke("return_loan", { id: loanId, condition: null, notes: null });
       showToast("Préstamo devolto correctamente");
       loadAll();
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  const exportDeliveryReceipt = async (loan) => {
+    try {
+      const filePath = await save({
+        filters: [{ name: "PDF", extensions: ["pdf"] }],
+        defaultPath: `albaran_${loan.user_name.replace(/\s+/g, "_")}_${loan.start_date}.pdf`,
+      });
+      if (filePath) {
+        await invoke("export_delivery_receipt_pdf", { path: filePath, loanId: loan.id });
+        showToast("Albarán gardado");
+      }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  const exportReturnReceipt = async (loan) => {
+    try {
+      const filePath = await save({
+        filters: [{ name: "PDF", extensions: ["pdf"] }],
+        defaultPath: `devolucion_${loan.user_name.replace(/\s+/g, "_")}_${loan.actual_end_date || new Date().toISOString().split("T")[0]}.pdf`,
+      });
+      if (filePath) {
+        await invoke("export_return_receipt_pdf", { path: filePath, loanId: loan.id });
+        showToast("Certificado de devolución gardado");
+      }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const addStockItem = async (itemId, quantity) => {
@@ -172,14 +232,14 @@ export default function SAFApp() {
         showToast("Stock actualizado");
         loadAll();
       }
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const handleBackup = async () => {
     try {
       await invoke("create_backup");
       showToast("Copia de seguridade creada con éxito!");
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const navItems = [
@@ -259,8 +319,8 @@ export default function SAFApp() {
         <div style={{ flex: 1, overflow: "auto", padding: 24 }}>
           <div style={{ animation: "fadeIn 0.25s ease" }}>
             {view === "dashboard" && <Dashboard stats={stats} loans={loans} items={items} getUserName={getUserName} />}
-            {view === "users" && <UsersView users={users} selectedUser={selectedUser} setSelectedUser={setSelectedUser} loans={loans} items={items} onNewUser={() => setShowNewUser(true)} onNewLoan={() => setShowNewLoan(true)} onReturn={(id) => setShowReturn(id)} loadAll={loadAll} showToast={showToast} />}
-            {view === "loans" && <LoansView loans={loans} getUserName={getUserName} items={items} onReturn={(id) => setShowReturn(id)} onNewLoan={() => setShowNewLoan(true)} />}
+            {view === "users" && <UsersView users={users} selectedUser={selectedUser} setSelectedUser={setSelectedUser} loans={loans} items={items} onNewUser={() => setShowNewUser(true)} onNewLoan={() => setShowNewLoan(true)} onReturn={(id) => setShowReturn(id)} onReceipt={exportDeliveryReceipt} onReturnReceipt={exportReturnReceipt} loadAll={loadAll} showToast={showToast} />}
+            {view === "loans" && <LoansView loans={loans} getUserName={getUserName} items={items} onReturn={(id) => setShowReturn(id)} onReceipt={exportDeliveryReceipt} onReturnReceipt={exportReturnReceipt} onNewLoan={() => setShowNewLoan(true)} />}
             {view === "stock" && <StockView items={items} onAdd={() => setShowNewStock(true)} loadAll={loadAll} showToast={showToast} />}
             {view === "reports" && <ReportsView showToast={showToast} />}
             {view === "admin" && <AdminView showToast={showToast} dbLocation={dbLocation} setDbLocation={setDbLocation} />}
@@ -284,6 +344,21 @@ export default function SAFApp() {
         </div>
       </Modal>
 
+      <Modal open={!!stockDrift} onClose={() => setStockDrift(null)} title="Desfases de stock">
+        <p style={{ color: "#5a6a7a", lineHeight: 1.6 }}>Os contadores destes artigos non coinciden coas súas unidades, os préstamos abertos ou o rexistro de eventos:</p>
+        <div style={{ marginTop: 12, display: "flex", flexDirection: "column", gap: 6 }}>
+          {stockDrift?.drifts.map(d => (
+            <div key={d.item_id} style={{ fontSize: 13, padding: "8px 12px", borderRadius: 8, background: "#FFF3E0" }}>
+              <b>{d.item_name}</b> · dispoñibles {d.stored_available} (deberían ser {d.expected_available}) · total {d.stored_total} (debería ser {d.expected_total})
+            </div>
+          ))}
+        </div>
+        <div style={{ display: "flex", gap: 10, justifyContent: "flex-end", marginTop: 20 }}>
+          <Btn onClick={() => setStockDrift(null)}>Pechar</Btn>
+          <Btn primary onClick={repairStock}>Corrixir stock</Btn>
+        </div>
+      </Modal>
+
       {toast && <div style={{
         position: "fixed", bottom: 24, left: "50%", transform: "translateX(-50%)", zIndex: 2000,
         background: "#1a6b5a", color: "#fff", padding: "12px 24px", borderRadius: 12, fontWeight: 700, fontSize: 14,
@@ -373,11 +448,11 @@ function Dashboard({ stats, loans, items, getUserName }) {
 // ============================================================
 // USERS VIEW
 // ============================================================
-function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUser, onNewLoan, onReturn, loadAll, showToast }) {
+function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUser, onNewLoan, onReturn, onReceipt, onReturnReceipt, loadAll, showToast }) {
   const [search, setSearch] = useState("");
   const [editUser, setEditUser] = useState(null);
   const [confirmDeactivate, setConfirmDeactivate] = useState(false);
-  const filtered = users.filter(u => fuzzyMatch(u.name, search) || fuzzyMatch(u.dni, search));
+  const filtered = users.filter(u => fuzzyMatch(u.name, search) || fuzzyMatch(u.dni || "", search));
   const getItemIcon = (itemId) => items.find(i => i.id === itemId)?.icon || "📦";
 
   const handleDeactivate = async (userId) => {
@@ -389,7 +464,7 @@ function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUs
       setConfirmDeactivate(false);
       setSelectedUser(null);
       loadAll();
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const handleSaveEdit = async (form) => {
@@ -397,14 +472,14 @@ function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUs
       await invoke("update_user", {
         id: editUser.id,
         req: {
-          name: form.name || null, dni: form.dni || null, address: form.address || null,
+          name: form.name || null, dni: form.noDocument ? null : (form.dni || null), no_document: form.noDocument, address: form.address || null,
           phone: form.phone || null, email: null, notes: form.notes || null,
         }
       });
       showToast("Usuario/a actualizado/a");
       setEditUser(null);
       loadAll();
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   return (
@@ -430,7 +505,7 @@ function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUs
                   <div style={{ fontWeight: 700, fontSize: 14 }}>{user.name}</div>
                   {al > 0 && <span style={{ background: "#E65100", color: "#fff", borderRadius: 20, padding: "2px 8px", fontSize: 10, fontWeight: 700 }}>{al}</span>}
                 </div>
-                <div style={{ fontSize: 12, color: "#8a96a3", marginTop: 2 }}>DNI: {user.dni} · ☎ {user.phone || "—"}</div>
+                <div style={{ fontSize: 12, color: "#8a96a3", marginTop: 2 }}>DNI: {user.dni || "sen documento"} · ☎ {user.phone || "—"}</div>
               </button>
             );
           })}
@@ -443,7 +518,7 @@ function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUs
               <div style={{ display: "flex", justifyContent: "space-between", alignItems: "flex-start" }}>
                 <div>
                   <h2 style={{ margin: "0 0 3px", fontSize: 20, fontWeight: 900 }}>{selectedUser.name}</h2>
-                  <span style={{ color: "#8a96a3", fontSize: 13 }}>DNI: {selectedUser.dni}</span>
+                  <span style={{ color: "#8a96a3", fontSize: 13 }}>DNI: {selectedUser.dni || "sen documento"}</span>
                 </div>
                 <div style={{ display: "flex", gap: 6 }}>
                   <Btn small onClick={() => setEditUser(selectedUser)}>✏️ Editar</Btn>
@@ -469,7 +544,7 @@ function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUs
         <div style={{ background: "#FCE4EC", border: "1px solid #EF9A9A", borderRadius: 10, padding: "14px 18px", marginBottom: 16 }}>
           <div style={{ fontWeight: 700, fontSize: 13, color: "#C62828", marginBottom: 6 }}>⚠️ Atención</div>
           <p style={{ fontSize: 13, color: "#5a6a7a", lineHeight: 1.6, margin: 0 }}>
-            Vas desactivar o/a usuario/a <strong>{selectedUser?.name}</strong> (DNI: {selectedUser?.dni}).
+            Vas desactivar o/a usuario/a <strong>{selectedUser?.name}</strong> (DNI: {selectedUser?.dni || "sen documento"}).
           </p>
         </div>
         <p style={{ fontSize: 13, color: "#5a6a7a", lineHeight: 1.6 }}>
@@ -492,7 +567,11 @@ function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUs
                     <span style={{ fontWeight: 700, fontSize: 13 }}>Préstamo do {loan.start_date}</span>
                     {loan.notes && <span style={{ color: "#8a96a3", fontSize: 12, marginLeft: 8 }}>— {loan.notes}</span>}
                   </div>
-                  <Btn small danger onClick={() => onReturn(loan.id)}>↩ Devolver</Btn>
+                  <div style={{ display: "flex", gap: 6 }}>
+                    <Btn small onClick={() => onReceipt(loan)}>🧾 Albarán</Btn>
+                    {loan.items.some(li => li.returned_quantity > 0) && <Btn small onClick={() => onReturnReceipt(loan)}>📄 Devolución</Btn>}
+                    <Btn small danger onClick={() => onReturn(loan.id)}>↩ Devolver</Btn>
+                  </div>
                 </div>
                 <div style={{ display: "flex", gap: 8, flexWrap: "wrap" }}>
                   {loan.items.map((li, i) => (
@@ -536,7 +615,7 @@ function UsersView({ users, selectedUser, setSelectedUser, loans, items, onNewUs
 // ============================================================
 // LOANS VIEW
 // ============================================================
-function LoansView({ loans, getUserName, items, onReturn, onNewLoan }) {
+function LoansView({ loans, getUserName, items, onReturn, onReceipt, onReturnReceipt, onNewLoan }) {
   const [filter, setFilter] = useState("active");
   const getItemIcon = (itemId) => items.find(i => i.id === itemId)?.icon || "📦";
   const filtered = loans.filter(l => {
@@ -580,7 +659,13 @@ function LoansView({ loans, getUserName, items, onReturn, onNewLoan }) {
                 <td style={{ padding: "11px 14px", color: "#5a6a7a" }}>{loan.start_date}</td>
                 <td style={{ padding: "11px 14px" }}><StatusBadge status={loan.status} /></td>
                 <td style={{ padding: "11px 14px", color: "#8a96a3", fontSize: 12, maxWidth: 180, overflow: "hidden", textOverflow: "ellipsis", whiteSpace: "nowrap" }}>{loan.notes || "—"}</td>
-                <td style={{ padding: "11px 14px" }}>{(loan.status === "active" || loan.status === "overdue") && <Btn small danger onClick={() => onReturn(loan.id)}>↩ Devolver</Btn>}</td>
+                <td style={{ padding: "11px 14px" }}>
+                  <div style={{ display: "flex", gap: 6 }}>
+                    <Btn small onClick={() => onReceipt(loan)}>🧾 Albarán</Btn>
+                    {loan.items.some(li => li.returned_quantity > 0) && <Btn small onClick={() => onReturnReceipt(loan)}>📄 Devolución</Btn>}
+                    {(loan.status === "active" || loan.status === "overdue") && <Btn small danger onClick={() => onReturn(loan.id)}>↩ Devolver</Btn>}
+                  </div>
+                </td>
               </tr>
             ))}
           </tbody>
@@ -599,6 +684,7 @@ function StockView({ items, onAdd, loadAll, showToast }) {
   const [editItem, setEditItem] = useState(null);
   const [editVal, setEditVal] = useState(0);
   const [confirmDeleteItem, setConfirmDeleteItem] = useState(null);
+  const [attachmentsItem, setAttachmentsItem] = useState(null);
   const itemsWithStock = items.filter(i => i.total_stock > 0);
   const filtered = cat === "Todos" ? itemsWithStock : itemsWithStock.filter(i => i.category === cat);
 
@@ -613,7 +699,7 @@ function StockView({ items, onAdd, loadAll, showToast }) {
       showToast("Stock actualizado");
       setEditItem(null);
       loadAll();
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const handleDelete = async () => {
@@ -623,7 +709,7 @@ function StockView({ items, onAdd, loadAll, showToast }) {
       showToast(`Artigo "${confirmDeleteItem.name}" eliminado`);
       setConfirmDeleteItem(null);
       loadAll();
-    } catch (err) { showToast("Erro: " + err); setConfirmDeleteItem(null); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); setConfirmDeleteItem(null); }
   };
 
   const loaned = editItem ? editItem.total_stock - editItem.available_stock : 0;
@@ -662,6 +748,9 @@ function StockView({ items, onAdd, loadAll, showToast }) {
                     <div style={{ fontWeight: 800, fontSize: 14 }}>{item.name}</div>
                     <div style={{ fontSize: 11, color: "#8a96a3" }}>{item.category}</div>
                   </div>
+                  <button onClick={() => setAttachmentsItem(item)}
+                    style={{ background: "none", border: "none", cursor: "pointer", fontSize: 16, padding: 4 }}
+                    title="Fotos e documentos">📎</button>
                   {canDelete && (
                     <button onClick={() => setConfirmDeleteItem(item)}
                       style={{ background: "none", border: "none", cursor: "pointer", color: "#c62828", fontSize: 16, padding: 4 }}
@@ -738,10 +827,103 @@ function StockView({ items, onAdd, loadAll, showToast }) {
           </div>
         )}
       </Modal>
+
+      <AttachmentsModal item={attachmentsItem} onClose={() => setAttachmentsItem(null)} showToast={showToast} />
     </div>
   );
 }
 
+// ============================================================
+// ATTACHMENTS MODAL — fotos e documentos dun artigo
+// ============================================================
+const ATTACHMENT_KINDS = { photo: "Foto", manual: "Manual", certificate: "Certificado", other: "Outro" };
+
+function AttachmentsModal({ item, onClose, showToast }) {
+  const [attachments, setAttachments] = useState([]);
+  const [thumbs, setThumbs] = useState({});
+  const [kind, setKind] = useState("");
+
+  const load = async () => {
+    if (!item) return;
+    try {
+      const list = await invoke("get_attachments", { itemId: item.id });
+      setAttachments(list);
+      const urls = {};
+      for (const a of list.filter(a => a.has_thumbnail)) {
+        const bytes = await invoke("get_attachment_thumbnail", { id: a.id });
+        urls[a.id] = URL.createObjectURL(new Blob([new Uint8Array(bytes)], { type: "image/png" }));
+      }
+      setThumbs(urls);
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  useEffect(() => { setAttachments([]); setThumbs({}); load(); }, [item]);
+  useEffect(() => () => Object.values(thumbs).forEach(url => URL.revokeObjectURL(url)), [thumbs]);
+
+  const handleAdd = async () => {
+    try {
+      const sourcePath = await tauriOpen({ multiple: false });
+      if (!sourcePath) return;
+      await invoke("add_attachment", { req: { item_id: item.id, source_path: sourcePath, kind: kind || null, notes: null } });
+      showToast("Anexo engadido");
+      load();
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  const handleExport = async (a) => {
+    try {
+      const destPath = await save({ defaultPath: a.file_name });
+      if (destPath) {
+        await invoke("export_attachment", { id: a.id, destPath });
+        showToast("Anexo gardado");
+      }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  const handleDelete = async (a) => {
+    try {
+      await invoke("delete_attachment", { id: a.id });
+      showToast(`Anexo "${a.file_name}" eliminado`);
+      load();
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  return (
+    <Modal open={!!item} onClose={onClose} title={`Fotos e documentos: ${item?.name || ""}`}>
+      {attachments.length === 0 ? (
+        <p style={{ fontSize: 13, color: "#8a96a3", textAlign: "center", padding: 20 }}>Este artigo non ten anexos</p>
+      ) : (
+        <div style={{ display: "flex", flexDirection: "column", gap: 8, marginBottom: 16 }}>
+          {attachments.map(a => (
+            <div key={a.id} style={{ display: "flex", alignItems: "center", gap: 10, padding: 8, borderRadius: 10, background: "#f5f7fa" }}>
+              {thumbs[a.id]
+                ? <img src={thumbs[a.id]} alt={a.file_name} style={{ width: 56, height: 56, objectFit: "cover", borderRadius: 8 }} />
+                : <span style={{ fontSize: 28, width: 56, textAlign: "center" }}>📄</span>}
+              <div style={{ flex: 1, minWidth: 0 }}>
+                <div style={{ fontWeight: 700, fontSize: 13, overflow: "hidden", textOverflow: "ellipsis", whiteSpace: "nowrap" }}>{a.file_name}</div>
+                <div style={{ fontSize: 11, color: "#8a96a3" }}>{ATTACHMENT_KINDS[a.kind]} · {Math.max(1, Math.round(a.size / 1024))} KB</div>
+              </div>
+              <Btn small onClick={() => handleExport(a)}>Gardar</Btn>
+              <button onClick={() => handleDelete(a)}
+                style={{ background: "none", border: "none", cursor: "pointer", color: "#c62828", fontSize: 16, padding: 4 }}
+                title="Eliminar anexo">🗑️</button>
+            </div>
+          ))}
+        </div>
+      )}
+      <div style={{ display: "flex", gap: 10, justifyContent: "flex-end", alignItems: "center" }}>
+        <select value={kind} onChange={e => setKind(e.target.value)} style={{
+          padding: "8px 12px", borderRadius: 8, border: "1.5px solid #d8dde3", fontSize: 13, fontFamily: "inherit",
+        }}>
+          <option value="">Tipo automático</option>
+          {Object.entries(ATTACHMENT_KINDS).map(([k, l]) => <option key={k} value={k}>{l}</option>)}
+        </select>
+        <Btn primary onClick={handleAdd}>+ Engadir anexo</Btn>
+      </div>
+    </Modal>
+  );
+}
+
 // ============================================================
 // EVENTS VIEW
 // ============================================================
@@ -755,7 +937,14 @@ function EventsView({ events }) {
     STOCK_RESERVED: { label: "Stock reservado", icon: "🔒", color: "#E65100" },
     STOCK_RELEASED: { label: "Stock liberado", icon: "🔓", color: "#2E7D32" },
     USER_UPDATED: { label: "Usuario/a editado/a", icon: "✏️", color: "#1565C0" },
+    ITEM_UPDATED: { label: "Artigo editado", icon: "✏️", color: "#2E7D32" },
+    ATTACHMENT_ADDED: { label: "Anexo engadido", icon: "📎", color: "#2E7D32" },
+    ATTACHMENT_DELETED: { label: "Anexo eliminado", icon: "📎", color: "#C62828" },
+    DELIVERY_RECEIPT_ISSUED: { label: "Albarán de entrega", icon: "🧾", color: "#1565C0" },
+    RETURN_RECEIPT_ISSUED: { label: "Certificado de devolución", icon: "📄", color: "#6A1B9A" },
+    REMINDER_SENT: { label: "Recordatorio enviado", icon: "✉️", color: "#E65100" },
     RETURN_CANCELLED: { label: "Devolución cancelada", icon: "↩️", color: "#C62828" },
+    EVENT_CANCELLED: { label: "Evento anulado", icon: "🚫", color: "#C62828" },
   };
   if (!events.length) return (
     <div style={{ textAlign: "center", padding: 50, color: "#b0bac5" }}>
@@ -776,7 +965,8 @@ function EventsView({ events }) {
           <div key={evt.id} style={{ display: "flex", gap: 12, padding: "10px 0", borderBottom: "1px solid #f0f2f5", alignItems: "flex-start" }}>
             <div style={{ width: 34, height: 34, borderRadius: 9, background: `${t.color}18`, display: "flex", alignItems: "center", justifyContent: "center", fontSize: 16, flexShrink: 0 }}>{t.icon}</div>
             <div style={{ flex: 1, minWidth: 0 }}>
-              <div style={{ fontWeight: 700, fontSize: 13, color: t.color }}>{t.label}</div>
+              <div style={{ fontWeight: 700, fontSize: 13, color: t.color, textDecoration: evt.cancelled_by ? "line-through" : "none" }}>{t.label}</div>
+              {evt.cancelled_by && <div style={{ fontSize: 11, fontWeight: 700, color: "#C62828" }}>Anulado{evt.cancellation_reason ? `: ${evt.cancellation_reason}` : ""}</div>}
               <div style={{ fontSize: 11, color: "#8a96a3" }}>{new Date(evt.created_at).toLocaleString("gl-ES")}</div>
               <div style={{ fontSize: 11, color: "#5a6a7a", marginTop: 3, background: "#f8f9fb", padding: "5px 8px", borderRadius: 5, fontFamily: "monospace", wordBreak: "break-all" }}>
                 {JSON.stringify(evt.data)}
@@ -808,7 +998,7 @@ function NewLoanModal({ open, onClose, users, items, onCreate, preUser }) {
     }
   }, [open, preUser]);
 
-  const filteredUsers = users.filter(u => fuzzyMatch(u.name, uSearch) || fuzzyMatch(u.dni, uSearch));
+  const filteredUsers = users.filter(u => fuzzyMatch(u.name, uSearch) || fuzzyMatch(u.dni || "", uSearch));
   const available = items.filter(i => i.available_stock > 0);
   const filteredItems = available.filter(i => fuzzyMatch(i.name, iSearch) || fuzzyMatch(i.category, iSearch));
 
@@ -845,7 +1035,7 @@ function NewLoanModal({ open, onClose, users, items, onCreate, preUser }) {
                 background: userId === u.id ? "#eef8f5" : "#fff", cursor: "pointer", marginBottom: 5, fontFamily: "inherit",
               }}>
                 <div style={{ fontWeight: 700, fontSize: 14 }}>{u.name}</div>
-                <div style={{ fontSize: 12, color: "#8a96a3" }}>DNI: {u.dni} · {u.address}</div>
+                <div style={{ fontSize: 12, color: "#8a96a3" }}>DNI: {u.dni || "sen documento"} · {u.address}</div>
               </button>
             ))}
           </div>
@@ -904,7 +1094,7 @@ function NewLoanModal({ open, onClose, users, items, onCreate, preUser }) {
           <p style={{ fontWeight: 700, fontSize: 14, marginBottom: 12 }}>3️⃣ Confirmar préstamo</p>
           <div style={{ background: "#f8f9fb", borderRadius: 10, padding: 16, marginBottom: 14 }}>
             <div style={{ fontWeight: 800, fontSize: 15, marginBottom: 4 }}>{user?.name}</div>
-            <div style={{ fontSize: 12, color: "#8a96a3" }}>DNI: {user?.dni} · {user?.address}</div>
+            <div style={{ fontSize: 12, color: "#8a96a3" }}>DNI: {user?.dni || "sen documento"} · {user?.address}</div>
           </div>
           <div style={{ marginBottom: 14 }}>
             <div style={{ fontSize: 12, fontWeight: 700, color: "#5a6a7a", marginBottom: 6, textTransform: "uppercase", letterSpacing: 0.5 }}>Artigos seleccionados</div>
@@ -936,8 +1126,8 @@ function NewLoanModal({ open, onClose, users, items, onCreate, preUser }) {
 // NEW USER MODAL
 // ============================================================
 function NewUserModal({ open, onClose, onSave }) {
-  const [form, setForm] = useState({ name: "", dni: "", phone: "", address: "", notes: "" });
-  useEffect(() => { if (open) setForm({ name: "", dni: "", phone: "", address: "", notes: "" }); }, [open]);
+  const [form, setForm] = useState({ name: "", dni: "", noDocument: false, phone: "", address: "", notes: "" });
+  useEffect(() => { if (open) setForm({ name: "", dni: "", noDocument: false, phone: "", address: "", notes: "" }); }, [open]);
   const set = (k, v) => setForm(p => ({ ...p, [k]: v }));
   const fields = [
     ["Nome completo", "name", "María García López"],
@@ -950,9 +1140,15 @@ function NewUserModal({ open, onClose, onSave }) {
       {fields.map(([label, key, ph]) => (
         <div key={key} style={{ marginBottom: 12 }}>
           <label style={{ display: "block", fontSize: 11, fontWeight: 700, color: "#5a6a7a", marginBottom: 4, textTransform: "uppercase", letterSpacing: 0.5 }}>{label}</label>
-          <input type="text" value={form[key]} onChange={e => set(key, e.target.value)} placeholder={ph} style={{
+          <input type="text" value={form[key]} onChange={e => set(key, e.target.value)} placeholder={ph} disabled={key === "dni" && form.noDocument} style={{
             width: "100%", padding: "10px 14px", borderRadius: 10, border: "1.5px solid #d8dde3", fontSize: 14, fontFamily: "inherit", boxSizing: "border-box", outline: "none",
           }} />
+          {key === "dni" && (
+            <label style={{ display: "flex", alignItems: "center", gap: 6, fontSize: 12, color: "#5a6a7a", marginTop: 6 }}>
+              <input type="checkbox" checked={form.noDocument} onChange={e => set("noDocument", e.target.checked)} />
+              Sen documento (a persoa non ten DNI/NIE)
+            </label>
+          )}
         </div>
       ))}
       <div style={{ marginBottom: 12 }}>
@@ -963,7 +1159,7 @@ function NewUserModal({ open, onClose, onSave }) {
       </div>
       <div style={{ display: "flex", gap: 10, justifyContent: "flex-end", marginTop: 16 }}>
         <Btn onClick={onClose}>Cancelar</Btn>
-        <Btn primary disabled={!form.name || !form.dni} onClick={() => { onSave(form); onClose(); }}>✓ Gardar usuario/a</Btn>
+        <Btn primary disabled={!form.name || (!form.dni && !form.noDocument)} onClick={() => { onSave(form); onClose(); }}>✓ Gardar usuario/a</Btn>
       </div>
     </Modal>
   );
@@ -995,7 +1191,7 @@ function NewStockModal({ open, onClose, items, loadAll, showToast }) {
       showToast(`+${qty} unidades de ${item.name}`);
       loadAll();
       onClose();
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const handleCreateCustom = async () => {
@@ -1007,17 +1203,17 @@ function NewStockModal({ open, onClose, items, loadAll, showToast }) {
       showToast(`Artigo "${customName}" creado con ${customStock} unidades`);
       loadAll();
       onClose();
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   // Get all items (including those with 0 stock) for adding stock
   const allItems = items;
 
-  const categories = [
-    ["Mobilidade", "Mobilidade"], ["Baño", "Baño"], ["Transferencia", "Transferencia"],
-    ["Coidados", "Coidados"], ["Cama/Descanso", "Cama/Descanso"], ["Respiración", "Respiración"],
-    ["Alimentación", "Alimentación"], ["Vestir", "Vestir"], ["Comunicación", "Comunicación"], ["Outros", "Outros"],
-  ];
+  const [categoryList, setCategoryList] = useState([]);
+  useEffect(() => {
+    if (open) invoke("get_categories", { includeInactive: false }).then(setCategoryList).catch(err => console.error("Error loading categories:", err));
+  }, [open]);
+  const categories = categoryList.map(c => [c.name, `${c.icon} ${c.name}`]);
 
   return (
     <Modal open={open} onClose={onClose} title="Engadir artigo ao inventario">
@@ -1143,9 +1339,9 @@ function EventLogModal({ open, onClose, events }) {
 // EDIT USER MODAL
 // ============================================================
 function EditUserModal({ open, onClose, user, onSave }) {
-  const [form, setForm] = useState({ name: "", dni: "", phone: "", address: "", notes: "" });
+  const [form, setForm] = useState({ name: "", dni: "", noDocument: false, phone: "", address: "", notes: "" });
   useEffect(() => {
-    if (open && user) setForm({ name: user.name || "", dni: user.dni || "", phone: user.phone || "", address: user.address || "", notes: user.notes || "" });
+    if (open && user) setForm({ name: user.name || "", dni: user.dni || "", noDocument: !user.dni, phone: user.phone || "", address: user.address || "", notes: user.notes || "" });
   }, [open, user]);
   const set = (k, v) => setForm(p => ({ ...p, [k]: v }));
   const fields = [
@@ -1159,9 +1355,15 @@ function EditUserModal({ open, onClose, user, onSave }) {
       {fields.map(([label, key, ph]) => (
         <div key={key} style={{ marginBottom: 12 }}>
           <label style={{ display: "block", fontSize: 11, fontWeight: 700, color: "#5a6a7a", marginBottom: 4, textTransform: "uppercase", letterSpacing: 0.5 }}>{label}</label>
-          <input type="text" value={form[key]} onChange={e => set(key, e.target.value)} placeholder={ph} style={{
+          <input type="text" value={form[key]} onChange={e => set(key, e.target.value)} placeholder={ph} disabled={key === "dni" && form.noDocument} style={{
             width: "100%", padding: "10px 14px", borderRadius: 10, border: "1.5px solid #d8dde3", fontSize: 14, fontFamily: "inherit", boxSizing: "border-box", outline: "none",
           }} />
+          {key === "dni" && (
+            <label style={{ display: "flex", alignItems: "center", gap: 6, fontSize: 12, color: "#5a6a7a", marginTop: 6 }}>
+              <input type="checkbox" checked={form.noDocument} onChange={e => set("noDocument", e.target.checked)} />
+              Sen documento (a persoa non ten DNI/NIE)
+            </label>
+          )}
         </div>
       ))}
       <div style={{ marginBottom: 12 }}>
@@ -1172,7 +1374,7 @@ function EditUserModal({ open, onClose, user, onSave }) {
       </div>
       <div style={{ display: "flex", gap: 10, justifyContent: "flex-end", marginTop: 16 }}>
         <Btn onClick={onClose}>Cancelar</Btn>
-        <Btn primary disabled={!form.name || !form.dni} onClick={() => { onSave(form); }}>✓ Gardar cambios</Btn>
+        <Btn primary disabled={!form.name || (!form.dni && !form.noDocument)} onClick={() => { onSave(form); }}>✓ Gardar cambios</Btn>
       </div>
     </Modal>
   );
@@ -1197,7 +1399,7 @@ function ReportsView({ showToast }) {
         await invoke("export_to_excel", { path: filePath });
         showToast("Exportado con éxito!");
       }
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const handleAnnualReport = async () => {
@@ -1221,7 +1423,37 @@ function ReportsView({ showToast }) {
           showToast(`Informe anual ${year} exportado en PDF!`);
         }
       }
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  const [template, setTemplate] = useState("");
+  const [placeholders, setPlaceholders] = useState([]);
+  const [perUser, setPerUser] = useState(false);
+
+  useEffect(() => {
+    invoke("get_reminder_template").then(setTemplate).catch(err => showToast("Erro: " + errorMessage(err)));
+    invoke("get_reminder_placeholders").then(setPlaceholders).catch(() => {});
+  }, []);
+
+  const handleSaveTemplate = async (value) => {
+    try {
+      await invoke("set_reminder_template", { template: value });
+      setTemplate(await invoke("get_reminder_template"));
+      showToast(value ? "Modelo gardado" : "Modelo restaurado");
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
+  };
+
+  const handleReminders = async () => {
+    try {
+      const today = new Date().toISOString().split("T")[0];
+      const path = perUser
+        ? await tauriOpen({ directory: true, multiple: false })
+        : await save({ filters: [{ name: "PDF", extensions: ["pdf"] }], defaultPath: `SAF_Recordatorios_${today}.pdf` });
+      if (path) {
+        const files = await invoke("export_reminder_letters", { path, oneFilePerUser: perUser });
+        showToast(perUser ? `${files.length} cartas xeradas` : "Cartas de recordatorio xeradas");
+      }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const lbl = { display: "block", fontSize: 11, fontWeight: 700, color: "#5a6a7a", marginBottom: 4, textTransform: "uppercase", letterSpacing: 0.5 };
@@ -1271,6 +1503,35 @@ function ReportsView({ showToast }) {
           </div>
           <Btn primary onClick={handleAnnualReport} style={{ width: "100%" }}>Xerar informe {year}</Btn>
         </div>
+
+        {/* Reminder letters */}
+        <div style={{ gridColumn: "1 / -1", background: "#fff", borderRadius: 14, padding: "22px 24px", boxShadow: "0 1px 4px rgba(0,0,0,0.05)" }}>
+          <div style={{ display: "flex", alignItems: "center", gap: 10, marginBottom: 14 }}>
+            <span style={{ fontSize: 28 }}>✉️</span>
+            <div>
+              <div style={{ fontWeight: 800, fontSize: 15 }}>Cartas de recordatorio</div>
+              <div style={{ fontSize: 12, color: "#8a96a3" }}>Unha carta por persoa con préstamos atrasados</div>
+            </div>
+          </div>
+          <div style={{ marginBottom: 12 }}>
+            <label style={lbl}>Modelo da carta</label>
+            <textarea value={template} onChange={e => setTemplate(e.target.value)} rows={10} style={{
+              width: "100%", padding: "10px 14px", borderRadius: 10, border: "1.5px solid #d8dde3", fontSize: 13, fontFamily: "inherit", resize: "vertical", boxSizing: "border-box", outline: "none",
+            }} />
+            <div style={{ fontSize: 11, color: "#8a96a3", marginTop: 4 }}>
+              {placeholders.map(([name, desc]) => <span key={name} style={{ marginRight: 12 }}><code>{`{${name}}`}</code> {desc}</span>)}
+            </div>
+          </div>
+          <div style={{ display: "flex", gap: 10, alignItems: "center", justifyContent: "flex-end" }}>
+            <label style={{ display: "flex", alignItems: "center", gap: 6, fontSize: 12, color: "#5a6a7a", marginRight: "auto" }}>
+              <input type="checkbox" checked={perUser} onChange={e => setPerUser(e.target.checked)} />
+              Un ficheiro por persoa
+            </label>
+            <Btn onClick={() => handleSaveTemplate(null)}>Restaurar modelo</Btn>
+            <Btn onClick={() => handleSaveTemplate(template)}>Gardar modelo</Btn>
+            <Btn primary onClick={handleReminders}>Xerar cartas</Btn>
+          </div>
+        </div>
       </div>
     </div>
   );
@@ -1357,7 +1618,7 @@ function AdminView({ showToast, dbLocation, setDbLocation }) {
       showToast(`Copia creada: ${result.filename}`);
       loadBackups();
     } catch (err) { 
-      showToast("Erro: " + err); 
+      showToast("Erro: " + errorMessage(err)); 
     }
   };
 
@@ -1367,7 +1628,7 @@ function AdminView({ showToast, dbLocation, setDbLocation }) {
       showToast("Datos restaurados! Reinicia a aplicación para ver os cambios.");
       setConfirmRestore(null);
     } catch (err) { 
-      showToast("Erro: " + err); 
+      showToast("Erro: " + errorMessage(err)); 
       setConfirmRestore(null);
     }
   };
@@ -1379,7 +1640,7 @@ function AdminView({ showToast, dbLocation, setDbLocation }) {
       setConfirmDelete(null);
       loadBackups();
     } catch (err) { 
-      showToast("Erro: " + err); 
+      showToast("Erro: " + errorMessage(err)); 
       setConfirmDelete(null);
     }
   };
@@ -1394,7 +1655,7 @@ function AdminView({ showToast, dbLocation, setDbLocation }) {
         await invoke("export_backup", { sourcePath: backupPath, destPath });
         showToast("Copia exportada correctamente!");
       }
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const handleImportBackup = async () => {
@@ -1409,7 +1670,7 @@ function AdminView({ showToast, dbLocation, setDbLocation }) {
         loadBackups();
         setConfirmImport(false);
       }
-    } catch (err) { showToast("Erro: " + err); setConfirmImport(false); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); setConfirmImport(false); }
   };
 
   const handleChangeDbLocation = async () => {
@@ -1423,7 +1684,7 @@ function AdminView({ showToast, dbLocation, setDbLocation }) {
         setDbLocation(selected);
         showToast("Localización actualizada! Reinicia a aplicación para usar a nova base de datos.");
       }
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const handleSelectNewDbLocation = async () => {
@@ -1437,7 +1698,7 @@ function AdminView({ showToast, dbLocation, setDbLocation }) {
         setDbLocation(selected);
         showToast("Localización configurada! Reinicia a aplicación.");
       }
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   return (
@@ -1582,7 +1843,7 @@ function DbConfigModal({ open, onClose, onConfigured, dbLocation, setDbLocation,
         showToast("Base de datos configurada!");
         onConfigured();
       }
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   const handleCreateNew = async () => {
@@ -1597,7 +1858,7 @@ function DbConfigModal({ open, onClose, onConfigured, dbLocation, setDbLocation,
         showToast("Nova base de datos configurada!");
         onConfigured();
       }
-    } catch (err) { showToast("Erro: " + err); }
+    } catch (err) { showToast("Erro: " + errorMessage(err)); }
   };
 
   return (